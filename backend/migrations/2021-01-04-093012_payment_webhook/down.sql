@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+drop table payment_event;
+
+alter table transaction
+drop column payment_status;
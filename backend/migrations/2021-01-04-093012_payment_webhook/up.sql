@@ -0,0 +1,13 @@
+-- Your SQL goes here
+alter table transaction
+add payment_status varchar(255) not null default 'pending';
+
+create table payment_event (
+    id varchar(255) primary key,
+    event_type varchar(255) not null,
+    transaction_id integer,
+    payload text not null,
+    received_at datetime not null default current_timestamp,
+
+    foreign key (transaction_id) references transaction(id)
+);
@@ -0,0 +1,16 @@
+-- This file should undo anything in `up.sql`
+drop table return_event;
+
+drop table return_item;
+
+drop table return_request;
+
+drop table refund;
+
+drop table transaction_item;
+
+alter table product
+drop column stock;
+
+alter table customer
+drop column role;
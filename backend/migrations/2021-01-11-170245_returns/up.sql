@@ -0,0 +1,62 @@
+-- Your SQL goes here
+alter table customer
+add role varchar(255) not null default 'customer';
+
+alter table product
+add stock integer;
+
+create table transaction_item (
+    id integer primary key auto_increment,
+    transaction_id integer not null,
+    product_id integer not null,
+    quantity integer not null,
+    unit_price float not null,
+
+    foreign key (transaction_id) references transaction(id),
+    foreign key (product_id) references product(id)
+);
+
+create table refund (
+    id integer primary key auto_increment,
+    transaction_id integer not null,
+    amount float not null,
+    status varchar(255) not null default 'pending',
+    created_at datetime not null default current_timestamp,
+
+    foreign key (transaction_id) references transaction(id)
+);
+
+create table return_request (
+    id integer primary key auto_increment,
+    transaction_id integer not null,
+    customer_id integer not null,
+    status varchar(255) not null default 'requested',
+    refund_amount float,
+    refund_id integer,
+    created_at datetime not null default current_timestamp,
+
+    foreign key (transaction_id) references transaction(id),
+    foreign key (customer_id) references customer(id),
+    foreign key (refund_id) references refund(id)
+);
+
+create table return_item (
+    return_id integer,
+    transaction_item_id integer,
+    quantity integer not null,
+    reason text(500) not null,
+
+    constraint return_item_pk primary key (return_id, transaction_item_id),
+    foreign key (return_id) references return_request(id),
+    foreign key (transaction_item_id) references transaction_item(id)
+);
+
+create table return_event (
+    id integer primary key auto_increment,
+    return_id integer not null,
+    status varchar(255) not null,
+    note text(500),
+    created_at datetime not null default current_timestamp,
+
+    foreign key (return_id) references return_request(id)
+);
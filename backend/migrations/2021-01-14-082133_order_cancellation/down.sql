@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+alter table transaction_item
+drop column cancelled_quantity;
+
+alter table transaction
+drop column fulfilment_status;
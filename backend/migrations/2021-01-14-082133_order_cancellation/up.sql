@@ -0,0 +1,6 @@
+-- Your SQL goes here
+alter table transaction
+add fulfilment_status varchar(255) not null default 'placed';
+
+alter table transaction_item
+add cancelled_quantity integer not null default 0;
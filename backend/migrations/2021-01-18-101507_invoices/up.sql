@@ -0,0 +1,8 @@
+-- Your SQL goes here
+create table invoice (
+    id integer primary key auto_increment,
+    transaction_id integer not null unique,
+    issued_at datetime not null default current_timestamp,
+
+    foreign key (transaction_id) references transaction(id)
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+alter table product
+drop column max_order_quantity;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+alter table product
+add max_order_quantity integer;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+create table guest_cart_items (
+    guest_id varchar(64),
+    product_id integer,
+    quantity integer not null default 1,
+    created_at datetime not null default current_timestamp,
+
+    constraint guest_cart_items_pk primary key (guest_id, product_id),
+    foreign key (product_id) references product(id)
+);
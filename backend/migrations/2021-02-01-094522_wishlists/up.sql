@@ -0,0 +1,22 @@
+-- Your SQL goes here
+create table wishlist (
+    id integer primary key auto_increment,
+    customer_id integer not null,
+    name varchar(255) not null,
+    is_default boolean not null default false,
+    share_token varchar(64) unique,
+    created_at datetime not null default current_timestamp,
+
+    foreign key (customer_id) references customer(id)
+);
+
+create table wishlist_item (
+    wishlist_id integer,
+    product_id integer,
+    quantity integer not null default 1,
+    added_at datetime not null default current_timestamp,
+
+    constraint wishlist_item_pk primary key (wishlist_id, product_id),
+    foreign key (wishlist_id) references wishlist(id) on delete cascade,
+    foreign key (product_id) references product(id)
+);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+alter table guest_cart_items drop column unit_price;
+alter table cart_items drop column unit_price;
+alter table product drop column archived;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+alter table product add column archived boolean not null default false;
+
+alter table cart_items add column unit_price float;
+alter table guest_cart_items add column unit_price float;
+
+update cart_items c join product p on c.product_id = p.id
+set c.unit_price = p.price;
+update guest_cart_items g join product p on g.product_id = p.id
+set g.unit_price = p.price;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+drop table cart_reminder;
+drop table notification_outbox;
+drop table cart;
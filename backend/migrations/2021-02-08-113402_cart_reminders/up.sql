@@ -0,0 +1,36 @@
+-- Your SQL goes here
+create table cart (
+    customer_id integer primary key,
+    updated_at datetime not null default current_timestamp,
+    reminders_sent integer not null default 0,
+    last_reminded_at datetime,
+
+    foreign key (customer_id) references customer(id)
+);
+
+insert into cart (customer_id) select distinct cart_id from cart_items;
+
+create table notification_outbox (
+    id integer primary key auto_increment,
+    customer_id integer,
+    kind varchar(64) not null,
+    recipient varchar(255) not null,
+    subject varchar(255) not null,
+    body text not null,
+    created_at datetime not null default current_timestamp,
+    sent_at datetime,
+
+    foreign key (customer_id) references customer(id)
+);
+
+create table cart_reminder (
+    id integer primary key auto_increment,
+    customer_id integer not null,
+    notification_id integer not null,
+    sent_at datetime not null default current_timestamp,
+    recovered_transaction_id integer,
+
+    foreign key (customer_id) references customer(id),
+    foreign key (notification_id) references notification_outbox(id),
+    foreign key (recovered_transaction_id) references transaction(id)
+);
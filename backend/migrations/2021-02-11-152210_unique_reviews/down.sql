@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+alter table rating drop column edited_at;
+alter table rating drop column verified_purchase;
+alter table rating drop index rating_customer_product;
+insert into rating select * from rating_duplicate;
+drop table rating_duplicate;
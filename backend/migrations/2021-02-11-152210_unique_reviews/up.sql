@@ -0,0 +1,25 @@
+-- Your SQL goes here
+-- older duplicates make way for the newest review, but are kept aside so
+-- that down.sql can bring them back
+create table rating_duplicate like rating;
+insert into rating_duplicate
+select * from rating r1 where exists (
+    select 1 from rating r2
+    where r2.customer_id = r1.customer_id
+    and r2.product_id = r1.product_id
+    and r2.id > r1.id
+);
+delete r1 from rating r1 join rating_duplicate d on r1.id = d.id;
+
+alter table rating add constraint rating_customer_product
+unique (customer_id, product_id);
+alter table rating add column verified_purchase boolean not null default false;
+alter table rating add column edited_at datetime;
+
+update rating r set verified_purchase = exists (
+    select 1 from transaction_item ti
+    join transaction t on ti.transaction_id = t.id
+    where t.customer_id = r.customer_id
+    and ti.product_id = r.product_id
+    and ti.quantity > ti.cancelled_quantity
+);
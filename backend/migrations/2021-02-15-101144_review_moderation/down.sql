@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+drop table rating_report;
+alter table rating drop foreign key rating_moderated_by;
+alter table rating drop column moderated_at;
+alter table rating drop column moderated_by;
+alter table rating drop column flag_reason;
+alter table rating drop column status;
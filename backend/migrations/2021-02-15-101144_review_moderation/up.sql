@@ -0,0 +1,23 @@
+-- Your SQL goes here
+alter table rating add column status varchar(16) not null default 'pending';
+alter table rating add column flag_reason varchar(255);
+alter table rating add column moderated_by integer;
+alter table rating add column moderated_at datetime;
+alter table rating add constraint rating_moderated_by
+foreign key (moderated_by) references customer(id);
+
+update rating set status = 'approved';
+
+create table rating_report (
+    id integer primary key auto_increment,
+    rating_id integer not null,
+    customer_id integer not null,
+    reason varchar(32) not null,
+    note text,
+    resolved boolean not null default false,
+    created_at datetime not null default current_timestamp,
+
+    constraint rating_report_once unique (rating_id, customer_id),
+    foreign key (rating_id) references rating(id) on delete cascade,
+    foreign key (customer_id) references customer(id)
+);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+drop table rating_vote;
+alter table rating drop column not_helpful_votes;
+alter table rating drop column helpful_votes;
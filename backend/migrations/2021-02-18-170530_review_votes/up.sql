@@ -0,0 +1,14 @@
+-- Your SQL goes here
+alter table rating add column helpful_votes integer not null default 0;
+alter table rating add column not_helpful_votes integer not null default 0;
+
+create table rating_vote (
+    rating_id integer,
+    customer_id integer,
+    helpful boolean not null,
+    created_at datetime not null default current_timestamp,
+
+    constraint rating_vote_pk primary key (rating_id, customer_id),
+    foreign key (rating_id) references rating(id) on delete cascade,
+    foreign key (customer_id) references customer(id)
+);
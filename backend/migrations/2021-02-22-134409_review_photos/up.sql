@@ -0,0 +1,12 @@
+-- Your SQL goes here
+create table rating_photo (
+    id integer primary key auto_increment,
+    rating_id integer not null,
+    storage_key varchar(255) not null,
+    thumbnail_key varchar(255) not null,
+    content_type varchar(64) not null,
+    size integer not null,
+    created_at datetime not null default current_timestamp,
+
+    foreign key (rating_id) references rating(id) on delete cascade
+);
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+create table user_token (
+    id integer primary key auto_increment,
+    customer_id integer not null,
+    purpose varchar(32) not null,
+    token_hash char(64) not null,
+    expires_at datetime not null,
+    used_at datetime,
+    created_at datetime not null default current_timestamp,
+
+    constraint user_token_hash unique (token_hash),
+    foreign key (customer_id) references customer(id) on delete cascade
+);
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+alter table customer
+add email_verified boolean not null default false;
+
+-- accounts from before verification existed stay usable
+update customer set email_verified = true;
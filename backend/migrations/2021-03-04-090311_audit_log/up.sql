@@ -0,0 +1,13 @@
+-- Your SQL goes here
+create table audit_log (
+    id integer primary key auto_increment,
+    event varchar(64) not null,
+    customer_id integer,
+    username varchar(255),
+    ip varchar(64),
+    detail text,
+    created_at datetime not null default current_timestamp,
+
+    index audit_log_event (event, created_at),
+    foreign key (customer_id) references customer(id) on delete set null
+);
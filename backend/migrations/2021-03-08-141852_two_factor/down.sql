@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+drop table site_setting;
+drop table recovery_code;
+drop table customer_totp;
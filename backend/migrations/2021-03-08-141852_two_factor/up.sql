@@ -0,0 +1,31 @@
+-- Your SQL goes here
+create table customer_totp (
+    customer_id integer primary key,
+    secret varchar(64) not null,
+    confirmed_at datetime,
+    last_used_step bigint,
+    created_at datetime not null default current_timestamp,
+
+    foreign key (customer_id) references customer(id) on delete cascade
+);
+
+create table recovery_code (
+    id integer primary key auto_increment,
+    customer_id integer not null,
+    code_hash char(64) not null,
+    used_at datetime,
+
+    constraint recovery_code_hash unique (customer_id, code_hash),
+    foreign key (customer_id) references customer(id) on delete cascade
+);
+
+create table site_setting (
+    name varchar(64) primary key,
+    value varchar(255) not null,
+    updated_by integer,
+    updated_at datetime not null default current_timestamp
+        on update current_timestamp,
+
+    constraint site_setting_updated_by
+        foreign key (updated_by) references customer(id) on delete set null
+);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+alter table customer
+add deleted_at datetime;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+alter table customer
+add created_at datetime not null default current_timestamp;
+
+-- best guess for existing accounts: their first order, if any
+update customer c
+set created_at = coalesce(
+    (select min(t.order_date) from transaction t where t.customer_id = c.id),
+    c.created_at
+);
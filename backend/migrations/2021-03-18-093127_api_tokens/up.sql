@@ -0,0 +1,17 @@
+-- Your SQL goes here
+create table api_token (
+    id integer primary key auto_increment,
+    customer_id integer not null,
+    name varchar(64) not null,
+    kind varchar(16) not null,
+    token_prefix varchar(16) not null,
+    token_hash char(64) not null,
+    scopes varchar(255) not null,
+    expires_at datetime not null,
+    last_used_at datetime,
+    revoked_at datetime,
+    created_at datetime not null default current_timestamp,
+
+    constraint api_token_hash unique (token_hash),
+    foreign key (customer_id) references customer(id) on delete cascade
+);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+alter table notification_outbox
+drop column attempts,
+drop column last_error,
+drop column next_attempt_at;
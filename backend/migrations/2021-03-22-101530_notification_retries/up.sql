@@ -0,0 +1,5 @@
+-- Your SQL goes here
+alter table notification_outbox
+add attempts integer not null default 0,
+add last_error text,
+add next_attempt_at datetime;
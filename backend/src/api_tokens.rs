@@ -0,0 +1,150 @@
+//! API tokens let scripts call the API with `Authorization: Bearer <token>`
+//! instead of a login cookie. A token acts as the account that created it,
+//! but only on the routes its scopes cover; everything else, like managing
+//! tokens, still needs a real login.
+//!
+//! Personal tokens are for customers' own scripts. Service tokens are for
+//! integrations such as the warehouse, can only be created by staff, and
+//! are looked after by admins.
+
+use crate::models::{AddApiToken, ApiToken, Customer};
+use crate::schema::api_token::dsl::*;
+use crate::schema::customer::dsl as cust;
+
+use chrono::naive::NaiveDateTime;
+use chrono::Duration;
+use diesel::dsl::now;
+use diesel::prelude::*;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+pub const PERSONAL: &str = "personal";
+pub const SERVICE: &str = "service";
+
+const TOKEN_PREFIX: &str = "furby_";
+pub const MAX_DAYS: i64 = 365;
+
+/// Scope, method and path prefix of the routes each scope opens up.
+const SCOPES: &[(&str, &str, &str)] = &[
+    ("products:read", "GET", "/product/"),
+    ("products:write", "POST", "/product/"),
+    ("orders:read", "GET", "/transaction/"),
+    ("orders:write", "POST", "/transaction/"),
+    ("returns:read", "GET", "/returns/"),
+    ("returns:write", "POST", "/returns/"),
+    ("cart:read", "GET", "/cart/"),
+    ("cart:write", "POST", "/cart/"),
+    ("reviews:moderate", "GET", "/moderation/"),
+    ("reviews:moderate", "POST", "/moderation/"),
+    ("profile:read", "GET", "/user/profile"),
+];
+
+pub fn default_days() -> i64 {
+    std::env::var("FURBY_API_TOKEN_DAYS")
+        .ok()
+        .and_then(|d| d.parse().ok())
+        .unwrap_or(90)
+}
+
+pub fn is_scope(scope: &str) -> bool {
+    SCOPES.iter().any(|(s, _, _)| *s == scope)
+}
+
+pub fn known_scopes() -> Vec<&'static str> {
+    let mut known = SCOPES.iter().map(|(s, _, _)| *s).collect::<Vec<_>>();
+    known.dedup();
+    known
+}
+
+fn covers(granted: &str, method: &str, path: &str) -> bool {
+    granted.split(',').any(|g| {
+        SCOPES
+            .iter()
+            .any(|(s, m, p)| *s == g && *m == method && path.starts_with(p))
+    })
+}
+
+fn hash_of(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Stores a new token and returns it along with its id. This is the only
+/// time the token itself is around.
+pub fn create(
+    conn: &MysqlConnection,
+    owner: i32,
+    token_name: &str,
+    token_kind: &str,
+    token_scopes: &[String],
+    days: i64,
+) -> QueryResult<(i32, String)> {
+    let token = format!(
+        "{}{}",
+        TOKEN_PREFIX,
+        hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+    );
+    let created = diesel::select(now).first::<NaiveDateTime>(conn)?;
+    diesel::insert_into(api_token)
+        .values(AddApiToken {
+            customer_id: owner,
+            name: token_name.to_string(),
+            kind: token_kind.to_string(),
+            token_prefix: token[..TOKEN_PREFIX.len() + 6].to_string(),
+            token_hash: hash_of(&token),
+            scopes: token_scopes.join(","),
+            expires_at: created + Duration::days(days.min(MAX_DAYS)),
+        })
+        .execute(conn)?;
+    let tid = api_token
+        .filter(token_hash.eq(hash_of(&token)))
+        .select(id)
+        .first::<i32>(conn)?;
+    Ok((tid, token))
+}
+
+pub fn revoke(conn: &MysqlConnection, tid: i32) -> QueryResult<usize> {
+    let revoked = diesel::select(now).first::<NaiveDateTime>(conn)?;
+    diesel::update(api_token.find(tid).filter(revoked_at.is_null()))
+        .set(revoked_at.eq(revoked))
+        .execute(conn)
+}
+
+/// Why a bearer token was turned away.
+#[derive(Debug)]
+pub enum Refusal {
+    Invalid,
+    OutOfScope,
+    Db(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for Refusal {
+    fn from(e: diesel::result::Error) -> Self {
+        Refusal::Db(e)
+    }
+}
+
+/// Username a request to `method` `path` with `token` acts as.
+pub fn authenticate(
+    conn: &MysqlConnection,
+    token: &str,
+    method: &str,
+    path: &str,
+) -> Result<String, Refusal> {
+    let used_now = diesel::select(now).first::<NaiveDateTime>(conn)?;
+    let (found, owner) = api_token
+        .inner_join(cust::customer)
+        .filter(token_hash.eq(hash_of(token.trim())))
+        .filter(revoked_at.is_null())
+        .filter(expires_at.gt(used_now))
+        .filter(cust::deleted_at.is_null())
+        .first::<(ApiToken, Customer)>(conn)
+        .optional()?
+        .ok_or(Refusal::Invalid)?;
+    if !covers(&found.scopes, method, path) {
+        return Err(Refusal::OutOfScope);
+    }
+    diesel::update(api_token.find(found.id))
+        .set(last_used_at.eq(used_now))
+        .execute(conn)?;
+    Ok(owner.username)
+}
@@ -0,0 +1,30 @@
+//! Security relevant events, kept in the `audit_log` table.
+
+use crate::models::AddAuditEvent;
+use crate::schema::audit_log::dsl::*;
+
+use actix_web::HttpRequest;
+use diesel::prelude::*;
+use log::{error, info};
+
+pub const LOGIN_FAILED: &str = "login_failed";
+pub const LOGIN_LOCKED: &str = "login_locked";
+pub const EMAIL_CHANGED: &str = "email_changed";
+pub const PASSWORD_CHANGED: &str = "password_changed";
+
+/// Address of the client that made `req`, without the port.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    req.peer_addr().map(|a| a.ip().to_string())
+}
+
+/// Records `entry`. The audit log must never be the reason a request
+/// fails, so errors are only logged.
+pub fn record(conn: &MysqlConnection, entry: AddAuditEvent) {
+    info!(
+        "Audit: {} {:?} from {:?}",
+        entry.event, entry.username, entry.ip
+    );
+    if let Err(e) = diesel::insert_into(audit_log).values(entry).execute(conn) {
+        error!("Unable to write audit log: {}", e);
+    }
+}
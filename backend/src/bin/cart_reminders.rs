@@ -0,0 +1,59 @@
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::MysqlConnection;
+use furby::reminders;
+
+use std::time::Duration;
+use std::{env, process, thread};
+
+fn usage() -> ! {
+    eprintln!("usage: cart_reminders [--every <seconds>] [--stats]");
+    eprintln!("queues reminders for abandoned carts, once or periodically");
+    process::exit(1);
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let mut every = None;
+    let mut stats = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--every" => {
+                let secs = args
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .unwrap_or_else(|| usage());
+                every = Some(Duration::from_secs(secs))
+            }
+            "--stats" => stats = true,
+            _ => usage(),
+        }
+    }
+
+    let db_url = env!("DATABASE_URL");
+    let manager = ConnectionManager::<MysqlConnection>::new(db_url);
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(manager)
+        .expect("Failed to create pool.");
+    let conn = pool.get().unwrap();
+
+    if stats {
+        let s =
+            reminders::reminder_stats(&conn).expect("Couldn't connect to DB");
+        println!("reminders sent:    {}", s.reminders_sent);
+        println!("orders recovered:  {}", s.orders_recovered);
+        println!("revenue recovered: {:.2}", s.revenue_recovered);
+        return;
+    }
+    loop {
+        let queued = reminders::queue_cart_reminders(&conn)
+            .expect("Couldn't connect to DB");
+        println!("queued {} cart reminders", queued);
+        match every {
+            Some(period) => thread::sleep(period),
+            None => break,
+        }
+    }
+}
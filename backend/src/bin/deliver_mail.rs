@@ -0,0 +1,56 @@
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::MysqlConnection;
+use furby::mailer::{FileMailer, Mailer, SendmailMailer};
+use furby::notifications;
+
+use std::time::Duration;
+use std::{env, process, thread};
+
+fn usage() -> ! {
+    eprintln!("usage: deliver_mail [--every <seconds>] [--to-dir <dir>]");
+    eprintln!("sends queued notifications with sendmail, once or periodically");
+    process::exit(1);
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let mut every = None;
+    let mut to_dir = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--every" => {
+                let secs = args
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .unwrap_or_else(|| usage());
+                every = Some(Duration::from_secs(secs))
+            }
+            "--to-dir" => to_dir = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+    let mailer: Box<dyn Mailer> = match to_dir {
+        Some(dir) => Box::new(FileMailer { dir: dir.into() }),
+        None => Box::new(SendmailMailer::from_env()),
+    };
+
+    let db_url = env!("DATABASE_URL");
+    let manager = ConnectionManager::<MysqlConnection>::new(db_url);
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(manager)
+        .expect("Failed to create pool.");
+    let conn = pool.get().unwrap();
+
+    loop {
+        let sent = notifications::deliver_pending(&conn, mailer.as_ref())
+            .expect("Couldn't connect to DB");
+        println!("delivered {} notifications", sent);
+        match every {
+            Some(period) => thread::sleep(period),
+            None => break,
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::MysqlConnection;
+use furby::invoice;
+use furby::models::Transaction;
+use furby::schema::transaction::dsl::*;
+
+use std::path::PathBuf;
+use std::{env, fs, process};
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: invoices [--out <dir>] [--format html|pdf|both] \
+         (--all | <order id>...)"
+    );
+    process::exit(1);
+}
+
+fn main() {
+    let mut out_dir = PathBuf::from("invoices");
+    let mut format = String::from("both");
+    let mut all = false;
+    let mut order_ids = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out" => out_dir = args.next().unwrap_or_else(|| usage()).into(),
+            "--format" => format = args.next().unwrap_or_else(|| usage()),
+            "--all" => all = true,
+            "-h" | "--help" => usage(),
+            _ => order_ids.push(arg.parse::<i32>().unwrap_or_else(|_| usage())),
+        }
+    }
+    if !["html", "pdf", "both"].contains(&format.as_str())
+        || (!all && order_ids.is_empty())
+    {
+        usage();
+    }
+
+    let db_url = env!("DATABASE_URL");
+    let manager = ConnectionManager::<MysqlConnection>::new(db_url);
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(manager)
+        .expect("Failed to create pool.");
+    let conn = pool.get().unwrap();
+
+    let orders = if all {
+        transaction.order(id.asc()).load::<Transaction>(&conn)
+    } else {
+        transaction
+            .filter(id.eq_any(&order_ids))
+            .order(id.asc())
+            .load::<Transaction>(&conn)
+    }
+    .expect("Couldn't connect to DB");
+    if !all && orders.len() != order_ids.len() {
+        eprintln!("warning: some orders were not found");
+    }
+
+    fs::create_dir_all(&out_dir).expect("Unable to create output directory");
+    for order in orders {
+        if !invoice::invoiceable(&order) {
+            if !all {
+                eprintln!("skipping cancelled order {}", order.id);
+            }
+            continue;
+        }
+        let inv = invoice::invoice_for(&conn, &order)
+            .expect("Couldn't connect to DB");
+        if format != "pdf" {
+            let path = out_dir.join(format!("{}.html", inv.number));
+            fs::write(&path, invoice::render_html(&inv))
+                .expect("Unable to write invoice");
+            println!("{}", path.display());
+        }
+        if format != "html" {
+            let path = out_dir.join(format!("{}.pdf", inv.number));
+            fs::write(&path, invoice::render_pdf(&inv))
+                .expect("Unable to write invoice");
+            println!("{}", path.display());
+        }
+    }
+}
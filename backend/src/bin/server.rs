@@ -1,11 +1,21 @@
 use actix_cors::Cors;
-use actix_identity::{CookieIdentityPolicy, IdentityService};
+use actix_files::Files;
+use actix_identity::IdentityService;
 use actix_web::middleware;
 use actix_web::{web, App, HttpServer};
 use diesel::r2d2::{ConnectionManager, Pool};
 use diesel::MysqlConnection;
+use furby::csrf::{allowed_origins, OriginCheck};
+use furby::handlers::payment::{webhook_secret, WebhookSecret};
 use furby::handlers::smoke::manual_hello;
-use furby::handlers::{cart_items, product, rating, transaction, users};
+use furby::handlers::{
+    account, api_tokens, cart_items, email_verification, moderation,
+    password_reset, payment, product, rating, returns, review_photos,
+    transaction, two_factor, users, wishlist,
+};
+use furby::idempotency::Idempotency;
+use furby::session::{login_cookie_policy, SessionPolicy};
+use furby::storage::LocalStorage;
 use rand::Rng;
 
 #[actix_web::main]
@@ -18,23 +28,22 @@ async fn main() -> std::io::Result<()> {
         .build(manager)
         .expect("Failed to create pool.");
 
+    let storage = LocalStorage::from_env();
+    std::fs::create_dir_all(&storage.root)?;
+
     let private_key = rand::thread_rng().gen::<[u8; 32]>();
+    let secret = webhook_secret();
+    let origins = allowed_origins();
     HttpServer::new(move || {
         App::new()
-            .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(&private_key)
-                    .name("user-login")
-                    .domain("127.0.0.1")
-                    .path("/")
-                    .same_site(actix_web::cookie::SameSite::None)
-                    .http_only(true)
-                    .secure(false),
-            ))
+            .wrap(IdentityService::new(SessionPolicy::new(
+                login_cookie_policy(&private_key),
+            )))
+            .wrap(OriginCheck::new(origins.clone()))
             .wrap(
-                Cors::default()
-                    .allowed_origin("http://127.0.0.1:8000")
-                    .allowed_origin("http://localhost:8000")
-                    .allowed_origin("https://poly.googleusercontent.com")
+                origins
+                    .iter()
+                    .fold(Cors::default(), |cors, o| cors.allowed_origin(o))
                     .allow_any_method()
                     .allow_any_header(),
             )
@@ -45,14 +54,61 @@ async fn main() -> std::io::Result<()> {
             )
             .wrap(middleware::Logger::default())
             .data(pool.clone())
+            .data(WebhookSecret(secret.clone()))
             .service(
                 web::scope("/user")
                     .route("/profile", web::get().to(users::user_profile))
+                    .route(
+                        "/profile/update",
+                        web::post().to(users::update_profile),
+                    )
                     .route("/existing", web::post().to(users::name_exists))
                     .route("/login", web::post().to(users::login))
+                    .route(
+                        "/login/verify",
+                        web::post().to(two_factor::verify_login),
+                    )
+                    .route("/2fa/enroll", web::post().to(two_factor::enroll))
+                    .route(
+                        "/2fa/confirm",
+                        web::post().to(two_factor::confirm_enrolment),
+                    )
+                    .route(
+                        "/2fa/recovery_codes",
+                        web::post().to(two_factor::regenerate_recovery_codes),
+                    )
+                    .route("/2fa/disable", web::post().to(two_factor::disable))
                     .route("/logout", web::post().to(users::logout))
+                    .route(
+                        "/verify_email",
+                        web::post().to(email_verification::verify_email),
+                    )
+                    .route(
+                        "/resend_verification",
+                        web::post().to(email_verification::resend_verification),
+                    )
+                    .route(
+                        "/forgot_password",
+                        web::post().to(password_reset::forgot_password),
+                    )
+                    .route(
+                        "/reset_password",
+                        web::post().to(password_reset::reset_password),
+                    )
+                    .route("/export", web::get().to(account::export_data))
+                    .route("/delete", web::post().to(account::delete_account))
+                    .route("/tokens", web::get().to(api_tokens::list_tokens))
+                    .route("/tokens/new", web::post().to(api_tokens::new_token))
+                    .route(
+                        "/tokens/{id}/revoke",
+                        web::post().to(api_tokens::revoke),
+                    )
                     .route("/{uname}", web::get().to(users::user_details))
-                    .route("/new", web::post().to(users::new_user))
+                    .service(
+                        web::resource("/new")
+                            .wrap(Idempotency::new("user/new"))
+                            .route(web::post().to(users::new_user)),
+                    )
                     .route(
                         "/change_password",
                         web::post().to(users::change_password),
@@ -67,6 +123,10 @@ async fn main() -> std::io::Result<()> {
                         "/reviews/{id}",
                         web::get().to(product::get_product_reviews),
                     )
+                    .route(
+                        "/rating_summary/{id}",
+                        web::get().to(rating::product_rating_summary),
+                    )
                     .route(
                         "/update_product/{id}",
                         web::post().to(product::update_product),
@@ -82,28 +142,176 @@ async fn main() -> std::io::Result<()> {
                         "/total",
                         web::get().to(cart_items::get_user_cart_total),
                     )
-                    .route("/add", web::post().to(cart_items::add_to_cart))
+                    .service(
+                        web::resource("/add")
+                            .wrap(Idempotency::new("cart/add"))
+                            .route(web::post().to(cart_items::add_to_cart)),
+                    )
                     .route(
                         "/remove",
                         web::post().to(cart_items::remove_from_cart),
+                    )
+                    .route(
+                        "/set",
+                        web::post().to(cart_items::set_cart_quantity),
+                    )
+                    .service(
+                        web::resource("/add_many")
+                            .wrap(Idempotency::new("cart/add_many"))
+                            .route(
+                                web::post().to(cart_items::add_cart_quantity),
+                            ),
+                    )
+                    .route(
+                        "/remove_line",
+                        web::post().to(cart_items::remove_cart_line),
+                    )
+                    .route("/clear", web::post().to(cart_items::clear_cart))
+                    .route(
+                        "/validate",
+                        web::get().to(cart_items::validate_user_cart),
+                    )
+                    .route(
+                        "/acknowledge",
+                        web::post().to(cart_items::acknowledge_cart),
                     ),
             )
             .service(
                 web::scope("/rating")
-                    .route("/add", web::post().to(rating::add_rating))
+                    .service(
+                        web::resource("/add")
+                            .wrap(Idempotency::new("rating/add"))
+                            .route(web::post().to(rating::add_rating)),
+                    )
+                    .route("/edit", web::post().to(rating::edit_rating))
+                    .route("/vote", web::post().to(rating::vote_rating))
+                    .route(
+                        "/{id}/photos",
+                        web::post().to(review_photos::upload_photos),
+                    )
+                    .route(
+                        "/{id}/photos/{photo_id}/remove",
+                        web::post().to(review_photos::remove_photo),
+                    )
+                    .route("/report", web::post().to(moderation::report_rating))
                     .route("/remove", web::post().to(rating::remove_rating)),
             )
             .service(
                 web::scope("/transaction")
-                    .route(
-                        "/checkout",
-                        web::post().to(transaction::checkout_cart),
+                    .service(
+                        web::resource("/checkout")
+                            .wrap(Idempotency::new("transaction/checkout"))
+                            .route(web::post().to(transaction::checkout_cart)),
                     )
                     .route(
                         "/list",
                         web::get().to(transaction::list_transactions),
+                    )
+                    .route("/cancel", web::post().to(transaction::cancel_order))
+                    .route("/{id}", web::get().to(transaction::order_details))
+                    .route(
+                        "/{id}/invoice",
+                        web::get().to(transaction::order_invoice),
+                    )
+                    .route(
+                        "/{id}/ship",
+                        web::post().to(transaction::ship_order),
+                    ),
+            )
+            .service(
+                web::scope("/returns")
+                    .route("/new", web::post().to(returns::request_return))
+                    .route("/list", web::get().to(returns::list_returns))
+                    .route(
+                        "/{id}/approve",
+                        web::post().to(returns::approve_return),
+                    )
+                    .route(
+                        "/{id}/reject",
+                        web::post().to(returns::reject_return),
+                    )
+                    .route(
+                        "/{id}/receive",
+                        web::post().to(returns::receive_return),
+                    ),
+            )
+            .service(
+                web::scope("/wishlist")
+                    .route("/list", web::get().to(wishlist::list_wishlists))
+                    .route("/new", web::post().to(wishlist::new_wishlist))
+                    .route(
+                        "/save_for_later",
+                        web::post().to(wishlist::save_for_later),
+                    )
+                    .route(
+                        "/shared/{token}",
+                        web::get().to(wishlist::shared_wishlist),
+                    )
+                    .route("/{id}", web::get().to(wishlist::wishlist_details))
+                    .route(
+                        "/{id}/rename",
+                        web::post().to(wishlist::rename_wishlist),
+                    )
+                    .route(
+                        "/{id}/delete",
+                        web::post().to(wishlist::delete_wishlist),
+                    )
+                    .route(
+                        "/{id}/add",
+                        web::post().to(wishlist::add_wishlist_item),
+                    )
+                    .route(
+                        "/{id}/remove",
+                        web::post().to(wishlist::remove_wishlist_item),
+                    )
+                    .route(
+                        "/{id}/move_to_cart",
+                        web::post().to(wishlist::move_to_cart),
+                    )
+                    .route(
+                        "/{id}/share",
+                        web::post().to(wishlist::share_wishlist),
+                    )
+                    .route(
+                        "/{id}/unshare",
+                        web::post().to(wishlist::unshare_wishlist),
+                    ),
+            )
+            .service(
+                web::scope("/admin")
+                    .route(
+                        "/require_staff_2fa",
+                        web::post().to(two_factor::require_for_staff),
+                    )
+                    .route(
+                        "/tokens",
+                        web::get().to(api_tokens::list_service_tokens),
+                    )
+                    .route(
+                        "/tokens/{id}/revoke",
+                        web::post().to(api_tokens::revoke_service_token),
                     ),
             )
+            .service(
+                web::scope("/moderation")
+                    .route(
+                        "/queue",
+                        web::get().to(moderation::moderation_queue),
+                    )
+                    .route(
+                        "/{id}/approve",
+                        web::post().to(moderation::approve_rating),
+                    )
+                    .route(
+                        "/{id}/reject",
+                        web::post().to(moderation::reject_rating),
+                    ),
+            )
+            .service(
+                web::scope("/payments")
+                    .route("/webhook", web::post().to(payment::webhook)),
+            )
+            .service(Files::new(&storage.base_url, &storage.root))
             .route("/hey", web::get().to(manual_hello))
     })
     .bind("127.0.0.1:7878")?
@@ -0,0 +1,59 @@
+use actix_web::client::Client;
+use furby::handlers::payment::{
+    sign_payload, webhook_secret, SIGNATURE_HEADER,
+};
+
+use std::{env, fs, process};
+
+const DEFAULT_URL: &str = "http://127.0.0.1:7878/payments/webhook";
+
+fn usage() -> ! {
+    eprintln!("usage: webhook [--url <endpoint>] <event.json>...");
+    eprintln!("signs each event with $PAYMENT_WEBHOOK_SECRET and replays it");
+    process::exit(1);
+}
+
+#[actix_web::main]
+async fn main() {
+    let mut url = String::from(DEFAULT_URL);
+    let mut events = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--url" => url = args.next().unwrap_or_else(|| usage()),
+            "-h" | "--help" => usage(),
+            _ => events.push(arg),
+        }
+    }
+    if events.is_empty() {
+        usage();
+    }
+
+    let secret = webhook_secret();
+    let client = Client::default();
+    for path in events {
+        let payload = fs::read(&path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        });
+        let signature = sign_payload(&secret, &payload);
+        let response = client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .send_body(payload)
+            .await;
+        match response {
+            Ok(mut r) => {
+                let body = r.body().await.unwrap_or_default();
+                println!(
+                    "{}: {} {}",
+                    path,
+                    r.status(),
+                    String::from_utf8_lossy(&body)
+                );
+            }
+            Err(e) => eprintln!("{}: {}", path, e),
+        }
+    }
+}
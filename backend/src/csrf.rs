@@ -0,0 +1,163 @@
+//! Cross-site request forgery checks. Browsers attach the login and guest
+//! cookies to requests from any site, so a mutating request that carries
+//! cookies is only let through when its `Origin` (or failing that its
+//! `Referer`) is the API itself or one of the allowed storefront origins.
+//!
+//! Requests without cookies can't ride on someone's session and are left
+//! alone, which keeps API tokens and the payment webhook working.
+
+use crate::notifications::store_url;
+use crate::session;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Method};
+use actix_web::{Error, HttpResponse};
+use futures::future::{ok, Either, Ready};
+use log::error;
+
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// Origins the storefront is served from, from `FURBY_ALLOWED_ORIGINS`
+/// (comma separated). Defaults to the origin of `FURBY_STORE_URL`, and in
+/// development to the usual local addresses as well.
+pub fn allowed_origins() -> Vec<String> {
+    let mut origins = match std::env::var("FURBY_ALLOWED_ORIGINS") {
+        Ok(list) => list.split(',').filter_map(origin_of).collect(),
+        Err(_) => {
+            let mut defaults: Vec<_> =
+                origin_of(&store_url()).into_iter().collect();
+            if !session::production() {
+                defaults.push(String::from("http://127.0.0.1:8000"));
+                defaults.push(String::from("http://localhost:8000"));
+            }
+            defaults
+        }
+    };
+    origins.sort();
+    origins.dedup();
+    origins
+}
+
+/// `scheme://host[:port]` of `url`, the way browsers send it in `Origin`.
+fn origin_of(url: &str) -> Option<String> {
+    let url = url.trim();
+    let rest_at = url.find("://")? + 3;
+    let end = url[rest_at..]
+        .find(&['/', '?', '#'][..])
+        .map_or(url.len(), |i| rest_at + i);
+    if end == rest_at {
+        return None;
+    }
+    Some(url[..end].to_lowercase())
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// Middleware refusing cross-site mutations, see the module docs.
+pub struct OriginCheck {
+    allowed: Rc<Vec<String>>,
+}
+
+impl OriginCheck {
+    pub fn new(allowed: Vec<String>) -> Self {
+        OriginCheck {
+            allowed: Rc::new(allowed),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for OriginCheck
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse<B>,
+        Error = Error,
+    >,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = OriginCheckMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(OriginCheckMiddleware {
+            service,
+            allowed: self.allowed.clone(),
+        })
+    }
+}
+
+pub struct OriginCheckMiddleware<S> {
+    service: S,
+    allowed: Rc<Vec<String>>,
+}
+
+impl<S> OriginCheckMiddleware<S> {
+    /// Why `req` looks forged, if it does.
+    fn refusal(&self, req: &ServiceRequest) -> Option<&'static str> {
+        if is_safe(req.method()) || !req.headers().contains_key(header::COOKIE)
+        {
+            return None;
+        }
+        let claimed = req
+            .headers()
+            .get(header::ORIGIN)
+            .or_else(|| req.headers().get(header::REFERER))
+            .and_then(|h| h.to_str().ok());
+        let origin = match claimed {
+            Some(c) => origin_of(c),
+            None => return Some("Missing Origin header"),
+        };
+        let own = {
+            let info = req.connection_info();
+            format!("{}://{}", info.scheme(), info.host()).to_lowercase()
+        };
+        match origin {
+            Some(o) if o == own || self.allowed.contains(&o) => None,
+            _ => Some("Cross-site request refused"),
+        }
+    }
+}
+
+impl<S, B> Service for OriginCheckMiddleware<S>
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse<B>,
+        Error = Error,
+    >,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        match self.refusal(&req) {
+            None => Either::Left(self.service.call(req)),
+            Some(reason) => {
+                error!(
+                    "{} to {} from {:?}",
+                    reason,
+                    req.path(),
+                    req.headers().get(header::ORIGIN)
+                );
+                Either::Right(ok(req.into_response(
+                    HttpResponse::Forbidden().body(reason).into_body(),
+                )))
+            }
+        }
+    }
+}
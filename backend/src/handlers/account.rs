@@ -0,0 +1,315 @@
+use crate::audit::{self, client_ip};
+use crate::handlers::review_photos::{
+    delete_photos, photos_of, remove_photo_files, ReviewPhoto,
+};
+use crate::handlers::transaction::{order_details_of, OrderDetails};
+use crate::handlers::two_factor;
+use crate::handlers::users::password_confirmed;
+use crate::handlers::wishlist::{wishlist_view, WishlistView};
+use crate::models::{
+    AddAuditEvent, AuditEvent, CartItem, Customer, Notification, Rating,
+    RatingReport, Transaction, Wishlist,
+};
+use crate::schema::api_token::dsl as at;
+use crate::schema::audit_log::dsl as al;
+use crate::schema::cart::dsl as ct;
+use crate::schema::cart_items::dsl as ci;
+use crate::schema::cart_reminder::dsl as cr;
+use crate::schema::customer::dsl::*;
+use crate::schema::customer_totp::dsl as totp;
+use crate::schema::notification_outbox::dsl as no;
+use crate::schema::rating::dsl as rs;
+use crate::schema::rating_duplicate::dsl as rd;
+use crate::schema::rating_report::dsl as rr;
+use crate::schema::rating_vote::dsl as rv;
+use crate::schema::recovery_code::dsl as rc;
+use crate::schema::transaction::dsl as ts;
+use crate::schema::user_token::dsl as ut;
+use crate::schema::wishlist::dsl as wl;
+use crate::schema::wishlist_item::dsl as wi;
+use crate::session::end_all_sessions;
+use crate::storage::LocalStorage;
+use crate::TPool;
+
+use actix_identity::Identity;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::naive::NaiveDateTime;
+use diesel::dsl::now;
+use diesel::prelude::*;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+pub const ACCOUNT_DELETED: &str = "account_deleted";
+
+#[derive(Serialize)]
+struct Profile {
+    id: i32,
+    username: String,
+    email_id: String,
+    email_verified: bool,
+    phone_number: String,
+    address: Option<String>,
+    role: String,
+    two_factor_enabled: bool,
+    joined: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct ExportedReview {
+    #[serde(flatten)]
+    review: Rating,
+    photos: Vec<ReviewPhoto>,
+}
+
+#[derive(Serialize)]
+struct ReviewVote {
+    rating_id: i32,
+    helpful: bool,
+    created_at: NaiveDateTime,
+}
+
+/// Everything the store keeps about a customer.
+#[derive(Serialize)]
+struct PersonalData {
+    exported_at: NaiveDateTime,
+    profile: Profile,
+    orders: Vec<OrderDetails>,
+    reviews: Vec<ExportedReview>,
+    review_votes: Vec<ReviewVote>,
+    review_reports: Vec<RatingReport>,
+    cart: Vec<CartItem>,
+    wishlists: Vec<WishlistView>,
+    notifications: Vec<Notification>,
+    security_events: Vec<AuditEvent>,
+}
+
+fn personal_data(
+    conn: &MysqlConnection,
+    member: Customer,
+) -> QueryResult<PersonalData> {
+    let storage = LocalStorage::from_env();
+    let exported_at = diesel::select(now).first::<NaiveDateTime>(conn)?;
+    let orders = ts::transaction
+        .filter(ts::customer_id.eq(member.id))
+        .order(ts::id.asc())
+        .load::<Transaction>(conn)?
+        .into_iter()
+        .map(|o| order_details_of(conn, o))
+        .collect::<QueryResult<Vec<_>>>()?;
+    let reviews = rs::rating
+        .filter(rs::customer_id.eq(member.id))
+        .order(rs::id.asc())
+        .load::<Rating>(conn)?;
+    let rids = reviews.iter().map(|r| r.id).collect::<Vec<_>>();
+    let photos = photos_of(conn, &rids)?;
+    let reviews = reviews
+        .into_iter()
+        .map(|review| ExportedReview {
+            photos: photos
+                .iter()
+                .filter(|p| p.rating_id == review.id)
+                .map(|p| ReviewPhoto::new(&storage, p))
+                .collect(),
+            review,
+        })
+        .collect();
+    let review_votes = rv::rating_vote
+        .filter(rv::customer_id.eq(member.id))
+        .select((rv::rating_id, rv::helpful, rv::created_at))
+        .load::<(i32, bool, NaiveDateTime)>(conn)?
+        .into_iter()
+        .map(|(rid, helpful, voted_at)| ReviewVote {
+            rating_id: rid,
+            helpful,
+            created_at: voted_at,
+        })
+        .collect();
+    let review_reports = rr::rating_report
+        .filter(rr::customer_id.eq(member.id))
+        .load::<RatingReport>(conn)?;
+    let cart = ci::cart_items
+        .filter(ci::cart_id.eq(member.id))
+        .load::<CartItem>(conn)?;
+    let wishlists = wl::wishlist
+        .filter(wl::customer_id.eq(member.id))
+        .load::<Wishlist>(conn)?
+        .into_iter()
+        .map(|w| wishlist_view(conn, w))
+        .collect::<QueryResult<Vec<_>>>()?;
+    let notifications = no::notification_outbox
+        .filter(no::customer_id.eq(member.id))
+        .order(no::id.asc())
+        .load::<Notification>(conn)?;
+    let security_events = al::audit_log
+        .filter(al::customer_id.eq(member.id))
+        .order(al::id.asc())
+        .load::<AuditEvent>(conn)?;
+    Ok(PersonalData {
+        exported_at,
+        profile: Profile {
+            two_factor_enabled: two_factor::enabled(conn, member.id)?,
+            id: member.id,
+            username: member.username,
+            email_id: member.email_id,
+            email_verified: member.email_verified,
+            phone_number: member.phone_number,
+            address: member.address,
+            role: member.role,
+            joined: member.created_at,
+        },
+        orders,
+        reviews,
+        review_votes,
+        review_reports,
+        cart,
+        wishlists,
+        notifications,
+        security_events,
+    })
+}
+
+/// Downloads everything the store keeps about the logged in customer as a
+/// JSON file.
+pub async fn export_data(
+    cookie: Identity,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    if let Some(uname) = cookie.identity() {
+        let selected_user = customer
+            .filter(username.eq(&uname))
+            .limit(1)
+            .first::<Customer>(&conn)
+            .expect("Couldn't connect to DB");
+        info!("Exporting personal data of {}", uname);
+        let data = personal_data(&conn, selected_user)
+            .expect("Couldn't connect to DB");
+        HttpResponse::Ok()
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"furby-{}.json\"", uname),
+            )
+            .json(&data)
+    } else {
+        HttpResponse::Unauthorized()
+            .body("Need to be logged in to export your data!")
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccount {
+    password: String,
+}
+
+/// Removes the personal data of `member`. Orders and returns stay for the
+/// books and reviews stay up, all pointing at the anonymised customer row.
+/// Returns the review photo files to remove once this is committed.
+fn anonymise(
+    conn: &MysqlConnection,
+    member: &Customer,
+) -> QueryResult<Vec<String>> {
+    let cid = member.id;
+    diesel::delete(ci::cart_items.filter(ci::cart_id.eq(cid))).execute(conn)?;
+    diesel::delete(cr::cart_reminder.filter(cr::customer_id.eq(cid)))
+        .execute(conn)?;
+    diesel::delete(ct::cart.find(cid)).execute(conn)?;
+    let wishlists = wl::wishlist
+        .filter(wl::customer_id.eq(cid))
+        .select(wl::id)
+        .load::<i32>(conn)?;
+    diesel::delete(
+        wi::wishlist_item.filter(wi::wishlist_id.eq_any(&wishlists)),
+    )
+    .execute(conn)?;
+    diesel::delete(wl::wishlist.filter(wl::customer_id.eq(cid)))
+        .execute(conn)?;
+    diesel::delete(rv::rating_vote.filter(rv::customer_id.eq(cid)))
+        .execute(conn)?;
+    diesel::delete(rr::rating_report.filter(rr::customer_id.eq(cid)))
+        .execute(conn)?;
+    diesel::delete(rd::rating_duplicate.filter(rd::customer_id.eq(cid)))
+        .execute(conn)?;
+    let mut photo_files = vec![];
+    for rid in rs::rating
+        .filter(rs::customer_id.eq(cid))
+        .select(rs::id)
+        .load::<i32>(conn)?
+    {
+        photo_files.extend(delete_photos(conn, rid)?);
+    }
+    diesel::delete(no::notification_outbox.filter(no::customer_id.eq(cid)))
+        .execute(conn)?;
+    diesel::delete(ut::user_token.filter(ut::customer_id.eq(cid)))
+        .execute(conn)?;
+    diesel::delete(at::api_token.filter(at::customer_id.eq(cid)))
+        .execute(conn)?;
+    diesel::delete(totp::customer_totp.find(cid)).execute(conn)?;
+    diesel::delete(rc::recovery_code.filter(rc::customer_id.eq(cid)))
+        .execute(conn)?;
+    diesel::update(al::audit_log.filter(al::customer_id.eq(cid)))
+        .set((al::username.eq(None::<String>), al::ip.eq(None::<String>)))
+        .execute(conn)?;
+
+    let deleted_now = diesel::select(now).first::<NaiveDateTime>(conn)?;
+    // names with a ':' can't be signed up for, so they never clash
+    diesel::update(customer.find(cid))
+        .set((
+            username.eq(format!("deleted:{}", cid)),
+            password.eq(""),
+            phone_number.eq("0000000000"),
+            email_id.eq(format!("deleted:{}@invalid", cid)),
+            address.eq(None::<String>),
+            role.eq("customer"),
+            email_verified.eq(false),
+            deleted_at.eq(deleted_now),
+        ))
+        .execute(conn)?;
+    Ok(photo_files)
+}
+
+/// Deletes the logged in customer's account after checking their
+/// password, and logs them out everywhere.
+pub async fn delete_account(
+    req: HttpRequest,
+    cookie: Identity,
+    details: web::Json<DeleteAccount>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let uname = match cookie.identity() {
+        Some(u) => u,
+        None => {
+            return HttpResponse::Unauthorized()
+                .body("Need to be logged in to delete your account!")
+        }
+    };
+    let selected_user = customer
+        .filter(username.eq(&uname))
+        .limit(1)
+        .first::<Customer>(&conn)
+        .expect("Couldn't connect to DB");
+    match password_confirmed(&req, &conn, &selected_user, &details.password) {
+        Ok(true) => (),
+        Ok(false) => return HttpResponse::Forbidden().body("Invalid password"),
+        Err(resp) => return resp,
+    }
+    let photo_files = conn
+        .transaction(|| anonymise(&conn, &selected_user))
+        .expect("Couldn't connect to DB");
+    remove_photo_files(&photo_files);
+    audit::record(
+        &conn,
+        AddAuditEvent {
+            event: ACCOUNT_DELETED.to_string(),
+            customer_id: Some(selected_user.id),
+            ip: client_ip(&req),
+            ..Default::default()
+        },
+    );
+    info!("Deleted account {}", selected_user.id);
+    if let Err(e) = end_all_sessions(&uname) {
+        error!("Unable to end sessions of {}: {}", uname, e);
+    }
+    cookie.forget();
+    HttpResponse::Ok().body("Account deleted")
+}
@@ -0,0 +1,288 @@
+use crate::api_tokens::{self, MAX_DAYS, PERSONAL, SERVICE};
+use crate::audit::{self, client_ip};
+use crate::handlers::users::{admin_member, staff_member};
+use crate::models::{AddAuditEvent, ApiToken, Customer};
+use crate::schema::api_token::dsl as at;
+use crate::schema::customer::dsl::*;
+use crate::validation::FieldErrors;
+use crate::TPool;
+
+use actix_identity::Identity;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::naive::NaiveDateTime;
+use diesel::prelude::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+pub const API_TOKEN_CREATED: &str = "api_token_created";
+pub const API_TOKEN_REVOKED: &str = "api_token_revoked";
+
+/// Length of `api_token.name`.
+const MAX_NAME_CHARS: usize = 64;
+
+/// A token as listed to its owner, without anything that would let it be
+/// used.
+#[derive(Serialize)]
+pub struct TokenSummary {
+    id: i32,
+    name: String,
+    kind: String,
+    owner: String,
+    token_prefix: String,
+    scopes: Vec<String>,
+    expires_at: NaiveDateTime,
+    last_used_at: Option<NaiveDateTime>,
+    revoked_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+impl TokenSummary {
+    fn new(token: ApiToken, owner: String) -> Self {
+        TokenSummary {
+            id: token.id,
+            scopes: token.scopes.split(',').map(String::from).collect(),
+            name: token.name,
+            kind: token.kind,
+            owner,
+            token_prefix: token.token_prefix,
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+            revoked_at: token.revoked_at,
+            created_at: token.created_at,
+        }
+    }
+}
+
+fn logged_in_member(
+    cookie: &Identity,
+    conn: &MysqlConnection,
+) -> Result<Customer, HttpResponse> {
+    let uname = cookie.identity().ok_or_else(|| {
+        HttpResponse::Unauthorized()
+            .body("Need to be logged in to manage API tokens!")
+    })?;
+    Ok(customer
+        .filter(username.eq(&uname))
+        .limit(1)
+        .first::<Customer>(conn)
+        .expect("Couldn't connect to DB"))
+}
+
+#[derive(Deserialize)]
+pub struct NewToken {
+    name: String,
+    scopes: Vec<String>,
+    expires_in_days: Option<i64>,
+    kind: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    id: i32,
+    token: String,
+    expires_in_days: i64,
+}
+
+/// Creates a token for the logged in customer. The token is in the answer
+/// and can't be looked up again later. Service tokens are for staff only.
+pub async fn new_token(
+    req: HttpRequest,
+    cookie: Identity,
+    details: web::Json<NewToken>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let token_kind = details.kind.as_deref().unwrap_or(PERSONAL);
+    let member = match token_kind {
+        PERSONAL => logged_in_member(&cookie, &conn),
+        SERVICE => staff_member(&cookie, &conn),
+        _ => {
+            return HttpResponse::BadRequest()
+                .body("Token kind must be personal or service")
+        }
+    };
+    let member = match member {
+        Ok(m) => m,
+        Err(resp) => return resp,
+    };
+    let days = details
+        .expires_in_days
+        .unwrap_or_else(api_tokens::default_days);
+
+    let mut errors = FieldErrors::default();
+    let name_length = details.name.trim().chars().count();
+    if name_length == 0 || name_length > MAX_NAME_CHARS {
+        errors.check(
+            "name",
+            Err(format!("must be 1 to {} characters long", MAX_NAME_CHARS)),
+        );
+    }
+    if details.scopes.is_empty() {
+        errors.check("scopes", Err(String::from("must not be empty")));
+    }
+    for s in details.scopes.iter().filter(|s| !api_tokens::is_scope(s)) {
+        errors.check(
+            "scopes",
+            Err(format!(
+                "unknown scope {}, known are {}",
+                s,
+                api_tokens::known_scopes().join(", ")
+            )),
+        );
+    }
+    if days < 1 {
+        errors
+            .check("expires_in_days", Err(String::from("must be at least 1")));
+    }
+    if let Err(resp) = errors.into_result() {
+        return resp;
+    }
+
+    let mut scopes = details.scopes.clone();
+    scopes.sort();
+    scopes.dedup();
+    let (tid, token) = api_tokens::create(
+        &conn,
+        member.id,
+        details.name.trim(),
+        token_kind,
+        &scopes,
+        days,
+    )
+    .expect("Couldn't connect to DB");
+    audit::record(
+        &conn,
+        AddAuditEvent {
+            event: API_TOKEN_CREATED.to_string(),
+            customer_id: Some(member.id),
+            username: Some(member.username.clone()),
+            ip: client_ip(&req),
+            ..Default::default()
+        },
+    );
+    info!(
+        "{} created {} API token {}",
+        member.username, token_kind, tid
+    );
+    HttpResponse::Ok().json(CreatedToken {
+        id: tid,
+        token,
+        expires_in_days: days.min(MAX_DAYS),
+    })
+}
+
+/// Tokens of the logged in customer, revoked and expired ones included.
+pub async fn list_tokens(
+    cookie: Identity,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let member = match logged_in_member(&cookie, &conn) {
+        Ok(m) => m,
+        Err(resp) => return resp,
+    };
+    let tokens = at::api_token
+        .filter(at::customer_id.eq(member.id))
+        .order(at::id.desc())
+        .load::<ApiToken>(&conn)
+        .expect("Couldn't connect to DB")
+        .into_iter()
+        .map(|t| TokenSummary::new(t, member.username.clone()))
+        .collect::<Vec<_>>();
+    HttpResponse::Ok().json(tokens)
+}
+
+fn revoke_token(
+    req: &HttpRequest,
+    conn: &MysqlConnection,
+    by: &Customer,
+    token: &ApiToken,
+) -> HttpResponse {
+    if token.revoked_at.is_some() {
+        return HttpResponse::Ok().body("Token was already revoked");
+    }
+    api_tokens::revoke(conn, token.id).expect("Couldn't connect to DB");
+    audit::record(
+        conn,
+        AddAuditEvent {
+            event: API_TOKEN_REVOKED.to_string(),
+            customer_id: Some(token.customer_id),
+            username: Some(by.username.clone()),
+            ip: client_ip(req),
+            ..Default::default()
+        },
+    );
+    info!("{} revoked API token {}", by.username, token.id);
+    HttpResponse::Ok().body("Token revoked")
+}
+
+/// Revokes one of the logged in customer's tokens.
+pub async fn revoke(
+    req: HttpRequest,
+    cookie: Identity,
+    tid: web::Path<i32>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let member = match logged_in_member(&cookie, &conn) {
+        Ok(m) => m,
+        Err(resp) => return resp,
+    };
+    match at::api_token
+        .find(*tid)
+        .filter(at::customer_id.eq(member.id))
+        .first::<ApiToken>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB")
+    {
+        Some(token) => revoke_token(&req, &conn, &member, &token),
+        None => HttpResponse::NotFound().body("No such token"),
+    }
+}
+
+/// Service tokens of all staff, for admins to keep an eye on.
+pub async fn list_service_tokens(
+    cookie: Identity,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    if let Err(resp) = admin_member(&cookie, &conn) {
+        return resp;
+    }
+    let tokens = at::api_token
+        .inner_join(customer)
+        .filter(at::kind.eq(SERVICE))
+        .order(at::id.desc())
+        .select((at::api_token::all_columns(), username))
+        .load::<(ApiToken, String)>(&conn)
+        .expect("Couldn't connect to DB")
+        .into_iter()
+        .map(|(t, owner)| TokenSummary::new(t, owner))
+        .collect::<Vec<_>>();
+    HttpResponse::Ok().json(tokens)
+}
+
+/// Lets admins revoke any service token, say when an integration is
+/// retired or its token leaked.
+pub async fn revoke_service_token(
+    req: HttpRequest,
+    cookie: Identity,
+    tid: web::Path<i32>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let admin = match admin_member(&cookie, &conn) {
+        Ok(a) => a,
+        Err(resp) => return resp,
+    };
+    match at::api_token
+        .find(*tid)
+        .filter(at::kind.eq(SERVICE))
+        .first::<ApiToken>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB")
+    {
+        Some(token) => revoke_token(&req, &conn, &admin, &token),
+        None => HttpResponse::NotFound().body("No such service token"),
+    }
+}
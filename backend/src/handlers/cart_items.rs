@@ -1,204 +1,735 @@
-use crate::models::{AddCartItem, CartItem, Customer, Product};
+use crate::handlers::payment::{sign_payload, verify_signature};
+use crate::models::{
+    AddCartItem, AddGuestCartItem, CartIssue, CartItem, Customer, Product,
+    TouchCart,
+};
+use crate::schema::cart::dsl as ct;
+use crate::schema::guest_cart_items::dsl as guest;
 use crate::schema::product::dsl as prod;
 use crate::schema::{cart_items::dsl::*, customer::dsl::*};
+use crate::session::harden;
 use crate::TPool;
 
 use actix_identity::Identity;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::http::Cookie;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
 use diesel::prelude::*;
 use log::{error, info};
-use serde::Serialize;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
-pub async fn add_to_cart(
-    cookie: Identity,
-    item_id: String,
-    pool: web::Data<TPool>,
-) -> impl Responder {
-    let item_details = item_id.parse::<i32>().unwrap_or(-1);
-    info!("Add to cart hit: {:?}", item_details);
-    info!("[cart] Current user: {:?}", cookie.identity());
-    let conn = pool.get().unwrap();
+pub const GUEST_COOKIE: &str = "furby-guest";
+
+fn max_order_quantity() -> i32 {
+    std::env::var("FURBY_MAX_ORDER_QUANTITY")
+        .ok()
+        .and_then(|q| q.parse().ok())
+        .unwrap_or(10)
+}
+
+fn guest_secret() -> String {
+    std::env::var("FURBY_GUEST_SECRET").expect("FURBY_GUEST_SECRET must be set")
+}
+
+/// Whose cart a request operates on: a customer, or a visitor identified
+/// by a signed guest cookie.
+pub enum CartOwner {
+    Customer(i32),
+    Guest(String),
+}
+
+/// Returns the guest id carried by the request, if its signature is valid.
+pub fn guest_id(req: &HttpRequest) -> Option<String> {
+    let cookie = req.cookie(GUEST_COOKIE)?;
+    let mut parts = cookie.value().splitn(2, '.');
+    let (gid, signature) = (parts.next()?, parts.next()?);
+    if verify_signature(&guest_secret(), gid.as_bytes(), signature) {
+        Some(gid.to_string())
+    } else {
+        error!("Guest cookie with invalid signature");
+        None
+    }
+}
+
+fn guest_cookie(gid: &str) -> Cookie<'static> {
+    let signature = sign_payload(&guest_secret(), gid.as_bytes());
+    harden(Cookie::build(
+        GUEST_COOKIE,
+        format!("{}.{}", gid, signature),
+    ))
+    .path("/")
+    .permanent()
+    .finish()
+}
+
+/// Bare guest cookie, to drop it with `HttpResponseBuilder::del_cookie`.
+pub fn guest_cookie_stub() -> Cookie<'static> {
+    Cookie::build(GUEST_COOKIE, "").path("/").finish()
+}
+
+fn cart_owner(
+    req: &HttpRequest,
+    cookie: &Identity,
+    conn: &MysqlConnection,
+) -> Option<CartOwner> {
     if let Some(uname) = cookie.identity() {
         let selected_user = customer
             .filter(username.eq(&uname))
             .limit(1)
-            .first::<Customer>(&conn)
+            .first::<Customer>(conn)
             .expect("Couldn't connect to DB");
-        let new_cart_item = AddCartItem {
-            cart_id: selected_user.id,
-            product_id: item_details,
-            quantity: Some(1),
-        };
-        info!(
-            "cart id: {:?}, product id {:?}",
-            selected_user.id, item_details
-        );
-        let current_entry = cart_items
-            .filter(cart_id.eq(selected_user.id))
-            .filter(product_id.eq(item_details))
-            .limit(1)
-            .first::<CartItem>(&conn);
-        match current_entry {
-            Ok(v) => {
-                info!("Item already present in cart, increasing quantity.");
-                let old_quantity = v.quantity.unwrap_or(1);
+        Some(CartOwner::Customer(selected_user.id))
+    } else {
+        guest_id(req).map(CartOwner::Guest)
+    }
+}
+
+/// Like `cart_owner`, but starts a new guest cart for anonymous visitors.
+/// The second value is the cookie that has to be set on the response.
+fn cart_owner_or_guest(
+    req: &HttpRequest,
+    cookie: &Identity,
+    conn: &MysqlConnection,
+) -> (CartOwner, Option<Cookie<'static>>) {
+    match cart_owner(req, cookie, conn) {
+        Some(owner) => (owner, None),
+        None => {
+            let gid = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+            info!("Starting guest cart {}", gid);
+            let cookie = guest_cookie(&gid);
+            (CartOwner::Guest(gid), Some(cookie))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CartError {
+    ProductNotFound,
+    InvalidQuantity(String),
+    Unavailable(String),
+    Db(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for CartError {
+    fn from(e: diesel::result::Error) -> Self {
+        CartError::Db(e)
+    }
+}
+
+impl CartError {
+    pub fn into_response(self) -> HttpResponse {
+        match self {
+            CartError::ProductNotFound => {
+                HttpResponse::NotFound().body("Product not found")
+            }
+            CartError::InvalidQuantity(reason) => {
+                HttpResponse::BadRequest().body(reason)
+            }
+            CartError::Unavailable(name) => HttpResponse::Conflict()
+                .body(format!("{} is no longer available", name)),
+            CartError::Db(e) => {
+                error!("Cart update failed: {}", e);
+                HttpResponse::InternalServerError()
+                    .body("Unable to update cart")
+            }
+        }
+    }
+}
+
+/// Checks a requested line quantity against the stock and the order limit
+/// of the product.
+pub fn check_quantity(p: &Product, requested: i32) -> Result<(), CartError> {
+    let limit = p.max_order_quantity.unwrap_or_else(max_order_quantity);
+    if requested > limit {
+        return Err(CartError::InvalidQuantity(format!(
+            "At most {} of {} can be ordered at once",
+            limit, p.name
+        )));
+    }
+    match p.stock {
+        Some(s) if requested > s => Err(CartError::InvalidQuantity(format!(
+            "Only {} of {} left in stock",
+            s.max(0),
+            p.name
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Largest quantity of `p` a single cart line may hold.
+fn quantity_cap(p: &Product) -> i32 {
+    let limit = p.max_order_quantity.unwrap_or_else(max_order_quantity);
+    p.stock.map_or(limit, |s| limit.min(s.max(0)))
+}
+
+/// A cart line, with the unit price the product had when it was added.
+struct Line {
+    product_id: i32,
+    quantity: i32,
+    unit_price: Option<f32>,
+}
+
+fn cart_lines(
+    conn: &MysqlConnection,
+    owner: &CartOwner,
+) -> QueryResult<Vec<Line>> {
+    match owner {
+        CartOwner::Customer(cid) => Ok(cart_items
+            .filter(cart_id.eq(cid))
+            .load::<CartItem>(conn)?
+            .into_iter()
+            .map(|item| Line {
+                product_id: item.product_id,
+                quantity: item.quantity.unwrap_or(1),
+                unit_price: item.unit_price,
+            })
+            .collect()),
+        CartOwner::Guest(gid) => Ok(guest::guest_cart_items
+            .filter(guest::guest_id.eq(gid))
+            .select((guest::product_id, guest::quantity, guest::unit_price))
+            .load::<(i32, i32, Option<f32>)>(conn)?
+            .into_iter()
+            .map(|(pid, q, p)| Line {
+                product_id: pid,
+                quantity: q,
+                unit_price: p,
+            })
+            .collect()),
+    }
+}
+
+pub fn current_quantity(
+    conn: &MysqlConnection,
+    owner: &CartOwner,
+    pid: i32,
+) -> QueryResult<i32> {
+    Ok(cart_lines(conn, owner)?
+        .into_iter()
+        .find(|l| l.product_id == pid)
+        .map(|l| l.quantity)
+        .unwrap_or(0))
+}
+
+/// Records a change to a customer's cart, which also restarts its
+/// abandoned cart reminders.
+pub fn touch(conn: &MysqlConnection, owner: &CartOwner) -> QueryResult<()> {
+    if let CartOwner::Customer(cid) = owner {
+        diesel::replace_into(ct::cart)
+            .values(TouchCart { customer_id: *cid })
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Sets the quantity of a cart line, removing the line at zero. New lines
+/// remember the current price of the product. Stock and order limits are
+/// only checked when the quantity goes up.
+pub fn set_quantity(
+    conn: &MysqlConnection,
+    owner: &CartOwner,
+    pid: i32,
+    new_quantity: i32,
+) -> Result<(), CartError> {
+    if new_quantity < 0 {
+        return Err(CartError::InvalidQuantity(String::from(
+            "Quantity cannot be negative",
+        )));
+    }
+    let existing = current_quantity(conn, owner, pid)?;
+    let mut snapshot = None;
+    if new_quantity > 0 {
+        let selected_product = prod::product
+            .filter(prod::id.eq(pid))
+            .first::<Product>(conn)
+            .optional()?
+            .ok_or(CartError::ProductNotFound)?;
+        // lowering a line is always fine, even when stock has dropped
+        // below it since it was added
+        if new_quantity > existing {
+            if selected_product.archived {
+                return Err(CartError::Unavailable(selected_product.name));
+            }
+            check_quantity(&selected_product, new_quantity)?;
+        }
+        snapshot = Some(selected_product.price);
+    }
+    let exists = existing != 0;
+    match owner {
+        CartOwner::Customer(cid) => {
+            let line = cart_items
+                .filter(cart_id.eq(cid))
+                .filter(product_id.eq(pid));
+            if new_quantity == 0 {
+                diesel::delete(line).execute(conn)?;
+            } else if exists {
+                diesel::update(line)
+                    .set(quantity.eq(new_quantity))
+                    .execute(conn)?;
+            } else {
+                diesel::insert_into(cart_items)
+                    .values(AddCartItem {
+                        cart_id: *cid,
+                        product_id: pid,
+                        quantity: Some(new_quantity),
+                        unit_price: snapshot,
+                    })
+                    .execute(conn)?;
+            }
+        }
+        CartOwner::Guest(gid) => {
+            let line = guest::guest_cart_items
+                .filter(guest::guest_id.eq(gid))
+                .filter(guest::product_id.eq(pid));
+            if new_quantity == 0 {
+                diesel::delete(line).execute(conn)?;
+            } else if exists {
+                diesel::update(line)
+                    .set(guest::quantity.eq(new_quantity))
+                    .execute(conn)?;
+            } else {
+                diesel::insert_into(guest::guest_cart_items)
+                    .values(AddGuestCartItem {
+                        guest_id: gid.clone(),
+                        product_id: pid,
+                        quantity: new_quantity,
+                        unit_price: snapshot,
+                    })
+                    .execute(conn)?;
+            }
+        }
+    }
+    touch(conn, owner)?;
+    Ok(())
+}
+
+fn clear(conn: &MysqlConnection, owner: &CartOwner) -> QueryResult<usize> {
+    touch(conn, owner)?;
+    match owner {
+        CartOwner::Customer(cid) => {
+            diesel::delete(cart_items.filter(cart_id.eq(cid))).execute(conn)
+        }
+        CartOwner::Guest(gid) => diesel::delete(
+            guest::guest_cart_items.filter(guest::guest_id.eq(gid)),
+        )
+        .execute(conn),
+    }
+}
+
+fn merge_rule() -> String {
+    std::env::var("FURBY_CART_MERGE").unwrap_or_else(|_| String::from("sum"))
+}
+
+/// Moves the guest cart of `req` into the cart of customer `cid`.
+/// Quantities of products present in both carts are summed, or the larger
+/// one is kept when `FURBY_CART_MERGE=max`, and capped by stock and order
+/// limits. Lines of archived or deleted products, or that can't be added
+/// for another reason, are dropped and logged. Returns whether the request
+/// carried a guest cart.
+pub fn merge_guest_cart(
+    conn: &MysqlConnection,
+    req: &HttpRequest,
+    cid: i32,
+) -> QueryResult<bool> {
+    let gid = match guest_id(req) {
+        Some(g) => g,
+        None => return Ok(false),
+    };
+    let guest_owner = CartOwner::Guest(gid);
+    let owner = CartOwner::Customer(cid);
+    let take_max = merge_rule() == "max";
+    conn.transaction(|| {
+        for line in cart_lines(conn, &guest_owner)? {
+            let pid = line.product_id;
+            let selected_product = match prod::product
+                .find(pid)
+                .first::<Product>(conn)
+                .optional()?
+            {
+                Some(p) if !p.archived => p,
+                _ => continue,
+            };
+            let existing = current_quantity(conn, &owner, pid)?;
+            let merged = if take_max {
+                existing.max(line.quantity)
+            } else {
+                existing + line.quantity
+            };
+            let merged = merged.min(quantity_cap(&selected_product));
+            match set_quantity(conn, &owner, pid, merged) {
+                Ok(_) => (),
+                Err(CartError::Db(e)) => return Err(e),
+                Err(e) => {
+                    error!(
+                        "Dropping product {} from guest cart of {}: {:?}",
+                        pid, cid, e
+                    );
+                    continue;
+                }
+            }
+            if existing == 0 {
+                // keep the price the visitor saw when adding the product
                 diesel::update(
                     cart_items
-                        .filter(cart_id.eq(selected_user.id))
-                        .filter(product_id.eq(item_details)),
+                        .filter(cart_id.eq(cid))
+                        .filter(product_id.eq(pid)),
                 )
-                .set(quantity.eq(old_quantity + 1))
-                .execute(&conn)
-                .unwrap();
-                return HttpResponse::Ok()
-                    .body("Updated quantity successfully!");
-            }
-            Err(_) => {
-                info!("Item not present, adding to cart.");
-                diesel::insert_into(cart_items)
-                    .values(new_cart_item)
-                    .execute(&conn)
-                    .expect("Couldn't connect to DB");
-                HttpResponse::Ok().body("Inserted successfully!")
+                .set(unit_price.eq(line.unit_price))
+                .execute(conn)?;
             }
         }
-    } else {
-        error!("Unauthorized add to cart action!");
-        return HttpResponse::Unauthorized()
-            .body("Need to be logged in to add to cart!");
+        clear(conn, &guest_owner)?;
+        Ok(())
+    })?;
+    info!("Merged guest cart into cart {}", cid);
+    Ok(true)
+}
+
+fn with_cookie(
+    mut resp: HttpResponse,
+    cookie: Option<Cookie<'static>>,
+) -> HttpResponse {
+    if let Some(c) = cookie {
+        let _ = resp.add_cookie(&c);
     }
+    resp
+}
+
+pub async fn add_to_cart(
+    req: HttpRequest,
+    cookie: Identity,
+    item_id: String,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let item_details = match item_id.trim().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => {
+            error!("Invalid product id: {:?}", item_id);
+            return HttpResponse::BadRequest().body("Invalid product id");
+        }
+    };
+    info!("Add to cart hit: {:?}", item_details);
+    info!("[cart] Current user: {:?}", cookie.identity());
+    let conn = pool.get().unwrap();
+    let (owner, new_guest) = cart_owner_or_guest(&req, &cookie, &conn);
+    let old_quantity = current_quantity(&conn, &owner, item_details)
+        .expect("Couldn't connect to DB");
+    let resp = match set_quantity(&conn, &owner, item_details, old_quantity + 1)
+    {
+        Ok(_) if old_quantity == 0 => {
+            HttpResponse::Ok().body("Inserted successfully!")
+        }
+        Ok(_) => HttpResponse::Ok().body("Updated quantity successfully!"),
+        Err(e) => e.into_response(),
+    };
+    with_cookie(resp, new_guest)
 }
 
 pub async fn remove_from_cart(
+    req: HttpRequest,
     cookie: Identity,
     item_id: String,
     pool: web::Data<TPool>,
 ) -> impl Responder {
     info!("Remove from cart hit: {:?}", item_id);
-    let item_details = item_id.parse::<i32>().unwrap_or(-1);
+    let item_details = match item_id.trim().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => {
+            error!("Invalid product id: {:?}", item_id);
+            return HttpResponse::BadRequest().body("Invalid product id");
+        }
+    };
     let conn = pool.get().unwrap();
-    if let Some(uname) = cookie.identity() {
-        let selected_user = customer
-            .filter(username.eq(&uname))
-            .limit(1)
-            .first::<Customer>(&conn)
-            .expect("Couldn't connect to DB");
-        let current_entry = cart_items
-            .filter(cart_id.eq(selected_user.id))
-            .filter(product_id.eq(item_details))
-            .limit(1)
-            .first::<CartItem>(&conn);
-        match current_entry {
-            Ok(v) => {
-                info!("Item already present in cart, increasing quantity.");
-                let old_quantity = v.quantity.unwrap_or(1);
-                if old_quantity == 1 {
-                    diesel::delete(
-                        cart_items
-                            .filter(cart_id.eq(selected_user.id))
-                            .filter(product_id.eq(item_details)),
-                    )
-                    .execute(&conn)
-                    .expect("Coundn't connect to DB");
-                } else {
-                    diesel::update(
-                        cart_items
-                            .filter(cart_id.eq(selected_user.id))
-                            .filter(product_id.eq(item_details)),
-                    )
-                    .set(quantity.eq(old_quantity - 1))
-                    .execute(&conn)
-                    .unwrap();
-                    return HttpResponse::Ok()
-                        .body("Updated quantity successfully!");
-                }
-                return HttpResponse::Ok()
-                    .body("Updated quantity successfully!");
-            }
-            Err(_) => {
-                info!("Item not present.");
-                return HttpResponse::InternalServerError()
-                    .body("Item not found!");
-            }
+    let owner = match cart_owner(&req, &cookie, &conn) {
+        Some(o) => o,
+        None => {
+            info!("Item not present.");
+            return HttpResponse::InternalServerError().body("Item not found!");
         }
-    } else {
-        error!("Unauthorized add to cart action!");
-        return HttpResponse::Unauthorized()
-            .body("Need to be logged in to add to cart!");
+    };
+    let old_quantity = current_quantity(&conn, &owner, item_details)
+        .expect("Couldn't connect to DB");
+    if old_quantity == 0 {
+        info!("Item not present.");
+        return HttpResponse::InternalServerError().body("Item not found!");
+    }
+    match set_quantity(&conn, &owner, item_details, old_quantity - 1) {
+        Ok(_) => HttpResponse::Ok().body("Updated quantity successfully!"),
+        Err(e) => e.into_response(),
     }
 }
 
 #[derive(Serialize)]
-struct UserCartItem {
+pub struct UserCartItem {
     product_item: Product,
     quantity: i32,
+    unit_price: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct CartView {
+    items: Vec<UserCartItem>,
+    total: f32,
+}
+
+pub fn load_cart(
+    conn: &MysqlConnection,
+    owner: &CartOwner,
+) -> QueryResult<CartView> {
+    let items = cart_lines(conn, owner)?
+        .into_iter()
+        .map(|line| {
+            let p = prod::product
+                .filter(prod::id.eq(line.product_id))
+                .limit(1)
+                .first::<Product>(conn)?;
+            Ok(UserCartItem {
+                product_item: p,
+                quantity: line.quantity,
+                unit_price: line.unit_price,
+            })
+        })
+        .collect::<QueryResult<Vec<_>>>()?;
+    let total = items
+        .iter()
+        .map(|i| i.product_item.price * i.quantity as f32)
+        .sum();
+    Ok(CartView { items, total })
+}
+
+fn empty_cart() -> CartView {
+    CartView {
+        items: vec![],
+        total: 0.,
+    }
 }
 
 pub async fn get_user_cart_items(
+    req: HttpRequest,
     cookie: Identity,
     pool: web::Data<TPool>,
 ) -> impl Responder {
     let conn = pool.get().unwrap();
-    if let Some(uname) = cookie.identity() {
-        let selected_user = customer
-            .filter(username.eq(&uname))
-            .limit(1)
-            .first::<Customer>(&conn)
-            .expect("Couldn't connect to DB");
-        let user_cart_items = cart_items
-            .filter(cart_id.eq(selected_user.id))
-            .load::<CartItem>(&conn)
-            .expect("Couldn't connect to DB");
-        let cart_products = user_cart_items
-            .into_iter()
-            .map(|item| {
-                let p = prod::product
-                    .filter(prod::id.eq(item.product_id))
-                    .limit(1)
-                    .first::<Product>(&conn)
-                    .expect("Couldn't connect to db");
-                UserCartItem {
-                    product_item: p,
-                    quantity: item.quantity.unwrap_or(1),
-                }
-            })
-            .collect::<Vec<_>>();
-        return HttpResponse::Ok().json(&cart_products);
-    } else {
-        return HttpResponse::Unauthorized()
-            .body("Need to be logged in to add to cart!");
-    }
+    let cart = match cart_owner(&req, &cookie, &conn) {
+        Some(owner) => {
+            load_cart(&conn, &owner).expect("Couldn't connect to DB")
+        }
+        None => empty_cart(),
+    };
+    HttpResponse::Ok().json(&cart.items)
 }
 
 pub async fn get_user_cart_total(
+    req: HttpRequest,
     cookie: Identity,
     pool: web::Data<TPool>,
 ) -> impl Responder {
     let conn = pool.get().unwrap();
-    if let Some(uname) = cookie.identity() {
-        let selected_user = customer
-            .filter(username.eq(&uname))
-            .limit(1)
-            .first::<Customer>(&conn)
-            .expect("Couldn't connect to DB");
-        let user_cart_items = cart_items
-            .filter(cart_id.eq(selected_user.id))
-            .load::<CartItem>(&conn)
-            .expect("Couldn't connect to DB");
-        let cart_total: f32 = user_cart_items
-            .into_iter()
-            .map(|item| {
-                let p = prod::product
-                    .filter(prod::id.eq(item.product_id))
-                    .limit(1)
-                    .first::<Product>(&conn)
-                    .expect("Couldn't connect to db");
-                return p.price * item.quantity.unwrap_or(1) as f32;
-            })
-            .sum();
-        return HttpResponse::Ok().json(&cart_total);
-    } else {
-        return HttpResponse::Unauthorized()
-            .body("Need to be logged in to add to cart!");
+    let cart = match cart_owner(&req, &cookie, &conn) {
+        Some(owner) => {
+            load_cart(&conn, &owner).expect("Couldn't connect to DB")
+        }
+        None => empty_cart(),
+    };
+    HttpResponse::Ok().json(cart.total)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CartLine {
+    product_id: i32,
+    quantity: i32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CartProduct {
+    product_id: i32,
+}
+
+/// Applies `change` to the cart of the request and responds with the
+/// updated cart. Anonymous visitors get a new guest cart.
+fn update_cart<F>(
+    req: &HttpRequest,
+    cookie: &Identity,
+    pool: &TPool,
+    change: F,
+) -> HttpResponse
+where
+    F: FnOnce(&MysqlConnection, &CartOwner) -> Result<(), CartError>,
+{
+    let conn = pool.get().unwrap();
+    let (owner, new_guest) = cart_owner_or_guest(req, cookie, &conn);
+    let changed = conn.transaction::<_, CartError, _>(|| change(&conn, &owner));
+    let resp = match changed.and_then(|_| Ok(load_cart(&conn, &owner)?)) {
+        Ok(cart) => HttpResponse::Ok().json(&cart),
+        Err(e) => return e.into_response(),
+    };
+    with_cookie(resp, new_guest)
+}
+
+pub async fn set_cart_quantity(
+    req: HttpRequest,
+    cookie: Identity,
+    line: web::Json<CartLine>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    info!("Set cart quantity: {:?}", line);
+    update_cart(&req, &cookie, &pool, |conn, owner| {
+        set_quantity(conn, owner, line.product_id, line.quantity)
+    })
+}
+
+pub async fn add_cart_quantity(
+    req: HttpRequest,
+    cookie: Identity,
+    line: web::Json<CartLine>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    info!("Add cart quantity: {:?}", line);
+    update_cart(&req, &cookie, &pool, |conn, owner| {
+        if line.quantity < 1 {
+            return Err(CartError::InvalidQuantity(String::from(
+                "Quantity must be at least 1",
+            )));
+        }
+        let old_quantity = current_quantity(conn, owner, line.product_id)?;
+        set_quantity(conn, owner, line.product_id, old_quantity + line.quantity)
+    })
+}
+
+pub async fn remove_cart_line(
+    req: HttpRequest,
+    cookie: Identity,
+    line: web::Json<CartProduct>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    info!("Remove cart line: {:?}", line);
+    update_cart(&req, &cookie, &pool, |conn, owner| {
+        set_quantity(conn, owner, line.product_id, 0)
+    })
+}
+
+pub async fn clear_cart(
+    req: HttpRequest,
+    cookie: Identity,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    info!("Clear cart for {:?}", cookie.identity());
+    update_cart(&req, &cookie, &pool, |conn, owner| {
+        clear(conn, owner)?;
+        Ok(())
+    })
+}
+
+/// Compares every cart line with the current price, stock and catalog
+/// status of its product.
+pub fn validate_cart(
+    conn: &MysqlConnection,
+    owner: &CartOwner,
+) -> QueryResult<Vec<CartIssue>> {
+    let mut issues = vec![];
+    for line in cart_lines(conn, owner)? {
+        let p = prod::product.find(line.product_id).first::<Product>(conn)?;
+        if p.archived {
+            issues.push(CartIssue::Archived {
+                product_id: p.id,
+                name: p.name,
+            });
+            continue;
+        }
+        if let Some(s) = p.stock {
+            if line.quantity > s {
+                issues.push(CartIssue::OutOfStock {
+                    product_id: p.id,
+                    name: p.name.clone(),
+                    requested: line.quantity,
+                    available: s.max(0),
+                });
+            }
+        }
+        match line.unit_price {
+            Some(old_price) if (old_price - p.price).abs() > f32::EPSILON => {
+                issues.push(CartIssue::PriceChanged {
+                    product_id: p.id,
+                    name: p.name,
+                    old_price,
+                    new_price: p.price,
+                })
+            }
+            _ => (),
+        }
     }
+    Ok(issues)
+}
+
+/// Accepts the current state of the catalog for every cart line: archived
+/// products are removed, quantities are reduced to what can be ordered and
+/// price snapshots are moved to the current prices.
+fn acknowledge(
+    conn: &MysqlConnection,
+    owner: &CartOwner,
+) -> Result<(), CartError> {
+    for line in cart_lines(conn, owner)? {
+        let p = prod::product.find(line.product_id).first::<Product>(conn)?;
+        if p.archived {
+            set_quantity(conn, owner, p.id, 0)?;
+            continue;
+        }
+        let cap = quantity_cap(&p);
+        if line.quantity > cap {
+            set_quantity(conn, owner, p.id, cap)?;
+        }
+        match owner {
+            CartOwner::Customer(cid) => diesel::update(
+                cart_items
+                    .filter(cart_id.eq(cid))
+                    .filter(product_id.eq(p.id)),
+            )
+            .set(unit_price.eq(p.price))
+            .execute(conn)?,
+            CartOwner::Guest(gid) => diesel::update(
+                guest::guest_cart_items
+                    .filter(guest::guest_id.eq(gid))
+                    .filter(guest::product_id.eq(p.id)),
+            )
+            .set(guest::unit_price.eq(p.price))
+            .execute(conn)?,
+        };
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct CartValidation {
+    issues: Vec<CartIssue>,
+    total: f32,
+}
+
+pub async fn validate_user_cart(
+    req: HttpRequest,
+    cookie: Identity,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let validation = match cart_owner(&req, &cookie, &conn) {
+        Some(owner) => CartValidation {
+            issues: validate_cart(&conn, &owner)
+                .expect("Couldn't connect to DB"),
+            total: load_cart(&conn, &owner)
+                .expect("Couldn't connect to DB")
+                .total,
+        },
+        None => CartValidation {
+            issues: vec![],
+            total: 0.,
+        },
+    };
+    HttpResponse::Ok().json(&validation)
+}
+
+pub async fn acknowledge_cart(
+    req: HttpRequest,
+    cookie: Identity,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    info!("Acknowledge cart changes for {:?}", cookie.identity());
+    update_cart(&req, &cookie, &pool, acknowledge)
 }
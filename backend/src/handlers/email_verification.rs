@@ -0,0 +1,155 @@
+use crate::mailer::{self, MailError};
+use crate::models::{AddNotification, Customer};
+use crate::notifications::store_url;
+use crate::schema::customer::dsl::*;
+use crate::tokens::{self, EMAIL_VERIFICATION};
+use crate::TPool;
+
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse, Responder};
+use diesel::prelude::*;
+use log::{error, info};
+use serde::Deserialize;
+
+fn verify_token_minutes() -> i64 {
+    std::env::var("FURBY_VERIFY_TOKEN_MINUTES")
+        .ok()
+        .and_then(|m| m.parse().ok())
+        .unwrap_or(48 * 60)
+}
+
+fn resend_interval() -> usize {
+    std::env::var("FURBY_VERIFY_RESEND_SECONDS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(300)
+}
+
+/// With `FURBY_REQUIRE_VERIFIED_EMAIL=true` customers have to verify their
+/// email address before they can check out.
+fn verified_checkout() -> bool {
+    std::env::var("FURBY_REQUIRE_VERIFIED_EMAIL").as_deref() == Ok("true")
+}
+
+fn verification_body(member: &Customer, token: &str) -> String {
+    format!(
+        "Hi {},\n\n\
+         Please confirm that this is your email address:\n\n\
+         {}/verify_email?token={}\n\n\
+         The link works for {} hours.\n",
+        member.username,
+        store_url(),
+        token,
+        verify_token_minutes() / 60
+    )
+}
+
+/// Mails a fresh verification link to `member`, the links sent before
+/// stop working.
+pub fn send_verification(
+    conn: &MysqlConnection,
+    member: &Customer,
+) -> Result<(), MailError> {
+    conn.transaction(|| {
+        tokens::revoke(conn, member.id, EMAIL_VERIFICATION)?;
+        let token = tokens::issue(
+            conn,
+            member.id,
+            EMAIL_VERIFICATION,
+            verify_token_minutes(),
+        )?;
+        mailer::from_env(conn).send(AddNotification {
+            customer_id: Some(member.id),
+            kind: EMAIL_VERIFICATION.to_string(),
+            recipient: member.email_id.clone(),
+            subject: String::from("Confirm your email address"),
+            body: verification_body(member, &token),
+        })
+    })
+}
+
+/// The response to send when `member` may not check out yet.
+pub fn checkout_allowed(member: &Customer) -> Result<(), HttpResponse> {
+    if member.email_verified || !verified_checkout() {
+        Ok(())
+    } else {
+        Err(HttpResponse::Forbidden()
+            .body("Please verify your email address before checking out"))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmail {
+    token: String,
+}
+
+pub async fn verify_email(
+    details: web::Json<VerifyEmail>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let verified = conn.transaction::<_, diesel::result::Error, _>(|| {
+        let t = tokens::redeem(&conn, &details.token, EMAIL_VERIFICATION)?;
+        if let Some(t) = &t {
+            diesel::update(customer.find(t.customer_id))
+                .set(email_verified.eq(true))
+                .execute(&conn)?;
+        }
+        Ok(t.map(|t| t.customer_id))
+    });
+    match verified.expect("Couldn't connect to DB") {
+        Some(cid) => {
+            info!("Verified email of customer {}", cid);
+            HttpResponse::Ok().body("Email address verified")
+        }
+        None => HttpResponse::BadRequest()
+            .body("Verification link is invalid or has expired"),
+    }
+}
+
+/// Mails the logged in customer a new verification link, at most once
+/// every `FURBY_VERIFY_RESEND_SECONDS`.
+pub async fn resend_verification(
+    cookie: Identity,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    if let Some(uname) = cookie.identity() {
+        let selected_user = customer
+            .filter(username.eq(&uname))
+            .limit(1)
+            .first::<Customer>(&conn)
+            .expect("Couldn't connect to DB");
+        if selected_user.email_verified {
+            return HttpResponse::BadRequest()
+                .body("Email address is already verified");
+        }
+        let redis_client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let mut redis_conn = redis_client.get_connection().unwrap();
+        let allowed = redis::cmd("SET")
+            .arg(format!("verify_resend:{}", selected_user.id))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(resend_interval())
+            .query::<Option<String>>(&mut redis_conn)
+            .unwrap()
+            .is_some();
+        if !allowed {
+            return HttpResponse::TooManyRequests().body(
+                "A verification mail was sent recently, try again later",
+            );
+        }
+        match send_verification(&conn, &selected_user) {
+            Ok(()) => HttpResponse::Ok().body("Verification mail sent"),
+            Err(e) => {
+                error!("Unable to send verification to {}: {}", uname, e);
+                HttpResponse::InternalServerError()
+                    .body("Unable to send verification mail")
+            }
+        }
+    } else {
+        HttpResponse::Unauthorized()
+            .body("Need to be logged in to verify your email!")
+    }
+}
@@ -1,6 +1,16 @@
+pub mod account;
+pub mod api_tokens;
 pub mod cart_items;
+pub mod email_verification;
+pub mod moderation;
+pub mod password_reset;
+pub mod payment;
 pub mod product;
 pub mod rating;
+pub mod returns;
+pub mod review_photos;
 pub mod smoke;
 pub mod transaction;
+pub mod two_factor;
 pub mod users;
+pub mod wishlist;
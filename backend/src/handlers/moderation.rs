@@ -0,0 +1,314 @@
+use crate::handlers::rating::invalidate_summary;
+use crate::handlers::review_photos::{delete_photos, remove_photo_files};
+use crate::handlers::users::staff_member;
+use crate::models::{AddRatingReport, Customer, Rating, RatingReport};
+use crate::schema::customer::dsl as cust;
+use crate::schema::product::dsl as prod;
+use crate::schema::rating::dsl::*;
+use crate::schema::rating_report::dsl as rr;
+use crate::TPool;
+
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::naive::NaiveDateTime;
+use diesel::dsl::now;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DBError};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+pub const PENDING: &str = "pending";
+pub const APPROVED: &str = "approved";
+pub const REJECTED: &str = "rejected";
+
+/// Shown for the product or author of reviews that lack them.
+const UNKNOWN: &str = "unknown";
+
+const REPORT_REASONS: &[&str] =
+    &["spam", "offensive", "off_topic", "fake", "other"];
+
+/// Lowercase words from the comma separated `FURBY_REVIEW_WORD_FILTER`.
+fn word_filter() -> Vec<String> {
+    std::env::var("FURBY_REVIEW_WORD_FILTER")
+        .unwrap_or_default()
+        .split(',')
+        .map(|w| w.trim().to_lowercase())
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+/// With `FURBY_REVIEW_MODERATION=flagged` only reviews caught by the word
+/// filter wait for a moderator, otherwise every review does.
+fn moderate_all() -> bool {
+    std::env::var("FURBY_REVIEW_MODERATION").map_or(true, |m| m != "flagged")
+}
+
+fn report_threshold() -> i64 {
+    std::env::var("FURBY_REPORT_THRESHOLD")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(3)
+}
+
+/// Filtered words that appear as whole words in `text`, ignoring case.
+pub fn flagged_words(text: &str) -> Vec<String> {
+    let filter = word_filter();
+    let mut found = text
+        .split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .filter(|w| filter.contains(w))
+        .collect::<Vec<_>>();
+    found.sort();
+    found.dedup();
+    found
+}
+
+/// Status and flag reason a new or edited review starts out with. A review
+/// that had been `previous`ly rejected always goes back to a moderator, so
+/// rejections can't be undone by resubmitting.
+pub fn screen(
+    comment: Option<&str>,
+    previous: Option<&str>,
+) -> (String, Option<String>) {
+    let found = comment.map(flagged_words).unwrap_or_default();
+    if !found.is_empty() {
+        info!("Review flagged by word filter: {:?}", found);
+        (
+            PENDING.to_string(),
+            Some(format!("word filter: {}", found.join(", "))),
+        )
+    } else if previous == Some(REJECTED) {
+        (
+            PENDING.to_string(),
+            Some(String::from("changed after being rejected")),
+        )
+    } else if moderate_all() {
+        (PENDING.to_string(), None)
+    } else {
+        (APPROVED.to_string(), None)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReportRating {
+    rating_id: i32,
+    reason: String,
+    note: Option<String>,
+}
+
+/// Reports a review. Once `FURBY_REPORT_THRESHOLD` customers have reported
+/// an approved review it is taken down until a moderator looks at it.
+pub async fn report_rating(
+    cookie: Identity,
+    report: web::Json<ReportRating>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    info!("Report rating hit: {:?}", report);
+    let conn = pool.get().unwrap();
+    let uname = match cookie.identity() {
+        Some(u) => u,
+        None => {
+            return HttpResponse::Unauthorized()
+                .body("Need to be logged in to report reviews!")
+        }
+    };
+    let report = report.into_inner();
+    if !REPORT_REASONS.contains(&report.reason.as_str()) {
+        return HttpResponse::BadRequest().body(format!(
+            "Reason must be one of: {}",
+            REPORT_REASONS.join(", ")
+        ));
+    }
+    let selected_user = cust::customer
+        .filter(cust::username.eq(&uname))
+        .limit(1)
+        .first::<Customer>(&conn)
+        .expect("Couldn't connect to DB");
+    let reported = match rating
+        .find(report.rating_id)
+        .first::<Rating>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB")
+    {
+        Some(r) => r,
+        None => return HttpResponse::NotFound().body("Review not found"),
+    };
+    if reported.customer_id == Some(selected_user.id) {
+        return HttpResponse::BadRequest()
+            .body("You cannot report your own review");
+    }
+    let inserted = diesel::insert_into(rr::rating_report)
+        .values(AddRatingReport {
+            rating_id: reported.id,
+            customer_id: selected_user.id,
+            reason: report.reason,
+            note: report.note,
+        })
+        .execute(&conn);
+    match inserted {
+        Ok(_) => (),
+        Err(DBError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            return HttpResponse::Conflict()
+                .body("You have already reported this review")
+        }
+        Err(e) => panic!("Couldn't connect to DB: {}", e),
+    }
+    let open_reports = rr::rating_report
+        .filter(rr::rating_id.eq(reported.id))
+        .filter(rr::resolved.eq(false))
+        .count()
+        .get_result::<i64>(&conn)
+        .expect("Couldn't connect to DB");
+    if reported.status == APPROVED && open_reports >= report_threshold() {
+        info!(
+            "Review {} hidden after {} reports",
+            reported.id, open_reports
+        );
+        diesel::update(rating.find(reported.id))
+            .set((
+                status.eq(PENDING),
+                flag_reason
+                    .eq(format!("reported by {} customers", open_reports)),
+            ))
+            .execute(&conn)
+            .expect("Couldn't connect to DB");
+        if let Some(pid) = reported.product_id {
+            invalidate_summary(pid);
+        }
+    }
+    HttpResponse::Ok().body("Thanks, a moderator will look at this review")
+}
+
+#[derive(Serialize)]
+pub struct QueueEntry {
+    #[serde(flatten)]
+    review: Rating,
+    product_name: String,
+    customer_name: String,
+    reports: Vec<RatingReport>,
+}
+
+/// Pending reviews and reviews with open reports, flagged ones first and
+/// otherwise oldest first.
+pub async fn moderation_queue(
+    cookie: Identity,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    if let Err(resp) = staff_member(&cookie, &conn) {
+        return resp;
+    }
+    let reported = rr::rating_report
+        .filter(rr::resolved.eq(false))
+        .select(rr::rating_id)
+        .load::<i32>(&conn)
+        .expect("Couldn't connect to DB");
+    let mut reviews = rating
+        .filter(status.eq(PENDING).or(id.eq_any(&reported)))
+        .order(id.asc())
+        .load::<Rating>(&conn)
+        .expect("Couldn't connect to DB");
+    reviews.sort_by_key(|r| r.flag_reason.is_none());
+    let queue = reviews
+        .into_iter()
+        .map(|r| {
+            // old reviews may lack either, they still need moderating
+            let product_name = match r.product_id {
+                Some(pid) => prod::product
+                    .find(pid)
+                    .select(prod::name)
+                    .first::<String>(&conn)
+                    .optional()?,
+                None => None,
+            };
+            let customer_name = match r.customer_id {
+                Some(cid) => cust::customer
+                    .find(cid)
+                    .select(cust::username)
+                    .first::<String>(&conn)
+                    .optional()?,
+                None => None,
+            };
+            let reports = rr::rating_report
+                .filter(rr::rating_id.eq(r.id))
+                .filter(rr::resolved.eq(false))
+                .order(rr::created_at.asc())
+                .load::<RatingReport>(&conn)?;
+            Ok(QueueEntry {
+                review: r,
+                product_name: product_name.unwrap_or_else(|| UNKNOWN.into()),
+                customer_name: customer_name.unwrap_or_else(|| UNKNOWN.into()),
+                reports,
+            })
+        })
+        .collect::<QueryResult<Vec<_>>>()
+        .expect("Couldn't connect to DB");
+    HttpResponse::Ok().json(&queue)
+}
+
+/// Records a moderator's decision on a review and closes its reports.
+/// Photos of rejected reviews are deleted.
+fn decide(
+    cookie: &Identity,
+    pool: &TPool,
+    rating_id: i32,
+    decision: &str,
+) -> HttpResponse {
+    let conn = pool.get().unwrap();
+    let staff = match staff_member(cookie, &conn) {
+        Ok(s) => s,
+        Err(resp) => return resp,
+    };
+    info!(
+        "{} marking review {} {}",
+        staff.username, rating_id, decision
+    );
+    let decided = conn.transaction::<_, DBError, _>(|| {
+        let decided_at = diesel::select(now).first::<NaiveDateTime>(&conn)?;
+        let changed = diesel::update(rating.find(rating_id))
+            .set((
+                status.eq(decision),
+                moderated_by.eq(staff.id),
+                moderated_at.eq(decided_at),
+            ))
+            .execute(&conn)?;
+        diesel::update(rr::rating_report.filter(rr::rating_id.eq(rating_id)))
+            .set(rr::resolved.eq(true))
+            .execute(&conn)?;
+        let mut photo_files = vec![];
+        if changed > 0 && decision == REJECTED {
+            photo_files = delete_photos(&conn, rating_id)?;
+        }
+        Ok((changed, photo_files))
+    });
+    if let Ok(Some(Some(pid))) = rating
+        .find(rating_id)
+        .select(product_id)
+        .first::<Option<i32>>(&conn)
+        .optional()
+    {
+        invalidate_summary(pid);
+    }
+    let (changed, photo_files) = decided.expect("Couldn't connect to DB");
+    remove_photo_files(&photo_files);
+    match changed {
+        0 => HttpResponse::NotFound().body("Review not found"),
+        _ => HttpResponse::Ok().body(format!("Review {}", decision)),
+    }
+}
+
+pub async fn approve_rating(
+    cookie: Identity,
+    rating_id: web::Path<i32>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    decide(&cookie, &pool, rating_id.into_inner(), APPROVED)
+}
+
+pub async fn reject_rating(
+    cookie: Identity,
+    rating_id: web::Path<i32>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    decide(&cookie, &pool, rating_id.into_inner(), REJECTED)
+}
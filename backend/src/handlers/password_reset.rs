@@ -0,0 +1,163 @@
+use crate::audit::client_ip;
+use crate::login_throttle::{self, Subject, RESET_MAIL};
+use crate::mailer;
+use crate::models::{AddNotification, Customer};
+use crate::notifications::store_url;
+use crate::schema::customer::dsl::*;
+use crate::session::end_all_sessions;
+use crate::tokens::{self, PASSWORD_RESET};
+use crate::validation::{self, FieldErrors};
+use crate::TPool;
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use bcrypt::{hash, DEFAULT_COST};
+use diesel::prelude::*;
+use log::{error, info};
+use serde::Deserialize;
+
+fn reset_token_minutes() -> i64 {
+    std::env::var("FURBY_RESET_TOKEN_MINUTES")
+        .ok()
+        .and_then(|m| m.parse().ok())
+        .unwrap_or(60)
+}
+
+fn reset_body(member: &Customer, token: &str) -> String {
+    format!(
+        "Hi {},\n\n\
+         Someone asked to reset the password of your account. If it was \
+         you, choose a new password here within {} minutes:\n\n\
+         {}/reset_password?token={}\n\n\
+         Otherwise you can ignore this mail, your password stays as it is.\n",
+        member.username,
+        reset_token_minutes(),
+        store_url(),
+        token
+    )
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPassword {
+    login: String,
+}
+
+/// Mails a password reset link to the account with the given username or
+/// email address. Answers the same whether or not the account exists, so
+/// it can't be used to find out who has one. Requests are throttled per
+/// login and per address like failed logins, so nobody's inbox can be
+/// flooded with reset mails.
+pub async fn forgot_password(
+    req: HttpRequest,
+    details: web::Json<ForgotPassword>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let login = details.login.trim();
+    info!("Password reset requested for {}", login);
+    let ip = client_ip(&req);
+    let lowered = login.to_lowercase();
+    let mut subjects = vec![Subject::User(&lowered)];
+    if let Some(ip) = &ip {
+        subjects.push(Subject::Ip(ip));
+    }
+    let throttled = login_throttle::retry_after_for(RESET_MAIL, &subjects)
+        .and_then(|wait| {
+            login_throttle::record_for(RESET_MAIL, &subjects)?;
+            Ok(wait)
+        });
+    match throttled {
+        Ok(None) => (),
+        Ok(Some(wait)) => {
+            info!("Password reset for {} throttled for {}s", login, wait);
+            return HttpResponse::TooManyRequests()
+                .header("Retry-After", wait.to_string())
+                .body(format!(
+                    "Too many password reset requests, try again in {}s",
+                    wait
+                ));
+        }
+        Err(e) => {
+            error!("Unable to throttle password resets: {}", e);
+            return HttpResponse::ServiceUnavailable()
+                .body("Unable to send password reset mail, try again later");
+        }
+    }
+    let selected_user = customer
+        .filter(username.eq(login).or(email_id.eq(login)))
+        .first::<Customer>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB");
+    if let Some(member) = selected_user {
+        let sent = conn.transaction::<_, mailer::MailError, _>(|| {
+            let token = tokens::issue(
+                &conn,
+                member.id,
+                PASSWORD_RESET,
+                reset_token_minutes(),
+            )?;
+            mailer::from_env(&conn).send(AddNotification {
+                customer_id: Some(member.id),
+                kind: PASSWORD_RESET.to_string(),
+                recipient: member.email_id.clone(),
+                subject: String::from("Reset your password"),
+                body: reset_body(&member, &token),
+            })
+        });
+        // answered like any other request, failing here would tell that
+        // the account exists
+        if let Err(e) = sent {
+            error!("Password reset for {} failed: {}", member.username, e);
+        }
+    }
+    HttpResponse::Ok()
+        .body("If the account exists, a password reset link is on its way")
+}
+
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    token: String,
+    new_password: String,
+}
+
+/// Sets a new password with a token from `forgot_password`. Each token
+/// works once, and a successful reset logs the account out everywhere.
+pub async fn reset_password(
+    details: web::Json<ResetPassword>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let details = details.into_inner();
+    let mut errors = FieldErrors::default();
+    errors.check("new_password", validation::password(&details.new_password));
+    if let Err(resp) = errors.into_result() {
+        return resp;
+    }
+    let hashed_new_password =
+        hash(&details.new_password, DEFAULT_COST).unwrap();
+    let reset = conn.transaction::<_, diesel::result::Error, _>(|| {
+        let reset_token =
+            match tokens::redeem(&conn, &details.token, PASSWORD_RESET)? {
+                Some(t) => t,
+                None => return Ok(None),
+            };
+        diesel::update(customer.find(reset_token.customer_id))
+            .set(password.eq(&hashed_new_password))
+            .execute(&conn)?;
+        customer
+            .find(reset_token.customer_id)
+            .select(username)
+            .first::<String>(&conn)
+            .map(Some)
+    });
+    match reset.expect("Couldn't connect to DB") {
+        Some(uname) => {
+            info!("Password reset for {}", uname);
+            if let Err(e) = end_all_sessions(&uname) {
+                error!("Unable to end sessions of {}: {}", uname, e);
+            }
+            HttpResponse::Ok().body("Password reset, please log in again")
+        }
+        None => HttpResponse::BadRequest()
+            .body("Reset link is invalid or has expired"),
+    }
+}
@@ -0,0 +1,277 @@
+use crate::models::{AddPaymentEvent, AddRefund, PaymentEvent};
+use crate::schema::payment_event::dsl as pe;
+use crate::schema::refund::dsl as rf;
+use crate::schema::transaction::dsl::*;
+use crate::{last_insert_id, TPool};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use hmac::{Hmac, Mac, NewMac};
+use log::{error, info};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use std::fmt;
+
+pub const SIGNATURE_HEADER: &str = "X-Furby-Signature";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// `PAYMENT_WEBHOOK_SECRET`. Read once at startup, so a missing secret stops
+/// the server from starting rather than failing each webhook.
+pub fn webhook_secret() -> String {
+    std::env::var("PAYMENT_WEBHOOK_SECRET")
+        .expect("PAYMENT_WEBHOOK_SECRET must be set")
+}
+
+/// The webhook secret, as handed to the app at startup.
+pub struct WebhookSecret(pub String);
+
+/// Hex encoded HMAC-SHA256 of `payload`, as sent in `SIGNATURE_HEADER`.
+/// Also used to sign guest cart cookies.
+pub fn sign_payload(secret: &str, payload: &[u8]) -> String {
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub fn verify_signature(secret: &str, payload: &[u8], signature: &str) -> bool {
+    let signature = match hex::decode(signature.trim()) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac.verify(&signature).is_ok()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WebhookEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub transaction_id: i32,
+    pub refund_id: Option<i32>,
+}
+
+/// Maps a provider event type onto the `payment_status` of a transaction.
+fn status_for_event(event_type: &str) -> Option<&'static str> {
+    match event_type {
+        "payment.succeeded" => Some("paid"),
+        "payment.failed" => Some("failed"),
+        "payment.refunded" => Some("refunded"),
+        _ => None,
+    }
+}
+
+/// Payment statuses a transaction may move to `status` from. Payments only
+/// move forward, so late or replayed events can't undo a failure or a
+/// refund.
+fn advances_from(status: &str) -> &'static [&'static str] {
+    match status {
+        "paid" | "failed" => &["pending"],
+        "refunded" => &["paid", "partially_refunded"],
+        _ => &[],
+    }
+}
+
+fn refund_status_for_event(event_type: &str) -> Option<&'static str> {
+    match event_type {
+        "refund.succeeded" => Some("succeeded"),
+        "refund.failed" => Some("failed"),
+        _ => None,
+    }
+}
+
+/// Refunds may be off by this much from the paid amount due to rounding.
+const REFUND_TOLERANCE: f32 = 0.005;
+
+#[derive(Debug)]
+pub enum RefundError {
+    Db(diesel::result::Error),
+    /// The refund would take the total refunded above what was paid, only
+    /// `refundable` is left.
+    ExceedsPayment {
+        refundable: f32,
+    },
+}
+
+impl fmt::Display for RefundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefundError::Db(e) => write!(f, "{}", e),
+            RefundError::ExceedsPayment { refundable } => {
+                write!(f, "only {} is left to refund", refundable)
+            }
+        }
+    }
+}
+
+impl From<diesel::result::Error> for RefundError {
+    fn from(e: diesel::result::Error) -> Self {
+        RefundError::Db(e)
+    }
+}
+
+fn refunded_so_far(conn: &MysqlConnection, tid: i32) -> QueryResult<f32> {
+    let refunded: Vec<f32> = rf::refund
+        .filter(rf::transaction_id.eq(tid))
+        .filter(rf::status.ne("failed"))
+        .select(rf::amount)
+        .load(conn)?;
+    Ok(refunded.into_iter().sum())
+}
+
+/// Records a refund of `refund_amount` against transaction `tid` and marks
+/// the transaction as refunded or partially refunded. The refund stays
+/// pending until the provider confirms it through the webhook. Refunds
+/// never add up to more than was paid.
+pub fn issue_refund(
+    conn: &MysqlConnection,
+    tid: i32,
+    refund_amount: f32,
+) -> Result<i32, RefundError> {
+    let paid = transaction
+        .filter(id.eq(tid))
+        .select(amount)
+        .for_update()
+        .first::<f32>(conn)?;
+    let refundable = paid - refunded_so_far(conn, tid)?;
+    if refund_amount > refundable + REFUND_TOLERANCE {
+        error!(
+            "Refusing refund of {} for transaction {}, {} left",
+            refund_amount, tid, refundable
+        );
+        return Err(RefundError::ExceedsPayment {
+            refundable: refundable.max(0.),
+        });
+    }
+    diesel::insert_into(rf::refund)
+        .values(AddRefund {
+            transaction_id: tid,
+            amount: refund_amount,
+        })
+        .execute(conn)?;
+    let refund_id = diesel::select(last_insert_id).first::<u64>(conn)? as i32;
+    let refunded = refunded_so_far(conn, tid)?;
+    let status = if refunded + REFUND_TOLERANCE >= paid {
+        "refunded"
+    } else {
+        "partially_refunded"
+    };
+    diesel::update(transaction.filter(id.eq(tid)))
+        .set(payment_status.eq(status))
+        .execute(conn)?;
+    info!(
+        "Issued refund {} of {} for transaction {}",
+        refund_id, refund_amount, tid
+    );
+    Ok(refund_id)
+}
+
+pub async fn webhook(
+    req: HttpRequest,
+    body: web::Bytes,
+    secret: web::Data<WebhookSecret>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let signature = req
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|h| h.to_str().ok());
+    let signature = match signature {
+        Some(s) => s,
+        None => {
+            error!("Payment webhook without signature");
+            return HttpResponse::BadRequest().body("Missing signature");
+        }
+    };
+    if !verify_signature(&secret.0, &body, signature) {
+        error!("Payment webhook with invalid signature");
+        return HttpResponse::Unauthorized().body("Invalid signature");
+    }
+    let event = match serde_json::from_slice::<WebhookEvent>(&body) {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Malformed payment event: {}", e);
+            return HttpResponse::BadRequest().body("Malformed event");
+        }
+    };
+    info!("Payment event {} ({})", event.id, event.event_type);
+
+    let conn = pool.get().unwrap();
+    let seen = pe::payment_event
+        .filter(pe::id.eq(&event.id))
+        .first::<PaymentEvent>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB");
+    if seen.is_some() {
+        info!("Duplicate payment event: {}", event.id);
+        return HttpResponse::Ok().body("Duplicate event");
+    }
+
+    let known_transaction = transaction
+        .filter(id.eq(event.transaction_id))
+        .select(id)
+        .first::<i32>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB");
+    if known_transaction.is_none() {
+        error!("Payment event for unknown transaction: {:?}", event);
+    }
+
+    let payload = String::from_utf8_lossy(&body).into_owned();
+    let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::insert_into(pe::payment_event)
+            .values(AddPaymentEvent {
+                id: event.id.clone(),
+                event_type: event.event_type.clone(),
+                transaction_id: known_transaction,
+                payload,
+            })
+            .execute(&conn)?;
+        if let (Some(tid), Some(status)) =
+            (known_transaction, status_for_event(&event.event_type))
+        {
+            let updated = diesel::update(
+                transaction
+                    .filter(id.eq(tid))
+                    .filter(payment_status.eq_any(advances_from(status))),
+            )
+            .set(payment_status.eq(status))
+            .execute(&conn)?;
+            if updated == 0 {
+                info!(
+                    "Ignoring {} for transaction {}, it can't become {}",
+                    event.id, tid, status
+                );
+            }
+        }
+        if let (Some(rid), Some(status)) =
+            (event.refund_id, refund_status_for_event(&event.event_type))
+        {
+            diesel::update(rf::refund.filter(rf::id.eq(rid)))
+                .set(rf::status.eq(status))
+                .execute(&conn)?;
+        }
+        Ok(())
+    });
+    match result {
+        Ok(_) => HttpResponse::Ok().body("Event processed"),
+        // delivered twice at once, the other delivery recorded it
+        Err(diesel::result::Error::DatabaseError(
+            DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => {
+            info!("Duplicate payment event: {}", event.id);
+            HttpResponse::Ok().body("Duplicate event")
+        }
+        Err(e) => {
+            error!("Unable to record payment event {}: {}", event.id, e);
+            HttpResponse::InternalServerError().body("Unable to record event")
+        }
+    }
+}
@@ -1,21 +1,33 @@
-use crate::models::{Customer, NewProduct, Product, Rating, UpdateProduct};
+use crate::handlers::moderation::APPROVED;
+use crate::handlers::review_photos::{photos_of, ReviewPhoto};
+use crate::handlers::users::staff_member;
+use crate::models::{NewProduct, Product, Rating, UpdateProduct};
 use crate::schema::customer::dsl as cust;
 use crate::schema::product::dsl::*;
 use crate::schema::rating::dsl as rating;
+use crate::storage::LocalStorage;
 use crate::TPool;
 
+use actix_identity::Identity;
 use actix_web::{web, HttpResponse, Responder};
-use chrono::naive::NaiveDate;
+use chrono::naive::{NaiveDate, NaiveDateTime};
+use diesel::mysql::Mysql;
 use diesel::prelude::*;
 use log::{error, info};
 use serde::{Deserialize, Serialize};
 
+/// Adds a product to the catalog. Staff only, either logged in or through
+/// an API token with `products:write`.
 pub async fn new_product(
+    cookie: Identity,
     pool: web::Data<TPool>,
     item: web::Json<NewProduct>,
 ) -> impl Responder {
     info!("New product hit: {:?}", item.name);
     let conn = pool.get().unwrap();
+    if let Err(resp) = staff_member(&cookie, &conn) {
+        return resp;
+    }
     diesel::insert_into(product)
         .values(item.into_inner())
         .execute(&conn)
@@ -46,24 +58,45 @@ pub async fn product_details(
     }
 }
 
+/// Changes a product's details, price, stock and limits. Staff only, like
+/// `new_product`.
 pub async fn update_product(
+    cookie: Identity,
     pool: web::Data<TPool>,
     product_id: web::Path<i32>,
     product_details: web::Json<UpdateProduct>,
 ) -> impl Responder {
     let conn = pool.get().unwrap();
+    if let Err(resp) = staff_member(&cookie, &conn) {
+        return resp;
+    }
     let product_id = product_id.into_inner();
     let product_details = product_details.into_inner();
     info!("Updating product: {:?}", product_id);
-    match diesel::update(product.filter(id.eq(product_id)))
-        .set((
-            name.eq(product_details.name),
-            kind.eq(product_details.kind),
-            price.eq(product_details.price),
-            description.eq(product_details.description),
-        ))
-        .execute(&conn)
-    {
+    let target = product.filter(id.eq(product_id));
+    let updated = conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::update(target)
+            .set((
+                name.eq(product_details.name),
+                kind.eq(product_details.kind),
+                price.eq(product_details.price),
+                description.eq(product_details.description),
+            ))
+            .execute(&conn)?;
+        if let Some(s) = product_details.stock {
+            diesel::update(target).set(stock.eq(s)).execute(&conn)?;
+        }
+        if let Some(m) = product_details.max_order_quantity {
+            diesel::update(target)
+                .set(max_order_quantity.eq(m))
+                .execute(&conn)?;
+        }
+        if let Some(a) = product_details.archived {
+            diesel::update(target).set(archived.eq(a)).execute(&conn)?;
+        }
+        Ok(())
+    });
+    match updated {
         Ok(_) => {
             return HttpResponse::Ok().body("Changed product successfully")
         }
@@ -83,6 +116,8 @@ struct CatalogProduct {
     pub description: Option<String>,
     pub src: Option<String>,
     pub ios_src: Option<String>,
+    pub stock: Option<i32>,
+    pub max_order_quantity: Option<i32>,
     pub average_rating: Option<f64>,
 }
 
@@ -90,6 +125,7 @@ pub async fn get_all_products(pool: web::Data<TPool>) -> impl Responder {
     let conn = pool.get().unwrap();
     info!("Generating and returning catalog ...");
     let product_entries = product
+        .filter(archived.eq(false))
         .load::<Product>(&conn)
         .expect("Couldn't connect to DB");
     let with_rating_avg = product_entries
@@ -97,6 +133,7 @@ pub async fn get_all_products(pool: web::Data<TPool>) -> impl Responder {
         .map(move |p| {
             let rating_list = rating::rating
                 .filter(rating::product_id.eq(p.id))
+                .filter(rating::status.eq(APPROVED))
                 .load::<Rating>(&conn)
                 .expect("Coundn't connect to DB")
                 .into_iter()
@@ -120,6 +157,8 @@ pub async fn get_all_products(pool: web::Data<TPool>) -> impl Responder {
                 description: p.description,
                 src: p.src,
                 ios_src: p.ios_src,
+                stock: p.stock,
+                max_order_quantity: p.max_order_quantity,
                 id: p.id,
             }
         })
@@ -127,53 +166,163 @@ pub async fn get_all_products(pool: web::Data<TPool>) -> impl Responder {
     return HttpResponse::Ok().json(&with_rating_avg);
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Debug)]
 struct ProductRating {
+    pub id: i32,
     pub comment_text: Option<String>,
     pub comment_date: NaiveDate,
     pub product_name: String,
     pub customer_name: String,
     pub stars: Option<i32>,
+    pub verified_purchase: bool,
+    pub edited_at: Option<NaiveDateTime>,
+    pub helpful_votes: i32,
+    pub not_helpful_votes: i32,
+    pub photos: Vec<ReviewPhoto>,
 }
 
+#[derive(Serialize, Debug)]
+struct ReviewPage {
+    reviews: Vec<ProductRating>,
+    total: i64,
+    page: i64,
+    per_page: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReviewQuery {
+    sort: Option<String>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+    stars: Option<i32>,
+}
+
+const MAX_REVIEWS_PER_PAGE: i64 = 50;
+
+/// Approved reviews of a product, optionally only those with `stars`.
+fn approved_reviews(
+    pid: i32,
+    stars: Option<i32>,
+) -> crate::schema::rating::BoxedQuery<'static, Mysql> {
+    let mut query = rating::rating
+        .filter(rating::product_id.eq(pid))
+        .filter(rating::status.eq(APPROVED))
+        .into_boxed();
+    if let Some(s) = stars {
+        query = query.filter(rating::stars.eq(s));
+    }
+    query
+}
+
+/// Approved reviews of a product, a page at a time. `sort` is one of
+/// `newest` (the default), `highest`, `lowest` or `helpful`.
 pub async fn get_product_reviews(
     pool: web::Data<TPool>,
     product_id: web::Path<i32>,
+    params: web::Query<ReviewQuery>,
 ) -> impl Responder {
     let conn = pool.get().unwrap();
-    info!("Fetching product reviews for {}", product_id);
+    info!("Fetching product reviews for {}: {:?}", product_id, params);
     let pid = product_id.into_inner();
-    let rating_entries = rating::rating
-        .filter(rating::product_id.eq(pid))
+    let params = params.into_inner();
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params.per_page.unwrap_or(10);
+    if !(1..=MAX_REVIEWS_PER_PAGE).contains(&per_page) {
+        return HttpResponse::BadRequest().body(format!(
+            "per_page must be between 1 and {}",
+            MAX_REVIEWS_PER_PAGE
+        ));
+    }
+    let skipped = match (page - 1).checked_mul(per_page) {
+        Some(s) => s,
+        None => return HttpResponse::BadRequest().body("Page is out of range"),
+    };
+    if let Some(s) = params.stars {
+        if !(1..=5).contains(&s) {
+            return HttpResponse::BadRequest()
+                .body("Stars must be between 1 and 5");
+        }
+    }
+    let query = approved_reviews(pid, params.stars);
+    let query = match params.sort.as_deref().unwrap_or("newest") {
+        "newest" => {
+            query.order((rating::comment_date.desc(), rating::id.desc()))
+        }
+        "highest" => query.order((rating::stars.desc(), rating::id.desc())),
+        "lowest" => query.order((rating::stars.asc(), rating::id.desc())),
+        "helpful" => query.order((
+            (rating::helpful_votes - rating::not_helpful_votes).desc(),
+            rating::helpful_votes.desc(),
+            rating::id.desc(),
+        )),
+        _ => {
+            return HttpResponse::BadRequest()
+                .body("sort must be newest, highest, lowest or helpful")
+        }
+    };
+    let product_name = match product
+        .find(pid)
+        .select(name)
+        .first::<String>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB")
+    {
+        Some(n) => n,
+        None => return HttpResponse::NotFound().body("Product not found"),
+    };
+    let total = approved_reviews(pid, params.stars)
+        .count()
+        .get_result::<i64>(&conn)
+        .expect("Couldn't connect to DB");
+    let rating_entries = query
+        .limit(per_page)
+        .offset(skipped)
         .load::<Rating>(&conn)
         .expect("Couldn't connect to DB");
-    let json_ratings = rating_entries
+    let reviewer_ids = rating_entries
+        .iter()
+        .filter_map(|r| r.customer_id)
+        .collect::<Vec<_>>();
+    let reviewers = cust::customer
+        .filter(cust::id.eq_any(&reviewer_ids))
+        .select((cust::id, cust::username))
+        .load::<(i32, String)>(&conn)
+        .expect("Couldn't connect to DB");
+    let review_ids = rating_entries.iter().map(|r| r.id).collect::<Vec<_>>();
+    let photos = photos_of(&conn, &review_ids).expect("Couldn't connect to DB");
+    let storage = LocalStorage::from_env();
+    let reviews = rating_entries
         .into_iter()
-        .map(move |p| {
-            let selected_product = product
-                .filter(id.eq(&p.product_id.unwrap()))
-                .limit(1)
-                .first::<Product>(&conn)
-                .unwrap()
-                .name
-                .clone();
-
-            let selected_customer = cust::customer
-                .filter(cust::id.eq(&p.customer_id.unwrap()))
-                .limit(1)
-                .first::<Customer>(&conn)
-                .unwrap()
-                .username
-                .clone();
-
+        .map(|r| {
+            let rid = r.id;
+            let customer_name = reviewers
+                .iter()
+                .find(|(cid, _)| Some(*cid) == r.customer_id)
+                .map(|(_, n)| n.clone())
+                .unwrap_or_default();
             ProductRating {
-                comment_text: p.comment_text,
-                comment_date: p.comment_date.unwrap(),
-                product_name: selected_product,
-                customer_name: selected_customer,
-                stars: p.stars,
+                id: r.id,
+                comment_text: r.comment_text,
+                comment_date: r.comment_date.unwrap(),
+                product_name: product_name.clone(),
+                customer_name,
+                stars: r.stars,
+                verified_purchase: r.verified_purchase,
+                edited_at: r.edited_at,
+                helpful_votes: r.helpful_votes,
+                not_helpful_votes: r.not_helpful_votes,
+                photos: photos
+                    .iter()
+                    .filter(|p| p.rating_id == rid)
+                    .map(|p| ReviewPhoto::new(&storage, p))
+                    .collect(),
             }
         })
         .collect::<Vec<_>>();
-    return HttpResponse::Ok().json(&json_ratings);
+    HttpResponse::Ok().json(&ReviewPage {
+        reviews,
+        total,
+        page,
+        per_page,
+    })
 }
@@ -1,13 +1,27 @@
-use crate::models::{AddRating, Customer, Rating};
+use crate::handlers::moderation::{screen, APPROVED};
+use crate::handlers::review_photos::{delete_photos, remove_photo_files};
+use crate::models::{AddRating, AddRatingVote, Customer, Product, Rating};
 use crate::schema::rating::dsl as rating;
+use crate::schema::rating_vote::dsl as rv;
+use crate::schema::transaction::dsl as ts;
+use crate::schema::transaction_item::dsl as ti;
 use crate::schema::{customer::dsl::*, product::dsl::*};
 use crate::TPool;
 
 use actix_identity::Identity;
 use actix_web::{web, HttpResponse, Responder};
+use chrono::naive::NaiveDateTime;
+use diesel::dsl::now;
 use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DBError};
+use diesel::sql_types::{BigInt, Integer, Nullable};
 use log::{error, info};
-use serde::Deserialize;
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+const MAX_COMMENT_LENGTH: usize = 500;
 
 #[derive(Deserialize, Debug)]
 pub struct AddRatingJson {
@@ -16,6 +30,90 @@ pub struct AddRatingJson {
     pub product_id: i32,
 }
 
+impl AddRatingJson {
+    /// Checks the review before it reaches the database, returning the
+    /// comment with surrounding whitespace removed.
+    fn validate(&self) -> Result<Option<String>, HttpResponse> {
+        if let Some(s) = self.stars {
+            if !(1..=5).contains(&s) {
+                return Err(HttpResponse::BadRequest()
+                    .body("Stars must be between 1 and 5"));
+            }
+        }
+        let comment = self
+            .comment_text
+            .as_deref()
+            .map(str::trim)
+            .filter(|c| !c.is_empty());
+        if let Some(c) = comment {
+            if c.chars().count() > MAX_COMMENT_LENGTH {
+                return Err(HttpResponse::BadRequest().body(format!(
+                    "Comments can be at most {} characters",
+                    MAX_COMMENT_LENGTH
+                )));
+            }
+        }
+        if self.stars.is_none() && comment.is_none() {
+            return Err(HttpResponse::BadRequest()
+                .body("A review needs stars or a comment"));
+        }
+        Ok(comment.map(String::from))
+    }
+}
+
+/// Whether customer `cid` has an order line for product `pid` that was not
+/// cancelled entirely.
+fn purchased(conn: &MysqlConnection, cid: i32, pid: i32) -> QueryResult<bool> {
+    let lines = ti::transaction_item
+        .inner_join(ts::transaction)
+        .filter(ts::customer_id.eq(cid))
+        .filter(ti::product_id.eq(pid))
+        .filter(ti::quantity.gt(ti::cancelled_quantity))
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(lines > 0)
+}
+
+fn existing_review(
+    conn: &MysqlConnection,
+    cid: i32,
+    pid: i32,
+) -> QueryResult<Option<Rating>> {
+    rating::rating
+        .filter(rating::customer_id.eq(cid))
+        .filter(rating::product_id.eq(pid))
+        .first::<Rating>(conn)
+        .optional()
+}
+
+/// Validates a review and resolves the reviewing customer, or the response
+/// to send instead.
+fn prepare_review(
+    cookie: &Identity,
+    conn: &MysqlConnection,
+    rating_details: &AddRatingJson,
+) -> Result<(Customer, Option<String>), HttpResponse> {
+    let uname = cookie.identity().ok_or_else(|| {
+        error!("Unauthorized add rating action!");
+        HttpResponse::Unauthorized().body("Need to be logged in to add rating!")
+    })?;
+    let comment = rating_details.validate()?;
+    let selected_user = customer
+        .filter(username.eq(&uname))
+        .limit(1)
+        .first::<Customer>(conn)
+        .expect("Couldn't connect to DB");
+    let found = product
+        .find(rating_details.product_id)
+        .first::<Product>(conn)
+        .optional()
+        .expect("Couldn't connect to DB");
+    if found.is_none() {
+        return Err(HttpResponse::NotFound().body("Product not found"));
+    }
+    Ok((selected_user, comment))
+}
+
 pub async fn add_rating(
     cookie: Identity,
     rating_details: web::Json<AddRatingJson>,
@@ -24,31 +122,107 @@ pub async fn add_rating(
     info!("Add rating hit: {:?}", rating_details.product_id);
     info!("{:?}", cookie.identity());
     let conn = pool.get().unwrap();
-    if let Some(uname) = cookie.identity() {
-        let selected_user = customer
-            .filter(username.eq(&uname))
-            .limit(1)
-            .first::<Customer>(&conn)
-            .expect("Couldn't connect to DB");
-        let rating_details = rating_details.into_inner();
-        let new_rating = AddRating {
-            comment_text: rating_details.comment_text,
-            stars: rating_details.stars,
-            product_id: rating_details.product_id,
-            customer_id: selected_user.id,
+    let (selected_user, comment) =
+        match prepare_review(&cookie, &conn, &rating_details) {
+            Ok(r) => r,
+            Err(resp) => return resp,
         };
-        diesel::insert_into(rating::rating)
-            .values(new_rating)
-            .execute(&conn)
-            .expect("Coundn't connect to DB");
-        HttpResponse::Ok().body("Inserted rating successfully!")
-    } else {
-        error!("Unauthorized add rating action!");
-        return HttpResponse::Unauthorized()
-            .body("Need to be logged in to add rating!");
+    let rating_details = rating_details.into_inner();
+    let (review_status, flag) = screen(comment.as_deref(), None);
+    let new_rating = AddRating {
+        status: review_status.clone(),
+        flag_reason: flag,
+        comment_text: comment,
+        stars: rating_details.stars,
+        product_id: rating_details.product_id,
+        customer_id: selected_user.id,
+        verified_purchase: purchased(
+            &conn,
+            selected_user.id,
+            rating_details.product_id,
+        )
+        .expect("Couldn't connect to DB"),
+    };
+    let inserted = diesel::insert_into(rating::rating)
+        .values(new_rating)
+        .execute(&conn);
+    if inserted.is_ok() {
+        invalidate_summary(rating_details.product_id);
+    }
+    match inserted {
+        Ok(_) if review_status == APPROVED => {
+            HttpResponse::Ok().body("Inserted rating successfully!")
+        }
+        Ok(_) => HttpResponse::Ok()
+            .body("Thanks, your review will appear once it is approved"),
+        Err(DBError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            HttpResponse::Conflict()
+                .body("Product already reviewed, edit the review instead")
+        }
+        Err(e) => panic!("Coundn't connect to DB: {}", e),
     }
 }
 
+/// Creates or replaces the caller's review of a product, and responds with
+/// the stored review. Edited reviews go through moderation again.
+pub async fn edit_rating(
+    cookie: Identity,
+    rating_details: web::Json<AddRatingJson>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    info!("Edit rating hit: {:?}", rating_details.product_id);
+    let conn = pool.get().unwrap();
+    let (selected_user, comment) =
+        match prepare_review(&cookie, &conn, &rating_details) {
+            Ok(r) => r,
+            Err(resp) => return resp,
+        };
+    let rating_details = rating_details.into_inner();
+    let (cid, pid) = (selected_user.id, rating_details.product_id);
+    let stored = conn
+        .transaction::<_, DBError, _>(|| {
+            let verified = purchased(&conn, cid, pid)?;
+            let edited = diesel::select(now).first::<NaiveDateTime>(&conn)?;
+            match existing_review(&conn, cid, pid)? {
+                Some(r) => {
+                    let (review_status, flag) =
+                        screen(comment.as_deref(), Some(&r.status));
+                    diesel::update(rating::rating.find(r.id))
+                        .set((
+                            rating::comment_text.eq(comment),
+                            rating::stars.eq(rating_details.stars),
+                            rating::verified_purchase.eq(verified),
+                            rating::edited_at.eq(edited),
+                            rating::status.eq(review_status),
+                            rating::flag_reason.eq(flag),
+                            rating::moderated_by.eq(None::<i32>),
+                            rating::moderated_at.eq(None::<NaiveDateTime>),
+                        ))
+                        .execute(&conn)?;
+                }
+                None => {
+                    let (review_status, flag) =
+                        screen(comment.as_deref(), None);
+                    diesel::insert_into(rating::rating)
+                        .values(AddRating {
+                            comment_text: comment,
+                            stars: rating_details.stars,
+                            product_id: pid,
+                            customer_id: cid,
+                            verified_purchase: verified,
+                            status: review_status,
+                            flag_reason: flag,
+                        })
+                        .execute(&conn)?;
+                }
+            }
+            existing_review(&conn, cid, pid)
+        })
+        .expect("Couldn't connect to DB");
+    invalidate_summary(pid);
+    HttpResponse::Ok().json(&stored)
+}
+
 #[derive(Deserialize, Debug)]
 pub struct RemoveRating {
     rating_id: i32,
@@ -68,13 +242,29 @@ pub async fn remove_rating(
             .first::<Customer>(&conn)
             .expect("Couldn't connect to DB");
 
-        diesel::delete(
-            rating::rating
-                .filter(rating::customer_id.eq(selected_user.id))
-                .filter(rating::id.eq(rating_details.rating_id)),
-        )
-        .execute(&conn)
-        .expect("Coundn't connect to DB");
+        let own_rating = rating::rating
+            .filter(rating::customer_id.eq(selected_user.id))
+            .filter(rating::id.eq(rating_details.rating_id));
+        let reviewed = own_rating
+            .select(rating::product_id)
+            .first::<Option<i32>>(&conn)
+            .optional()
+            .expect("Coundn't connect to DB");
+        let photo_files = conn
+            .transaction::<_, DBError, _>(|| {
+                let mut photo_files = vec![];
+                if reviewed.is_some() {
+                    photo_files =
+                        delete_photos(&conn, rating_details.rating_id)?;
+                }
+                diesel::delete(own_rating).execute(&conn)?;
+                Ok(photo_files)
+            })
+            .expect("Coundn't connect to DB");
+        remove_photo_files(&photo_files);
+        if let Some(Some(pid)) = reviewed {
+            invalidate_summary(pid);
+        }
         HttpResponse::Ok().body("Removed successfully!")
     } else {
         error!("Unauthorized add to cart action!");
@@ -82,3 +272,220 @@ pub async fn remove_rating(
             .body("Need to be logged in to add to cart!");
     }
 }
+
+#[derive(Deserialize, Debug)]
+pub struct VoteRating {
+    rating_id: i32,
+    helpful: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct VoteCounts {
+    helpful_votes: i32,
+    not_helpful_votes: i32,
+}
+
+/// Moves the vote counter of a review matching `helpful` by `delta`.
+fn count_vote(
+    conn: &MysqlConnection,
+    rid: i32,
+    helpful: bool,
+    delta: i32,
+) -> QueryResult<usize> {
+    let target = rating::rating.find(rid);
+    if helpful {
+        diesel::update(target)
+            .set(rating::helpful_votes.eq(rating::helpful_votes + delta))
+            .execute(conn)
+    } else {
+        diesel::update(target)
+            .set(
+                rating::not_helpful_votes.eq(rating::not_helpful_votes + delta),
+            )
+            .execute(conn)
+    }
+}
+
+/// Records whether the caller found a review helpful, replacing their
+/// earlier vote. A `helpful` of null withdraws the vote.
+pub async fn vote_rating(
+    cookie: Identity,
+    vote: web::Json<VoteRating>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    info!("Vote rating hit: {:?}", vote);
+    let conn = pool.get().unwrap();
+    let uname = match cookie.identity() {
+        Some(u) => u,
+        None => {
+            return HttpResponse::Unauthorized()
+                .body("Need to be logged in to vote on reviews!")
+        }
+    };
+    let selected_user = customer
+        .filter(username.eq(&uname))
+        .limit(1)
+        .first::<Customer>(&conn)
+        .expect("Couldn't connect to DB");
+    let voted = match rating::rating
+        .find(vote.rating_id)
+        .filter(rating::status.eq(APPROVED))
+        .first::<Rating>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB")
+    {
+        Some(r) => r,
+        None => return HttpResponse::NotFound().body("Review not found"),
+    };
+    if voted.customer_id == Some(selected_user.id) {
+        return HttpResponse::BadRequest()
+            .body("You cannot vote on your own review");
+    }
+    let counts = conn
+        .transaction::<_, DBError, _>(|| {
+            let own_vote = rv::rating_vote
+                .filter(rv::rating_id.eq(voted.id))
+                .filter(rv::customer_id.eq(selected_user.id));
+            let previous = own_vote
+                .select(rv::helpful)
+                .first::<bool>(&conn)
+                .optional()?;
+            if let Some(h) = previous {
+                diesel::delete(own_vote).execute(&conn)?;
+                count_vote(&conn, voted.id, h, -1)?;
+            }
+            if let Some(h) = vote.helpful {
+                diesel::insert_into(rv::rating_vote)
+                    .values(AddRatingVote {
+                        rating_id: voted.id,
+                        customer_id: selected_user.id,
+                        helpful: h,
+                    })
+                    .execute(&conn)?;
+                count_vote(&conn, voted.id, h, 1)?;
+            }
+            rating::rating
+                .find(voted.id)
+                .select((rating::helpful_votes, rating::not_helpful_votes))
+                .first::<(i32, i32)>(&conn)
+        })
+        .expect("Couldn't connect to DB");
+    HttpResponse::Ok().json(&VoteCounts {
+        helpful_votes: counts.0,
+        not_helpful_votes: counts.1,
+    })
+}
+
+#[derive(QueryableByName)]
+struct StarCount {
+    #[sql_type = "Nullable<Integer>"]
+    stars: Option<i32>,
+    #[sql_type = "BigInt"]
+    reviews: i64,
+    #[sql_type = "BigInt"]
+    verified: i64,
+}
+
+/// Approved reviews of a product at a glance. `stars` counts the reviews
+/// for each star level, reviews without stars only count towards `total`.
+#[derive(Serialize, Deserialize)]
+pub struct RatingSummary {
+    product_id: i32,
+    total: i64,
+    average: Option<f64>,
+    stars: BTreeMap<i32, i64>,
+    verified_percentage: Option<f64>,
+}
+
+fn summary_key(pid: i32) -> String {
+    format!("rating_summary:{}", pid)
+}
+
+fn summary_ttl() -> usize {
+    std::env::var("FURBY_RATING_SUMMARY_TTL")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(60 * 60)
+}
+
+pub fn rating_summary(
+    conn: &MysqlConnection,
+    pid: i32,
+) -> QueryResult<RatingSummary> {
+    let counts = diesel::sql_query(
+        "select stars, count(*) as reviews, \
+         cast(sum(verified_purchase) as signed) as verified \
+         from rating where product_id = ? and status = ? group by stars",
+    )
+    .bind::<Integer, _>(pid)
+    .bind::<diesel::sql_types::Varchar, _>(APPROVED)
+    .load::<StarCount>(conn)?;
+    let mut stars = (1..=5).map(|s| (s, 0)).collect::<BTreeMap<_, _>>();
+    let (mut total, mut verified, mut rated, mut star_sum) = (0, 0, 0, 0);
+    for c in counts {
+        total += c.reviews;
+        verified += c.verified;
+        if let Some(s) = c.stars {
+            *stars.entry(s).or_insert(0) += c.reviews;
+            rated += c.reviews;
+            star_sum += s as i64 * c.reviews;
+        }
+    }
+    Ok(RatingSummary {
+        product_id: pid,
+        total,
+        average: if rated > 0 {
+            Some(star_sum as f64 / rated as f64)
+        } else {
+            None
+        },
+        stars,
+        verified_percentage: if total > 0 {
+            Some(verified as f64 * 100. / total as f64)
+        } else {
+            None
+        },
+    })
+}
+
+/// Drops the cached summary of a product after its reviews changed.
+pub fn invalidate_summary(pid: i32) {
+    let deleted = redis::Client::open("redis://127.0.0.1/")
+        .and_then(|c| c.get_connection())
+        .and_then(|mut c| c.del::<_, ()>(summary_key(pid)));
+    if let Err(e) = deleted {
+        error!("Unable to invalidate rating summary of {}: {}", pid, e);
+    }
+}
+
+pub async fn product_rating_summary(
+    product_id: web::Path<i32>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let pid = product_id.into_inner();
+    let redis_client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let mut redis_conn = redis_client.get_connection().unwrap();
+    let cached: Option<String> = redis_conn.get(summary_key(pid)).unwrap();
+    if let Some(c) = cached {
+        if let Ok(summary) = serde_json::from_str::<RatingSummary>(&c) {
+            return HttpResponse::Ok().json(&summary);
+        }
+    }
+    let conn = pool.get().unwrap();
+    let found = product
+        .find(pid)
+        .first::<Product>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB");
+    if found.is_none() {
+        return HttpResponse::NotFound().body("Product not found");
+    }
+    info!("Computing rating summary for {}", pid);
+    let summary = rating_summary(&conn, pid).expect("Couldn't connect to DB");
+    let _: redis::RedisResult<()> = redis_conn.set_ex(
+        summary_key(pid),
+        serde_json::to_string(&summary).unwrap(),
+        summary_ttl(),
+    );
+    HttpResponse::Ok().json(&summary)
+}
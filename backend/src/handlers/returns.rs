@@ -0,0 +1,469 @@
+use crate::handlers::payment::{issue_refund, RefundError};
+use crate::handlers::users::staff_member;
+use crate::models::{
+    AddReturnEvent, AddReturnRequest, Customer, ReturnEvent, ReturnItem,
+    ReturnRequest, Transaction, TransactionItem,
+};
+use crate::schema::customer::dsl::*;
+use crate::schema::product::dsl as prod;
+use crate::schema::return_event::dsl as re;
+use crate::schema::return_item::dsl as ri;
+use crate::schema::return_request::dsl as rr;
+use crate::schema::transaction::dsl as ts;
+use crate::schema::transaction_item::dsl as ti;
+use crate::{last_insert_id, TPool};
+
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse, Responder};
+use diesel::prelude::*;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+#[derive(Serialize)]
+pub struct ReturnDetails {
+    #[serde(flatten)]
+    pub request: ReturnRequest,
+    pub items: Vec<ReturnItem>,
+    pub events: Vec<ReturnEvent>,
+}
+
+pub fn return_details(
+    conn: &MysqlConnection,
+    request: ReturnRequest,
+) -> QueryResult<ReturnDetails> {
+    let items = ri::return_item
+        .filter(ri::return_id.eq(request.id))
+        .load::<ReturnItem>(conn)?;
+    let events = re::return_event
+        .filter(re::return_id.eq(request.id))
+        .order(re::created_at.asc())
+        .load::<ReturnEvent>(conn)?;
+    Ok(ReturnDetails {
+        request,
+        items,
+        events,
+    })
+}
+
+fn record_event(
+    conn: &MysqlConnection,
+    return_id: i32,
+    status: &str,
+    note: Option<String>,
+) -> QueryResult<usize> {
+    diesel::insert_into(re::return_event)
+        .values(AddReturnEvent {
+            return_id,
+            status: status.to_string(),
+            note,
+        })
+        .execute(conn)
+}
+
+/// Quantity of an order line already claimed by returns that were not
+/// rejected.
+pub fn returned_quantity(
+    conn: &MysqlConnection,
+    transaction_item_id: i32,
+) -> QueryResult<i32> {
+    let claimed: Vec<i32> = ri::return_item
+        .inner_join(rr::return_request)
+        .filter(ri::transaction_item_id.eq(transaction_item_id))
+        .filter(rr::status.ne("rejected"))
+        .select(ri::quantity)
+        .load(conn)?;
+    Ok(claimed.into_iter().sum())
+}
+
+/// Whether an order line is part of a return that is still being handled.
+pub fn has_open_return(
+    conn: &MysqlConnection,
+    transaction_item_id: i32,
+) -> QueryResult<bool> {
+    let open = ri::return_item
+        .inner_join(rr::return_request)
+        .filter(ri::transaction_item_id.eq(transaction_item_id))
+        .filter(rr::status.eq_any(vec!["requested", "approved"]))
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(open > 0)
+}
+
+fn refund_total(
+    conn: &MysqlConnection,
+    request: &ReturnRequest,
+) -> QueryResult<f32> {
+    let lines: Vec<(i32, f32)> = ri::return_item
+        .inner_join(ti::transaction_item)
+        .filter(ri::return_id.eq(request.id))
+        .select((ri::quantity, ti::unit_price))
+        .load(conn)?;
+    Ok(lines.into_iter().map(|(q, p)| q as f32 * p).sum())
+}
+
+/// Why a return request was not created.
+#[derive(Debug)]
+pub enum ReturnError {
+    Db(diesel::result::Error),
+    /// Only `returnable` of order line `item` are left to return.
+    TooMany {
+        item: i32,
+        returnable: i32,
+    },
+}
+
+impl fmt::Display for ReturnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReturnError::Db(e) => write!(f, "{}", e),
+            ReturnError::TooMany { item, returnable } => {
+                write!(
+                    f,
+                    "Only {} of item {} can be returned",
+                    returnable, item
+                )
+            }
+        }
+    }
+}
+
+impl From<diesel::result::Error> for ReturnError {
+    fn from(e: diesel::result::Error) -> Self {
+        ReturnError::Db(e)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReturnLine {
+    transaction_item_id: i32,
+    quantity: i32,
+    reason: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewReturn {
+    transaction_id: i32,
+    items: Vec<ReturnLine>,
+}
+
+pub async fn request_return(
+    cookie: Identity,
+    return_details_json: web::Json<NewReturn>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    info!("Return request: {:?}", return_details_json);
+    let conn = pool.get().unwrap();
+    if let Some(uname) = cookie.identity() {
+        let selected_user = customer
+            .filter(username.eq(&uname))
+            .limit(1)
+            .first::<Customer>(&conn)
+            .expect("Couldn't connect to DB");
+        let details = return_details_json.into_inner();
+        let order = ts::transaction
+            .filter(ts::id.eq(details.transaction_id))
+            .filter(ts::customer_id.eq(selected_user.id))
+            .first::<Transaction>(&conn);
+        let order = match order {
+            Ok(o) => o,
+            Err(_) => return HttpResponse::NotFound().body("Order not found"),
+        };
+        if order.fulfilment_status != "shipped" {
+            return HttpResponse::Conflict()
+                .body("Only shipped orders can be returned");
+        }
+        if details.items.is_empty() {
+            return HttpResponse::BadRequest()
+                .body("Select at least one item to return");
+        }
+        let mut item_ids = details
+            .items
+            .iter()
+            .map(|l| l.transaction_item_id)
+            .collect::<Vec<_>>();
+        item_ids.sort_unstable();
+        item_ids.dedup();
+        if item_ids.len() != details.items.len() {
+            return HttpResponse::BadRequest()
+                .body("Each item can only be listed once");
+        }
+        for line in &details.items {
+            let order_line = ti::transaction_item
+                .filter(ti::id.eq(line.transaction_item_id))
+                .filter(ti::transaction_id.eq(details.transaction_id))
+                .first::<TransactionItem>(&conn);
+            let order_line = match order_line {
+                Ok(l) => l,
+                Err(_) => {
+                    return HttpResponse::BadRequest()
+                        .body("Item is not part of this order")
+                }
+            };
+            if line.reason.trim().is_empty() {
+                return HttpResponse::BadRequest()
+                    .body("A reason is required for every item");
+            }
+            if line.quantity < 1 {
+                return HttpResponse::BadRequest().body(format!(
+                    "Return at least one of item {}",
+                    order_line.id
+                ));
+            }
+        }
+        let created = conn.transaction::<_, ReturnError, _>(|| {
+            // with the order locked, concurrent returns and cancellations
+            // of it see each other's quantities
+            ts::transaction
+                .find(order.id)
+                .for_update()
+                .first::<Transaction>(&conn)?;
+            for line in &details.items {
+                let order_line = ti::transaction_item
+                    .find(line.transaction_item_id)
+                    .first::<TransactionItem>(&conn)?;
+                let returnable = order_line.quantity
+                    - order_line.cancelled_quantity
+                    - returned_quantity(&conn, order_line.id)?;
+                if line.quantity > returnable {
+                    return Err(ReturnError::TooMany {
+                        item: order_line.id,
+                        returnable: returnable.max(0),
+                    });
+                }
+            }
+            diesel::insert_into(rr::return_request)
+                .values(AddReturnRequest {
+                    transaction_id: details.transaction_id,
+                    customer_id: selected_user.id,
+                })
+                .execute(&conn)?;
+            let rid =
+                diesel::select(last_insert_id).first::<u64>(&conn)? as i32;
+            let items = details
+                .items
+                .into_iter()
+                .map(|line| ReturnItem {
+                    return_id: rid,
+                    transaction_item_id: line.transaction_item_id,
+                    quantity: line.quantity,
+                    reason: line.reason,
+                })
+                .collect::<Vec<_>>();
+            diesel::insert_into(ri::return_item)
+                .values(&items)
+                .execute(&conn)?;
+            record_event(&conn, rid, "requested", None)?;
+            let request = rr::return_request
+                .filter(rr::id.eq(rid))
+                .first::<ReturnRequest>(&conn)?;
+            Ok(return_details(&conn, request)?)
+        });
+        match created {
+            Ok(r) => HttpResponse::Ok().json(&r),
+            Err(e @ ReturnError::TooMany { .. }) => {
+                HttpResponse::BadRequest().body(e.to_string())
+            }
+            Err(e) => {
+                error!("Unable to create return: {}", e);
+                HttpResponse::InternalServerError()
+                    .body("Unable to create return")
+            }
+        }
+    } else {
+        HttpResponse::Unauthorized()
+            .body("Need to be logged in to request a return!")
+    }
+}
+
+pub async fn list_returns(
+    cookie: Identity,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    if let Some(uname) = cookie.identity() {
+        let selected_user = customer
+            .filter(username.eq(&uname))
+            .limit(1)
+            .first::<Customer>(&conn)
+            .expect("Couldn't connect to DB");
+        let user_returns = rr::return_request
+            .filter(rr::customer_id.eq(selected_user.id))
+            .order(rr::created_at.desc())
+            .load::<ReturnRequest>(&conn)
+            .expect("Couldn't connect to DB")
+            .into_iter()
+            .map(|r| return_details(&conn, r))
+            .collect::<QueryResult<Vec<_>>>()
+            .expect("Couldn't connect to DB");
+        HttpResponse::Ok().json(&user_returns)
+    } else {
+        HttpResponse::Unauthorized()
+            .body("Need to be logged in to view returns!")
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReviewReturn {
+    note: Option<String>,
+    refund_amount: Option<f32>,
+}
+
+pub async fn approve_return(
+    cookie: Identity,
+    return_id: web::Path<i32>,
+    review: web::Json<ReviewReturn>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let staff = match staff_member(&cookie, &conn) {
+        Ok(s) => s,
+        Err(resp) => return resp,
+    };
+    let return_id = return_id.into_inner();
+    let review = review.into_inner();
+    info!(
+        "{} approving return {}: {:?}",
+        staff.username, return_id, review
+    );
+    let request = match rr::return_request
+        .filter(rr::id.eq(return_id))
+        .first::<ReturnRequest>(&conn)
+    {
+        Ok(r) => r,
+        Err(_) => return HttpResponse::NotFound().body("Return not found"),
+    };
+    if request.status != "requested" {
+        return HttpResponse::Conflict()
+            .body(format!("Return is already {}", request.status));
+    }
+    let full_refund =
+        refund_total(&conn, &request).expect("Couldn't connect to DB");
+    let refund = review.refund_amount.unwrap_or(full_refund);
+    if refund < 0. || refund > full_refund {
+        return HttpResponse::BadRequest()
+            .body(format!("Refund must be between 0 and {}", full_refund));
+    }
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::update(rr::return_request.filter(rr::id.eq(return_id)))
+            .set((rr::status.eq("approved"), rr::refund_amount.eq(refund)))
+            .execute(&conn)?;
+        record_event(&conn, return_id, "approved", review.note)?;
+        Ok(())
+    })
+    .expect("Couldn't connect to DB");
+    HttpResponse::Ok().body("Return approved")
+}
+
+pub async fn reject_return(
+    cookie: Identity,
+    return_id: web::Path<i32>,
+    review: web::Json<ReviewReturn>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let staff = match staff_member(&cookie, &conn) {
+        Ok(s) => s,
+        Err(resp) => return resp,
+    };
+    let return_id = return_id.into_inner();
+    let review = review.into_inner();
+    info!(
+        "{} rejecting return {}: {:?}",
+        staff.username, return_id, review
+    );
+    let request = match rr::return_request
+        .filter(rr::id.eq(return_id))
+        .first::<ReturnRequest>(&conn)
+    {
+        Ok(r) => r,
+        Err(_) => return HttpResponse::NotFound().body("Return not found"),
+    };
+    if request.status != "requested" && request.status != "approved" {
+        return HttpResponse::Conflict()
+            .body(format!("Return is already {}", request.status));
+    }
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::update(rr::return_request.filter(rr::id.eq(return_id)))
+            .set(rr::status.eq("rejected"))
+            .execute(&conn)?;
+        record_event(&conn, return_id, "rejected", review.note)?;
+        Ok(())
+    })
+    .expect("Couldn't connect to DB");
+    HttpResponse::Ok().body("Return rejected")
+}
+
+pub async fn receive_return(
+    cookie: Identity,
+    return_id: web::Path<i32>,
+    review: web::Json<ReviewReturn>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let staff = match staff_member(&cookie, &conn) {
+        Ok(s) => s,
+        Err(resp) => return resp,
+    };
+    let return_id = return_id.into_inner();
+    let review = review.into_inner();
+    info!("{} receiving return {}", staff.username, return_id);
+    let request = match rr::return_request
+        .filter(rr::id.eq(return_id))
+        .first::<ReturnRequest>(&conn)
+    {
+        Ok(r) => r,
+        Err(_) => return HttpResponse::NotFound().body("Return not found"),
+    };
+    if request.status != "approved" {
+        return HttpResponse::Conflict()
+            .body("Only approved returns can be received");
+    }
+    let received = conn.transaction::<_, RefundError, _>(|| {
+        let lines: Vec<(i32, i32)> = ri::return_item
+            .inner_join(ti::transaction_item)
+            .filter(ri::return_id.eq(return_id))
+            .select((ti::product_id, ri::quantity))
+            .load(&conn)?;
+        for (pid, q) in lines {
+            diesel::update(prod::product.filter(prod::id.eq(pid)))
+                .set(prod::stock.eq(prod::stock + q))
+                .execute(&conn)?;
+        }
+        diesel::update(rr::return_request.filter(rr::id.eq(return_id)))
+            .set(rr::status.eq("received"))
+            .execute(&conn)?;
+        record_event(&conn, return_id, "received", review.note)?;
+        let payment = ts::transaction
+            .find(request.transaction_id)
+            .select(ts::payment_status)
+            .first::<String>(&conn)?;
+        // nothing to give back if the payment never came through
+        let paid = payment == "paid" || payment == "partially_refunded";
+        let refund = request.refund_amount.unwrap_or(0.);
+        if paid && refund > 0. {
+            let refund_id =
+                issue_refund(&conn, request.transaction_id, refund)?;
+            diesel::update(rr::return_request.filter(rr::id.eq(return_id)))
+                .set((rr::status.eq("refunded"), rr::refund_id.eq(refund_id)))
+                .execute(&conn)?;
+            record_event(
+                &conn,
+                return_id,
+                "refunded",
+                Some(format!("Refund of {} issued", refund)),
+            )?;
+        }
+        Ok(())
+    });
+    match received {
+        Ok(_) => HttpResponse::Ok().body("Return received"),
+        Err(e @ RefundError::ExceedsPayment { .. }) => HttpResponse::Conflict()
+            .body(format!("Unable to refund the return, {}", e)),
+        Err(e) => {
+            error!("Unable to receive return {}: {}", return_id, e);
+            HttpResponse::InternalServerError().body("Unable to receive return")
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use crate::handlers::moderation::screen;
+use crate::handlers::rating::invalidate_summary;
+use crate::models::{AddRatingPhoto, Customer, Rating, RatingPhoto};
+use crate::schema::customer::dsl::*;
+use crate::schema::rating::dsl as rating;
+use crate::schema::rating_photo::dsl as rp;
+use crate::storage::LocalStorage;
+use crate::TPool;
+
+use actix_identity::Identity;
+use actix_multipart::Multipart;
+use actix_web::error::BlockingError;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::naive::NaiveDateTime;
+use diesel::prelude::*;
+use futures::StreamExt;
+use image::io::Reader;
+use image::ImageFormat;
+use log::{error, info};
+use serde::Serialize;
+
+use std::io::{self, Cursor};
+
+const MAX_PHOTOS_PER_REVIEW: usize = 4;
+const THUMBNAIL_SIZE: u32 = 320;
+/// Largest photo that is decoded, a small file can claim a huge image.
+const MAX_PHOTO_SIDE: u32 = 10_000;
+const MAX_PHOTO_PIXELS: u64 = 40_000_000;
+
+fn max_photo_bytes() -> usize {
+    std::env::var("FURBY_REVIEW_PHOTO_MAX_BYTES")
+        .ok()
+        .and_then(|b| b.parse().ok())
+        .unwrap_or(5 * 1024 * 1024)
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReviewPhoto {
+    pub id: i32,
+    pub url: String,
+    pub thumbnail_url: String,
+}
+
+impl ReviewPhoto {
+    pub fn new(storage: &LocalStorage, photo: &RatingPhoto) -> Self {
+        ReviewPhoto {
+            id: photo.id,
+            url: storage.url(&photo.storage_key),
+            thumbnail_url: storage.url(&photo.thumbnail_key),
+        }
+    }
+}
+
+/// Photos of each of the reviews `rids`, oldest first.
+pub fn photos_of(
+    conn: &MysqlConnection,
+    rids: &[i32],
+) -> QueryResult<Vec<RatingPhoto>> {
+    rp::rating_photo
+        .filter(rp::rating_id.eq_any(rids))
+        .order(rp::id.asc())
+        .load::<RatingPhoto>(conn)
+}
+
+fn remove_files(storage: &LocalStorage, keys: &[&str]) {
+    for key in keys {
+        if let Err(e) = storage.remove(key) {
+            error!("Unable to remove {}: {}", key, e);
+        }
+    }
+}
+
+/// Deletes the photos of a review and returns the keys of their files. The
+/// files are only removed by `remove_photo_files`, once the deletion is
+/// committed, so a rollback never leaves photos without files.
+pub fn delete_photos(
+    conn: &MysqlConnection,
+    rid: i32,
+) -> QueryResult<Vec<String>> {
+    let keys = photos_of(conn, &[rid])?
+        .into_iter()
+        .flat_map(|p| vec![p.storage_key, p.thumbnail_key])
+        .collect();
+    diesel::delete(rp::rating_photo.filter(rp::rating_id.eq(rid)))
+        .execute(conn)?;
+    Ok(keys)
+}
+
+/// Removes the files of photos deleted with `delete_photos`.
+pub fn remove_photo_files(keys: &[String]) {
+    let storage = LocalStorage::from_env();
+    remove_files(
+        &storage,
+        &keys.iter().map(|k| k.as_str()).collect::<Vec<_>>(),
+    );
+}
+
+#[derive(Debug)]
+enum PhotoError {
+    Unsupported,
+    Corrupt,
+    TooLarge,
+    Io(io::Error),
+}
+
+struct StoredPhoto {
+    storage_key: String,
+    thumbnail_key: String,
+    content_type: &'static str,
+    size: usize,
+}
+
+/// Checks that `bytes` hold a JPEG or PNG image of a sane size, whatever
+/// the client claimed, and stores it along with a thumbnail. Decoding is
+/// slow, so this is meant to run in `web::block`.
+fn store_photo(bytes: Vec<u8>) -> Result<StoredPhoto, PhotoError> {
+    let format =
+        image::guess_format(&bytes).map_err(|_| PhotoError::Unsupported)?;
+    let (extension, content_type) = match format {
+        ImageFormat::Jpeg => ("jpg", "image/jpeg"),
+        ImageFormat::Png => ("png", "image/png"),
+        _ => return Err(PhotoError::Unsupported),
+    };
+    let (width, height) = Reader::with_format(Cursor::new(&bytes), format)
+        .into_dimensions()
+        .map_err(|_| PhotoError::Corrupt)?;
+    if width > MAX_PHOTO_SIDE
+        || height > MAX_PHOTO_SIDE
+        || width as u64 * height as u64 > MAX_PHOTO_PIXELS
+    {
+        return Err(PhotoError::TooLarge);
+    }
+    let img = image::load_from_memory_with_format(&bytes, format)
+        .map_err(|_| PhotoError::Corrupt)?;
+    let mut thumbnail = vec![];
+    img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut thumbnail, format)
+        .map_err(|_| PhotoError::Corrupt)?;
+
+    let storage = LocalStorage::from_env();
+    let storage_key = LocalStorage::new_key("reviews", extension);
+    let thumbnail_key = LocalStorage::new_key("reviews/thumbnails", extension);
+    storage.save(&storage_key, &bytes).map_err(PhotoError::Io)?;
+    if let Err(e) = storage.save(&thumbnail_key, &thumbnail) {
+        remove_files(&storage, &[&storage_key]);
+        return Err(PhotoError::Io(e));
+    }
+    Ok(StoredPhoto {
+        storage_key,
+        thumbnail_key,
+        content_type,
+        size: bytes.len(),
+    })
+}
+
+/// Logged in customer and their review `rid`, or the response to send.
+fn own_review(
+    cookie: &Identity,
+    conn: &MysqlConnection,
+    rid: i32,
+) -> Result<Rating, HttpResponse> {
+    let uname = cookie.identity().ok_or_else(|| {
+        HttpResponse::Unauthorized()
+            .body("Need to be logged in to manage review photos!")
+    })?;
+    let selected_user = customer
+        .filter(username.eq(&uname))
+        .limit(1)
+        .first::<Customer>(conn)
+        .expect("Couldn't connect to DB");
+    rating::rating
+        .find(rid)
+        .filter(rating::customer_id.eq(selected_user.id))
+        .first::<Rating>(conn)
+        .optional()
+        .expect("Couldn't connect to DB")
+        .ok_or_else(|| HttpResponse::NotFound().body("Review not found"))
+}
+
+/// Attaches the images of a multipart upload to a review. Each part must
+/// be a JPEG or PNG of at most `FURBY_REVIEW_PHOTO_MAX_BYTES`. The review
+/// goes through moderation again.
+pub async fn upload_photos(
+    cookie: Identity,
+    rating_id: web::Path<i32>,
+    mut payload: Multipart,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let review = match own_review(&cookie, &conn, rating_id.into_inner()) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+    info!("Photo upload for review {}", review.id);
+    let existing = photos_of(&conn, &[review.id])
+        .expect("Couldn't connect to DB")
+        .len();
+    let storage = LocalStorage::from_env();
+    let mut stored: Vec<StoredPhoto> = vec![];
+    let discard = |stored: &[StoredPhoto]| {
+        for p in stored {
+            remove_files(&storage, &[&p.storage_key, &p.thumbnail_key]);
+        }
+    };
+
+    while let Some(field) = payload.next().await {
+        let mut field = match field {
+            Ok(f) => f,
+            Err(e) => {
+                discard(&stored);
+                return HttpResponse::BadRequest()
+                    .body(format!("Malformed upload: {}", e));
+            }
+        };
+        if existing + stored.len() >= MAX_PHOTOS_PER_REVIEW {
+            discard(&stored);
+            return HttpResponse::BadRequest().body(format!(
+                "A review can have at most {} photos",
+                MAX_PHOTOS_PER_REVIEW
+            ));
+        }
+        let mut bytes = vec![];
+        while let Some(chunk) = field.next().await {
+            let chunk = match chunk {
+                Ok(c) => c,
+                Err(e) => {
+                    discard(&stored);
+                    return HttpResponse::BadRequest()
+                        .body(format!("Malformed upload: {}", e));
+                }
+            };
+            if bytes.len() + chunk.len() > max_photo_bytes() {
+                discard(&stored);
+                return HttpResponse::PayloadTooLarge().body(format!(
+                    "Photos can be at most {} bytes",
+                    max_photo_bytes()
+                ));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        match web::block(move || store_photo(bytes)).await {
+            Ok(p) => stored.push(p),
+            Err(BlockingError::Error(PhotoError::Unsupported)) => {
+                discard(&stored);
+                return HttpResponse::UnsupportedMediaType()
+                    .body("Photos must be JPEG or PNG images");
+            }
+            Err(BlockingError::Error(PhotoError::Corrupt)) => {
+                discard(&stored);
+                return HttpResponse::BadRequest()
+                    .body("Photo could not be read");
+            }
+            Err(BlockingError::Error(PhotoError::TooLarge)) => {
+                discard(&stored);
+                return HttpResponse::PayloadTooLarge().body(format!(
+                    "Photos can be at most {} pixels on a side and {} \
+                     megapixels",
+                    MAX_PHOTO_SIDE,
+                    MAX_PHOTO_PIXELS / 1_000_000
+                ));
+            }
+            Err(BlockingError::Error(PhotoError::Io(e))) => {
+                discard(&stored);
+                error!("Unable to store review photo: {}", e);
+                return HttpResponse::InternalServerError()
+                    .body("Unable to store photo");
+            }
+            Err(BlockingError::Canceled) => {
+                discard(&stored);
+                error!("Storing review photo was cancelled");
+                return HttpResponse::InternalServerError()
+                    .body("Unable to store photo");
+            }
+        }
+    }
+    if stored.is_empty() {
+        return HttpResponse::BadRequest().body("No photos uploaded");
+    }
+
+    let (review_status, flag) =
+        screen(review.comment_text.as_deref(), Some(&review.status));
+    let saved = conn.transaction::<_, diesel::result::Error, _>(|| {
+        for p in &stored {
+            diesel::insert_into(rp::rating_photo)
+                .values(AddRatingPhoto {
+                    rating_id: review.id,
+                    storage_key: p.storage_key.clone(),
+                    thumbnail_key: p.thumbnail_key.clone(),
+                    content_type: p.content_type.to_string(),
+                    size: p.size as i32,
+                })
+                .execute(&conn)?;
+        }
+        diesel::update(rating::rating.find(review.id))
+            .set((
+                rating::status.eq(review_status),
+                rating::flag_reason.eq(flag),
+                rating::moderated_by.eq(None::<i32>),
+                rating::moderated_at.eq(None::<NaiveDateTime>),
+            ))
+            .execute(&conn)?;
+        photos_of(&conn, &[review.id])
+    });
+    match saved {
+        Ok(photos) => {
+            if let Some(pid) = review.product_id {
+                invalidate_summary(pid);
+            }
+            let photos = photos
+                .iter()
+                .map(|p| ReviewPhoto::new(&storage, p))
+                .collect::<Vec<_>>();
+            HttpResponse::Ok().json(&photos)
+        }
+        Err(e) => {
+            discard(&stored);
+            panic!("Couldn't connect to DB: {}", e);
+        }
+    }
+}
+
+pub async fn remove_photo(
+    cookie: Identity,
+    path: web::Path<(i32, i32)>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let (rid, photo_id) = path.into_inner();
+    let review = match own_review(&cookie, &conn, rid) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+    let photo = match rp::rating_photo
+        .find(photo_id)
+        .filter(rp::rating_id.eq(review.id))
+        .first::<RatingPhoto>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB")
+    {
+        Some(p) => p,
+        None => return HttpResponse::NotFound().body("Photo not found"),
+    };
+    diesel::delete(rp::rating_photo.find(photo.id))
+        .execute(&conn)
+        .expect("Couldn't connect to DB");
+    remove_files(
+        &LocalStorage::from_env(),
+        &[&photo.storage_key, &photo.thumbnail_key],
+    );
+    HttpResponse::Ok().body("Photo removed")
+}
@@ -1,14 +1,59 @@
-use crate::models::{AddTransaction, CartItem, Customer, Product, Transaction};
+use crate::handlers::cart_items::{touch, validate_cart, CartOwner};
+use crate::handlers::email_verification::checkout_allowed;
+use crate::handlers::payment::{issue_refund, RefundError};
+use crate::handlers::returns::{
+    has_open_return, return_details, returned_quantity, ReturnDetails,
+};
+use crate::handlers::users::staff_member;
+use crate::models::{
+    AddTransaction, AddTransactionItem, CartItem, Customer, Product,
+    ReturnRequest, Transaction, TransactionItem,
+};
 use crate::schema::cart_items::dsl::*;
 use crate::schema::customer::dsl::*;
 use crate::schema::product::dsl as prod;
+use crate::schema::return_request::dsl as rr;
 use crate::schema::transaction::dsl::*;
-use crate::TPool;
+use crate::schema::transaction_item::dsl as ti;
+use crate::{invoice, reminders};
+use crate::{last_insert_id, TPool};
 
 use actix_identity::Identity;
 use actix_web::{web, HttpResponse, Responder};
 use diesel::prelude::*;
 use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+/// Why a checkout was not placed.
+#[derive(Debug)]
+pub enum CheckoutError {
+    Db(diesel::result::Error),
+    /// Someone else bought the stock between validating the cart and
+    /// placing the order.
+    OutOfStock {
+        pid: i32,
+        left: i32,
+    },
+}
+
+impl fmt::Display for CheckoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckoutError::Db(e) => write!(f, "{}", e),
+            CheckoutError::OutOfStock { pid, left } => {
+                write!(f, "Only {} of product {} left in stock", left, pid)
+            }
+        }
+    }
+}
+
+impl From<diesel::result::Error> for CheckoutError {
+    fn from(e: diesel::result::Error) -> Self {
+        CheckoutError::Db(e)
+    }
+}
 
 pub async fn checkout_cart(
     pool: web::Data<TPool>,
@@ -23,32 +68,93 @@ pub async fn checkout_cart(
             .limit(1)
             .first::<Customer>(&conn)
             .expect("Couldn't connect to DB");
+        if let Err(resp) = checkout_allowed(&selected_user) {
+            return resp;
+        }
+        let issues =
+            validate_cart(&conn, &CartOwner::Customer(selected_user.id))
+                .expect("Couldn't connect to DB");
+        if !issues.is_empty() {
+            info!("Cart changed since it was filled: {}", uname);
+            return HttpResponse::Conflict().json(&issues);
+        }
         let user_cart_items = cart_items
             .filter(cart_id.eq(selected_user.id))
             .load::<CartItem>(&conn)
             .expect("Couldn't connect to DB");
-        let cart_total = user_cart_items.into_iter().fold(0., |acc, item| {
-            let item_price = prod::product
-                .filter(prod::id.eq(item.product_id))
-                .limit(1)
-                .first::<Product>(&conn)
-                .unwrap()
-                .price;
-            acc + item.quantity.unwrap_or(1) as f32 * item_price
-        });
+        let mut order_lines = user_cart_items
+            .into_iter()
+            .map(|item| {
+                let item_price = prod::product
+                    .filter(prod::id.eq(item.product_id))
+                    .limit(1)
+                    .first::<Product>(&conn)
+                    .unwrap()
+                    .price;
+                (item.product_id, item.quantity.unwrap_or(1), item_price)
+            })
+            .collect::<Vec<_>>();
+        // products are locked in id order so checkouts can't deadlock
+        order_lines.sort_by_key(|(pid, _, _)| *pid);
+        let cart_total = order_lines
+            .iter()
+            .fold(0., |acc, (_, q, p)| acc + *q as f32 * p);
         let transaction_entry = AddTransaction {
             customer_id: Some(selected_user.id),
             amount: cart_total,
             payment_type: pmt_kind,
         };
-        diesel::insert_into(transaction)
-            .values(transaction_entry)
-            .execute(&conn)
-            .expect("Coundn't connect to DB");
-        diesel::delete(cart_items.filter(cart_id.eq(selected_user.id)))
-            .execute(&conn)
-            .expect("Coundn't connect to DB");
-        return HttpResponse::Ok().body("Transaction performed successfully");
+        let placed = conn.transaction::<_, CheckoutError, _>(|| {
+            diesel::insert_into(transaction)
+                .values(transaction_entry)
+                .execute(&conn)?;
+            let tid =
+                diesel::select(last_insert_id).first::<u64>(&conn)? as i32;
+            for (pid, q, p) in order_lines {
+                diesel::insert_into(ti::transaction_item)
+                    .values(AddTransactionItem {
+                        transaction_id: tid,
+                        product_id: pid,
+                        quantity: q,
+                        unit_price: p,
+                    })
+                    .execute(&conn)?;
+                // the cart was validated without locks, so check the stock
+                // again now that nobody else can take it
+                let in_stock = prod::product
+                    .find(pid)
+                    .select(prod::stock)
+                    .for_update()
+                    .first::<Option<i32>>(&conn)?;
+                if let Some(left) = in_stock {
+                    if left < q {
+                        return Err(CheckoutError::OutOfStock { pid, left });
+                    }
+                    diesel::update(prod::product.find(pid))
+                        .set(prod::stock.eq(left - q))
+                        .execute(&conn)?;
+                }
+            }
+            diesel::delete(cart_items.filter(cart_id.eq(selected_user.id)))
+                .execute(&conn)?;
+            touch(&conn, &CartOwner::Customer(selected_user.id))?;
+            reminders::mark_recovered(&conn, selected_user.id, tid)?;
+            Ok(())
+        });
+        return match placed {
+            Ok(_) => {
+                HttpResponse::Ok().body("Transaction performed successfully")
+            }
+            Err(e @ CheckoutError::OutOfStock { .. }) => {
+                info!("Checkout of {} ran out of stock: {}", uname, e);
+                HttpResponse::Conflict().body(e.to_string())
+            }
+            Err(e) => {
+                error!("Unable to check out {}: {}", uname, e);
+                HttpResponse::InternalServerError()
+                    .body("Unable to place order")
+            }
+        };
     } else {
         return HttpResponse::Unauthorized().body("Login first");
     }
@@ -75,3 +181,360 @@ pub async fn list_transactions(
             .body("Need to be logged in to add to cart!");
     }
 }
+
+#[derive(Deserialize, Debug)]
+pub struct CancelLine {
+    transaction_item_id: i32,
+    quantity: i32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CancelOrder {
+    transaction_id: i32,
+    items: Option<Vec<CancelLine>>,
+}
+
+/// Why an order, or some of its items, were not cancelled.
+pub enum CancelError {
+    Db(diesel::result::Error),
+    /// The cancellation was refused, the response says why.
+    Refused(HttpResponse),
+}
+
+impl From<diesel::result::Error> for CancelError {
+    fn from(e: diesel::result::Error) -> Self {
+        CancelError::Db(e)
+    }
+}
+
+impl From<RefundError> for CancelError {
+    fn from(e: RefundError) -> Self {
+        match e {
+            RefundError::Db(e) => CancelError::Db(e),
+            e @ RefundError::ExceedsPayment { .. } => CancelError::Refused(
+                HttpResponse::Conflict()
+                    .body(format!("Unable to refund the cancellation, {}", e)),
+            ),
+        }
+    }
+}
+
+fn refuse<T>(resp: HttpResponse) -> Result<T, CancelError> {
+    Err(CancelError::Refused(resp))
+}
+
+/// Cancels `cancel_details` of an order of customer `cid`. Meant to run in
+/// a transaction: the order row is locked first so concurrent cancellations
+/// and returns of the same order see each other's quantities.
+fn cancel_locked(
+    conn: &MysqlConnection,
+    cid: i32,
+    cancel_details: CancelOrder,
+) -> Result<(), CancelError> {
+    let order = transaction
+        .find(cancel_details.transaction_id)
+        .filter(customer_id.eq(cid))
+        .for_update()
+        .first::<Transaction>(conn)
+        .optional()?;
+    let order = match order {
+        Some(o) => o,
+        None => {
+            return refuse(HttpResponse::NotFound().body("Order not found"))
+        }
+    };
+    match order.fulfilment_status.as_str() {
+        "placed" => (),
+        "cancelled" => {
+            return refuse(
+                HttpResponse::Conflict().body("Order is already cancelled"),
+            )
+        }
+        _ => {
+            return refuse(
+                HttpResponse::Conflict()
+                    .body("Order has already shipped and cannot be cancelled"),
+            )
+        }
+    }
+    let order_lines = ti::transaction_item
+        .filter(ti::transaction_id.eq(order.id))
+        .load::<TransactionItem>(conn)?;
+    // units already returned can't be cancelled as well, and lines with
+    // a return in progress are left for the return to settle
+    let mut cancellable = vec![];
+    for line in &order_lines {
+        if has_open_return(conn, line.id)? {
+            cancellable.push((line, 0, true));
+            continue;
+        }
+        let returned = returned_quantity(conn, line.id)?;
+        cancellable.push((
+            line,
+            line.quantity - line.cancelled_quantity - returned,
+            false,
+        ));
+    }
+    let to_cancel = match cancel_details.items {
+        None => {
+            if cancellable.iter().any(|(_, _, open)| *open) {
+                return refuse(HttpResponse::Conflict().body(
+                    "Order has a return in progress, cancel items \
+                     that are not being returned instead",
+                ));
+            }
+            cancellable
+                .iter()
+                .map(|(l, q, _)| (*l, *q))
+                .filter(|(_, q)| *q > 0)
+                .collect::<Vec<_>>()
+        }
+        Some(lines) => {
+            let mut to_cancel: Vec<(&TransactionItem, i32)> = vec![];
+            for line in lines {
+                let order_line = cancellable
+                    .iter()
+                    .find(|(l, _, _)| l.id == line.transaction_item_id);
+                let (order_line, remaining) = match order_line {
+                    Some((l, _, true)) => {
+                        return refuse(HttpResponse::Conflict().body(format!(
+                            "Item {} has a return in progress",
+                            l.id
+                        )))
+                    }
+                    Some((l, q, false)) => (*l, *q),
+                    None => {
+                        return refuse(
+                            HttpResponse::BadRequest()
+                                .body("Item is not part of this order"),
+                        )
+                    }
+                };
+                if to_cancel.iter().any(|(l, _)| l.id == order_line.id) {
+                    return refuse(
+                        HttpResponse::BadRequest()
+                            .body("Each item can only be listed once"),
+                    );
+                }
+                if line.quantity < 1 || line.quantity > remaining {
+                    return refuse(HttpResponse::BadRequest().body(format!(
+                        "Only {} of item {} can be cancelled",
+                        remaining, order_line.id
+                    )));
+                }
+                to_cancel.push((order_line, line.quantity));
+            }
+            to_cancel
+        }
+    };
+    if to_cancel.is_empty() {
+        return refuse(HttpResponse::BadRequest().body("Nothing to cancel"));
+    }
+    let mut refund = 0.;
+    for (line, q) in &to_cancel {
+        diesel::update(ti::transaction_item.find(line.id))
+            .set(ti::cancelled_quantity.eq(ti::cancelled_quantity + q))
+            .execute(conn)?;
+        diesel::update(prod::product.find(line.product_id))
+            .set(prod::stock.eq(prod::stock + q))
+            .execute(conn)?;
+        refund += *q as f32 * line.unit_price;
+    }
+    let mut fully_cancelled = true;
+    for line in ti::transaction_item
+        .filter(ti::transaction_id.eq(order.id))
+        .load::<TransactionItem>(conn)?
+    {
+        if line.cancelled_quantity + returned_quantity(conn, line.id)?
+            < line.quantity
+        {
+            fully_cancelled = false;
+        }
+    }
+    if fully_cancelled {
+        diesel::update(transaction.find(order.id))
+            .set(fulfilment_status.eq("cancelled"))
+            .execute(conn)?;
+    }
+    let paid = order.payment_status == "paid"
+        || order.payment_status == "partially_refunded";
+    if paid && refund > 0. {
+        issue_refund(conn, order.id, refund)?;
+    }
+    Ok(())
+}
+
+pub async fn cancel_order(
+    pool: web::Data<TPool>,
+    cookie: Identity,
+    cancel_details: web::Json<CancelOrder>,
+) -> impl Responder {
+    info!("Cancel order hit: {:?}", cancel_details);
+    let conn = pool.get().unwrap();
+    if let Some(uname) = cookie.identity() {
+        let selected_user = customer
+            .filter(username.eq(&uname))
+            .limit(1)
+            .first::<Customer>(&conn)
+            .expect("Couldn't connect to DB");
+        let cancel_details = cancel_details.into_inner();
+        let order_id = cancel_details.transaction_id;
+        let cancelled = conn.transaction::<_, CancelError, _>(|| {
+            cancel_locked(&conn, selected_user.id, cancel_details)
+        });
+        match cancelled {
+            Ok(_) => HttpResponse::Ok().body("Cancelled successfully"),
+            Err(CancelError::Refused(resp)) => resp,
+            Err(CancelError::Db(e)) => {
+                error!("Unable to cancel order {}: {}", order_id, e);
+                HttpResponse::InternalServerError()
+                    .body("Unable to cancel order")
+            }
+        }
+    } else {
+        HttpResponse::Unauthorized().body("Login first")
+    }
+}
+
+pub async fn ship_order(
+    pool: web::Data<TPool>,
+    cookie: Identity,
+    order_id: web::Path<i32>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    if let Err(resp) = staff_member(&cookie, &conn) {
+        return resp;
+    }
+    let order_id = order_id.into_inner();
+    info!("Marking order {} as shipped", order_id);
+    let updated = diesel::update(
+        transaction
+            .find(order_id)
+            .filter(fulfilment_status.eq("placed")),
+    )
+    .set(fulfilment_status.eq("shipped"))
+    .execute(&conn)
+    .expect("Couldn't connect to DB");
+    if updated == 0 {
+        HttpResponse::Conflict().body("Only placed orders can be shipped")
+    } else {
+        HttpResponse::Ok().body("Order shipped")
+    }
+}
+
+#[derive(Serialize)]
+pub struct OrderDetails {
+    #[serde(flatten)]
+    order: Transaction,
+    items: Vec<TransactionItem>,
+    returns: Vec<ReturnDetails>,
+}
+
+/// An order with its items and returns.
+pub fn order_details_of(
+    conn: &MysqlConnection,
+    order: Transaction,
+) -> QueryResult<OrderDetails> {
+    let items = ti::transaction_item
+        .filter(ti::transaction_id.eq(order.id))
+        .load::<TransactionItem>(conn)?;
+    let returns = rr::return_request
+        .filter(rr::transaction_id.eq(order.id))
+        .load::<ReturnRequest>(conn)?
+        .into_iter()
+        .map(|r| return_details(conn, r))
+        .collect::<QueryResult<Vec<_>>>()?;
+    Ok(OrderDetails {
+        order,
+        items,
+        returns,
+    })
+}
+
+pub async fn order_details(
+    pool: web::Data<TPool>,
+    cookie: Identity,
+    order_id: web::Path<i32>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let order_id = order_id.into_inner();
+    if let Some(uname) = cookie.identity() {
+        let selected_user = customer
+            .filter(username.eq(&uname))
+            .limit(1)
+            .first::<Customer>(&conn)
+            .expect("Couldn't connect to DB");
+        let order = transaction
+            .find(order_id)
+            .filter(customer_id.eq(selected_user.id))
+            .first::<Transaction>(&conn);
+        let order = match order {
+            Ok(o) => o,
+            Err(_) => {
+                error!("Order not found: {}", order_id);
+                return HttpResponse::NotFound().finish();
+            }
+        };
+        HttpResponse::Ok().json(
+            order_details_of(&conn, order).expect("Couldn't connect to DB"),
+        )
+    } else {
+        HttpResponse::Unauthorized()
+            .body("Need to be logged in to view orders!")
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InvoiceFormat {
+    format: Option<String>,
+}
+
+pub async fn order_invoice(
+    pool: web::Data<TPool>,
+    cookie: Identity,
+    order_id: web::Path<i32>,
+    query: web::Query<InvoiceFormat>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let order_id = order_id.into_inner();
+    info!("Invoice requested for order {}", order_id);
+    if let Some(uname) = cookie.identity() {
+        let selected_user = customer
+            .filter(username.eq(&uname))
+            .limit(1)
+            .first::<Customer>(&conn)
+            .expect("Couldn't connect to DB");
+        let order = match transaction.find(order_id).first::<Transaction>(&conn)
+        {
+            Ok(o) => o,
+            Err(_) => return HttpResponse::NotFound().finish(),
+        };
+        if order.customer_id != Some(selected_user.id)
+            && !selected_user.is_staff()
+        {
+            return HttpResponse::NotFound().finish();
+        }
+        if !invoice::invoiceable(&order) {
+            return HttpResponse::Conflict()
+                .body("Cancelled orders have no invoice");
+        }
+        let invoice = invoice::invoice_for(&conn, &order)
+            .expect("Couldn't connect to DB");
+        match query.format.as_deref() {
+            Some("pdf") => HttpResponse::Ok()
+                .content_type("application/pdf")
+                .header(
+                    "Content-Disposition",
+                    format!("inline; filename=\"{}.pdf\"", invoice.number),
+                )
+                .body(invoice::render_pdf(&invoice)),
+            None | Some("html") => HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(invoice::render_html(&invoice)),
+            Some(f) => HttpResponse::BadRequest()
+                .body(format!("Unknown invoice format: {}", f)),
+        }
+    } else {
+        HttpResponse::Unauthorized().body("Login first")
+    }
+}
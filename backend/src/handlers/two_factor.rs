@@ -0,0 +1,399 @@
+use crate::audit::{self, client_ip};
+use crate::handlers::users::{admin_member, complete_login, too_many_attempts};
+use crate::login_throttle::{self, Subject};
+use crate::models::{
+    AddAuditEvent, AddCustomerTotp, AddRecoveryCode, Customer, CustomerTotp,
+    RecoveryCode,
+};
+use crate::schema::customer::dsl::*;
+use crate::schema::customer_totp::dsl as ct;
+use crate::schema::recovery_code::dsl as rc;
+use crate::session::{self, pending_login_stub};
+use crate::{settings, totp, TPool};
+
+use actix_identity::Identity;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::naive::NaiveDateTime;
+use diesel::dsl::now;
+use diesel::prelude::*;
+use log::{error, info};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const RECOVERY_CODES: usize = 10;
+
+pub const SECOND_FACTOR_FAILED: &str = "second_factor_failed";
+pub const RECOVERY_CODE_USED: &str = "recovery_code_used";
+
+/// Confirmed TOTP enrolment of a customer.
+fn enrolment(
+    conn: &MysqlConnection,
+    cid: i32,
+) -> QueryResult<Option<CustomerTotp>> {
+    ct::customer_totp
+        .find(cid)
+        .filter(ct::confirmed_at.is_not_null())
+        .first::<CustomerTotp>(conn)
+        .optional()
+}
+
+pub fn enabled(conn: &MysqlConnection, cid: i32) -> QueryResult<bool> {
+    Ok(enrolment(conn, cid)?.is_some())
+}
+
+/// Whether staff accounts must have two-factor authentication enabled.
+pub fn required_for_staff(conn: &MysqlConnection) -> QueryResult<bool> {
+    settings::flag(conn, settings::REQUIRE_STAFF_2FA)
+}
+
+/// Recovery codes are compared without dashes, spaces or case.
+fn recovery_hash(code: &str) -> String {
+    let normalized = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_lowercase();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+/// Replaces the recovery codes of a customer with new ones and returns
+/// them. They can't be shown again later.
+fn issue_recovery_codes(
+    conn: &MysqlConnection,
+    cid: i32,
+) -> QueryResult<Vec<String>> {
+    diesel::delete(rc::recovery_code.filter(rc::customer_id.eq(cid)))
+        .execute(conn)?;
+    let codes = (0..RECOVERY_CODES)
+        .map(|_| {
+            let c = hex::encode(rand::thread_rng().gen::<[u8; 8]>());
+            format!("{}-{}-{}-{}", &c[..4], &c[4..8], &c[8..12], &c[12..])
+        })
+        .collect::<Vec<_>>();
+    let rows = codes
+        .iter()
+        .map(|c| AddRecoveryCode {
+            customer_id: cid,
+            code_hash: recovery_hash(c),
+        })
+        .collect::<Vec<_>>();
+    diesel::insert_into(rc::recovery_code)
+        .values(&rows)
+        .execute(conn)?;
+    Ok(codes)
+}
+
+/// Checks a code from the authenticator app of `member`, or failing that
+/// one of their recovery codes, and uses it up.
+fn check_code(
+    conn: &MysqlConnection,
+    member: &Customer,
+    code: &str,
+) -> QueryResult<bool> {
+    conn.transaction(|| {
+        let enrolled = match ct::customer_totp
+            .find(member.id)
+            .for_update()
+            .first::<CustomerTotp>(conn)
+            .optional()?
+        {
+            Some(e) => e,
+            None => return Ok(false),
+        };
+        let last_used = enrolled.last_used_step.map(|s| s as u64);
+        if let Some(step) = totp::verify(
+            &enrolled.secret,
+            code,
+            totp::current_step(),
+            last_used,
+        ) {
+            diesel::update(ct::customer_totp.find(member.id))
+                .set(ct::last_used_step.eq(step as i64))
+                .execute(conn)?;
+            return Ok(true);
+        }
+        if enrolled.confirmed_at.is_none() {
+            return Ok(false);
+        }
+        let recovery = rc::recovery_code
+            .filter(rc::customer_id.eq(member.id))
+            .filter(rc::code_hash.eq(recovery_hash(code)))
+            .filter(rc::used_at.is_null())
+            .for_update()
+            .first::<RecoveryCode>(conn)
+            .optional()?;
+        match recovery {
+            Some(r) => {
+                let used_now =
+                    diesel::select(now).first::<NaiveDateTime>(conn)?;
+                diesel::update(rc::recovery_code.find(r.id))
+                    .set(rc::used_at.eq(used_now))
+                    .execute(conn)?;
+                audit::record(
+                    conn,
+                    AddAuditEvent {
+                        event: RECOVERY_CODE_USED.to_string(),
+                        customer_id: Some(member.id),
+                        username: Some(member.username.clone()),
+                        ..Default::default()
+                    },
+                );
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    })
+}
+
+fn logged_in_member(
+    cookie: &Identity,
+    conn: &MysqlConnection,
+) -> Result<Customer, HttpResponse> {
+    let uname = cookie.identity().ok_or_else(|| {
+        HttpResponse::Unauthorized()
+            .body("Need to be logged in to manage two-factor authentication!")
+    })?;
+    Ok(customer
+        .filter(username.eq(&uname))
+        .limit(1)
+        .first::<Customer>(conn)
+        .expect("Couldn't connect to DB"))
+}
+
+#[derive(Serialize)]
+pub struct Enrolment {
+    secret: String,
+    otpauth_uri: String,
+    qr_code_svg: String,
+}
+
+/// Starts setting up two-factor authentication. Answers with the secret
+/// to add to an authenticator app, as text and as a QR code, and takes
+/// effect once a first code is sent to `confirm_enrolment`.
+pub async fn enroll(
+    cookie: Identity,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let member = match logged_in_member(&cookie, &conn) {
+        Ok(m) => m,
+        Err(resp) => return resp,
+    };
+    if enabled(&conn, member.id).expect("Couldn't connect to DB") {
+        return HttpResponse::Conflict()
+            .body("Two-factor authentication is already enabled");
+    }
+    let secret = totp::new_secret();
+    diesel::replace_into(ct::customer_totp)
+        .values(AddCustomerTotp {
+            customer_id: member.id,
+            secret: secret.clone(),
+        })
+        .execute(&conn)
+        .expect("Couldn't connect to DB");
+    info!("{} started two-factor enrolment", member.username);
+    let otpauth_uri = totp::otpauth_uri(&secret, &member.username);
+    HttpResponse::Ok().json(Enrolment {
+        qr_code_svg: totp::qr_code_svg(&otpauth_uri),
+        otpauth_uri,
+        secret,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct SecondFactor {
+    code: String,
+}
+
+/// Turns two-factor authentication on with a first code from the app, and
+/// answers with the recovery codes.
+pub async fn confirm_enrolment(
+    cookie: Identity,
+    details: web::Json<SecondFactor>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let member = match logged_in_member(&cookie, &conn) {
+        Ok(m) => m,
+        Err(resp) => return resp,
+    };
+    if enabled(&conn, member.id).expect("Couldn't connect to DB") {
+        return HttpResponse::Conflict()
+            .body("Two-factor authentication is already enabled");
+    }
+    let codes = conn.transaction::<_, diesel::result::Error, _>(|| {
+        if !check_code(&conn, &member, &details.code)? {
+            return Ok(None);
+        }
+        let confirmed_at = diesel::select(now).first::<NaiveDateTime>(&conn)?;
+        diesel::update(ct::customer_totp.find(member.id))
+            .set(ct::confirmed_at.eq(confirmed_at))
+            .execute(&conn)?;
+        issue_recovery_codes(&conn, member.id).map(Some)
+    });
+    match codes.expect("Couldn't connect to DB") {
+        Some(codes) => {
+            info!("{} enabled two-factor authentication", member.username);
+            HttpResponse::Ok().json(&codes)
+        }
+        None => HttpResponse::BadRequest()
+            .body("Code is invalid, start the enrolment again if need be"),
+    }
+}
+
+/// New recovery codes in exchange for a current code, the old ones stop
+/// working.
+pub async fn regenerate_recovery_codes(
+    cookie: Identity,
+    details: web::Json<SecondFactor>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let member = match logged_in_member(&cookie, &conn) {
+        Ok(m) => m,
+        Err(resp) => return resp,
+    };
+    if !enabled(&conn, member.id).expect("Couldn't connect to DB") {
+        return HttpResponse::BadRequest()
+            .body("Two-factor authentication is not enabled");
+    }
+    let codes = conn.transaction::<_, diesel::result::Error, _>(|| {
+        if check_code(&conn, &member, &details.code)? {
+            issue_recovery_codes(&conn, member.id).map(Some)
+        } else {
+            Ok(None)
+        }
+    });
+    match codes.expect("Couldn't connect to DB") {
+        Some(codes) => HttpResponse::Ok().json(&codes),
+        None => HttpResponse::BadRequest().body("Code is invalid"),
+    }
+}
+
+/// Turns two-factor authentication off, unless the account is a staff
+/// account and admins require it.
+pub async fn disable(
+    cookie: Identity,
+    details: web::Json<SecondFactor>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let member = match logged_in_member(&cookie, &conn) {
+        Ok(m) => m,
+        Err(resp) => return resp,
+    };
+    if member.is_staff()
+        && required_for_staff(&conn).expect("Couldn't connect to DB")
+    {
+        return HttpResponse::Forbidden()
+            .body("Staff accounts must keep two-factor authentication");
+    }
+    let disabled = conn.transaction::<_, diesel::result::Error, _>(|| {
+        if !enabled(&conn, member.id)?
+            || !check_code(&conn, &member, &details.code)?
+        {
+            return Ok(false);
+        }
+        diesel::delete(ct::customer_totp.find(member.id)).execute(&conn)?;
+        diesel::delete(rc::recovery_code.filter(rc::customer_id.eq(member.id)))
+            .execute(&conn)?;
+        Ok(true)
+    });
+    if disabled.expect("Couldn't connect to DB") {
+        info!("{} disabled two-factor authentication", member.username);
+        HttpResponse::Ok().body("Two-factor authentication disabled")
+    } else {
+        HttpResponse::BadRequest().body("Code is invalid")
+    }
+}
+
+/// Second step of a login, with a code from the app or a recovery code.
+/// Wrong codes count as failed logins.
+pub async fn verify_login(
+    req: HttpRequest,
+    cookie: Identity,
+    details: web::Json<SecondFactor>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let uname = match session::pending_login(&req).unwrap() {
+        Some(u) => u,
+        None => {
+            return HttpResponse::Unauthorized()
+                .body("Login expired, enter your password again")
+        }
+    };
+    let ip = client_ip(&req);
+    let mut subjects = vec![Subject::User(&uname)];
+    if let Some(ip) = &ip {
+        subjects.push(Subject::Ip(ip));
+    }
+    if let Some(wait) = login_throttle::retry_after(&subjects).unwrap() {
+        return too_many_attempts(wait);
+    }
+    let member = customer
+        .filter(username.eq(&uname))
+        .first::<Customer>(&conn)
+        .expect("Couldn't connect to DB");
+    if !check_code(&conn, &member, &details.code)
+        .expect("Couldn't connect to DB")
+    {
+        login_throttle::record_failure(&subjects).unwrap();
+        audit::record(
+            &conn,
+            AddAuditEvent {
+                event: SECOND_FACTOR_FAILED.to_string(),
+                customer_id: Some(member.id),
+                username: Some(uname.clone()),
+                ip,
+                ..Default::default()
+            },
+        );
+        return HttpResponse::Unauthorized().body("Invalid code");
+    }
+    if let Err(e) = session::end_pending_login(&req) {
+        error!("Unable to end pending login of {}: {}", uname, e);
+    }
+    let mut resp = complete_login(&req, &conn, &cookie, &member);
+    if let Err(e) = resp.add_cookie(&pending_login_stub()) {
+        error!("Unable to drop pending login cookie: {}", e);
+    }
+    resp
+}
+
+#[derive(Deserialize)]
+pub struct StaffRequirement {
+    required: bool,
+}
+
+/// Lets admins require two-factor authentication for staff accounts.
+/// Staff without it keep their login but lose staff powers until they
+/// enable it.
+pub async fn require_for_staff(
+    cookie: Identity,
+    details: web::Json<StaffRequirement>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let admin = match admin_member(&cookie, &conn) {
+        Ok(a) => a,
+        Err(resp) => return resp,
+    };
+    settings::set(
+        &conn,
+        settings::REQUIRE_STAFF_2FA,
+        &details.required.to_string(),
+        admin.id,
+    )
+    .expect("Couldn't connect to DB");
+    info!(
+        "{} set two-factor requirement for staff to {}",
+        admin.username, details.required
+    );
+    HttpResponse::Ok().body(if details.required {
+        "Staff now need two-factor authentication"
+    } else {
+        "Staff no longer need two-factor authentication"
+    })
+}
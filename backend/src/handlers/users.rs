@@ -1,32 +1,91 @@
-use crate::models::{Customer, NewCustomer, Rating, Transaction};
+use crate::audit::{self, client_ip};
+use crate::handlers::cart_items::{guest_cookie_stub, merge_guest_cart};
+use crate::handlers::email_verification::send_verification;
+use crate::handlers::moderation::APPROVED;
+use crate::handlers::two_factor;
+use crate::login_throttle::{self, Penalty, Subject};
+use crate::models::{
+    AddAuditEvent, Customer, NewCustomer, Rating, Transaction,
+};
 use crate::schema::customer::dsl::*;
 use crate::schema::rating::dsl as rs;
 use crate::schema::transaction::dsl as ts;
+use crate::session;
+use crate::validation::{self, FieldErrors};
 use crate::TPool;
 
 use actix_identity::Identity;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use bcrypt::{hash, verify, DEFAULT_COST};
+use chrono::naive::NaiveDate;
 use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DBError};
 use log::{error, info};
-use redis::Commands;
 use serde::{Deserialize, Serialize};
 
+fn validate_new_customer(item: &NewCustomer) -> Result<(), HttpResponse> {
+    let mut errors = FieldErrors::default();
+    errors.check("username", validation::username(&item.username));
+    errors.check("password", validation::password(&item.password));
+    errors.check("email_id", validation::email(&item.email_id));
+    errors.check("phone_number", validation::phone_number(&item.phone_number));
+    if let Some(a) = &item.address {
+        errors.check("address", validation::address(a));
+    }
+    errors.into_result()
+}
+
+fn username_taken() -> HttpResponse {
+    HttpResponse::Conflict().body("Username is already taken")
+}
+
 pub async fn new_user(
+    req: HttpRequest,
     pool: web::Data<TPool>,
     item: web::Json<NewCustomer>,
 ) -> impl Responder {
     info!("Creating ... {:?}", item.username);
     let conn = pool.get().unwrap();
+    if let Err(resp) = validate_new_customer(&item) {
+        return resp;
+    }
+    let uname = item.username.clone();
+    let existing = customer
+        .filter(username.eq(&uname))
+        .count()
+        .get_result::<i64>(&conn)
+        .expect("Couldn't connect to DB");
+    if existing > 0 {
+        return username_taken();
+    }
     let hashed_item = NewCustomer {
         password: hash(&item.password, DEFAULT_COST).unwrap(),
         ..(item.into_inner())
     };
-    diesel::insert_into(customer)
+    match diesel::insert_into(customer)
         .values(hashed_item)
         .execute(&conn)
-        .expect("Coundn't connect to DB");
-    HttpResponse::Ok().body("Inserted successfully!")
+    {
+        Ok(_) => (),
+        Err(DBError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            return username_taken()
+        }
+        Err(e) => panic!("Couldn't connect to DB: {}", e),
+    }
+    let new_customer = customer
+        .filter(username.eq(&uname))
+        .first::<Customer>(&conn)
+        .expect("Couldn't connect to DB");
+    if let Err(e) = send_verification(&conn, &new_customer) {
+        error!("Unable to send verification to {}: {}", uname, e);
+    }
+    let mut resp = HttpResponse::Ok();
+    if merge_guest_cart(&conn, &req, new_customer.id)
+        .expect("Couldn't connect to DB")
+    {
+        resp.del_cookie(&guest_cookie_stub());
+    }
+    resp.body("Inserted successfully!")
 }
 
 pub async fn name_exists(
@@ -49,6 +108,117 @@ pub async fn name_exists(
     }
 }
 
+/// Resolves the logged in user, rejecting anyone who is not staff.
+pub fn staff_member(
+    cookie: &Identity,
+    conn: &MysqlConnection,
+) -> Result<Customer, HttpResponse> {
+    let uname = match cookie.identity() {
+        Some(u) => u,
+        None => return Err(HttpResponse::Unauthorized().body("Login first")),
+    };
+    let selected_user = customer
+        .filter(username.eq(&uname))
+        .limit(1)
+        .first::<Customer>(conn)
+        .expect("Couldn't connect to DB");
+    if !selected_user.is_staff() {
+        error!("Non-staff user {} attempted a staff action", uname);
+        return Err(HttpResponse::Forbidden().body("Staff only"));
+    }
+    if two_factor::required_for_staff(conn).expect("Couldn't connect to DB")
+        && !two_factor::enabled(conn, selected_user.id)
+            .expect("Couldn't connect to DB")
+    {
+        info!("Staff user {} has no two-factor authentication", uname);
+        return Err(HttpResponse::Forbidden().body(
+            "Staff accounts need two-factor authentication, enable it first",
+        ));
+    }
+    Ok(selected_user)
+}
+
+/// Like `staff_member`, for actions only admins may take.
+pub fn admin_member(
+    cookie: &Identity,
+    conn: &MysqlConnection,
+) -> Result<Customer, HttpResponse> {
+    let selected_user = staff_member(cookie, conn)?;
+    if selected_user.is_admin() {
+        Ok(selected_user)
+    } else {
+        error!(
+            "Non-admin user {} attempted an admin action",
+            selected_user.username
+        );
+        Err(HttpResponse::Forbidden().body("Admins only"))
+    }
+}
+
+pub fn too_many_attempts(wait: usize) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .header("Retry-After", wait.to_string())
+        .body(format!("Too many failed logins, try again in {}s", wait))
+}
+
+/// Checks `given` against the password of the logged in `member` before a
+/// sensitive change. Wrong guesses count and are audited as failed logins,
+/// so a session can't be used to guess the password either; the `Err` is
+/// the 429 once too many were wrong.
+pub fn password_confirmed(
+    req: &HttpRequest,
+    conn: &MysqlConnection,
+    member: &Customer,
+    given: &str,
+) -> Result<bool, HttpResponse> {
+    let subjects = [Subject::User(&member.username)];
+    let unavailable = |e: redis::RedisError| {
+        error!("Unable to check login failures: {}", e);
+        HttpResponse::ServiceUnavailable().body("Try again later")
+    };
+    if let Some(wait) =
+        login_throttle::retry_after(&subjects).map_err(unavailable)?
+    {
+        return Err(too_many_attempts(wait));
+    }
+    match verify(given, &member.password) {
+        Ok(true) => return Ok(true),
+        Ok(false) => (),
+        Err(e) => {
+            error!("Unable to check password of {}: {}", member.username, e);
+            return Err(HttpResponse::InternalServerError()
+                .body("Unable to check password"));
+        }
+    }
+    let penalty =
+        login_throttle::record_failure(&subjects).map_err(unavailable)?;
+    let event = AddAuditEvent {
+        customer_id: Some(member.id),
+        username: Some(member.username.clone()),
+        ip: client_ip(req),
+        detail: Some(format!("confirming {}", req.path())),
+        ..Default::default()
+    };
+    audit::record(
+        conn,
+        AddAuditEvent {
+            event: audit::LOGIN_FAILED.to_string(),
+            ..event.clone()
+        },
+    );
+    if let Penalty::Locked(wait) = penalty {
+        audit::record(
+            conn,
+            AddAuditEvent {
+                event: audit::LOGIN_LOCKED.to_string(),
+                detail: Some(format!("locked for {}s", wait)),
+                ..event
+            },
+        );
+    }
+    Ok(false)
+}
+
 #[derive(Deserialize)]
 pub struct Login {
     username: String,
@@ -56,6 +226,7 @@ pub struct Login {
 }
 
 pub async fn login(
+    req: HttpRequest,
     pool: web::Data<TPool>,
     cookie: Identity,
     login_details: web::Json<Login>,
@@ -66,41 +237,117 @@ pub async fn login(
         return HttpResponse::Ok().finish();
     }
     let conn = pool.get().unwrap();
-    let entered_pass = &login_details.password;
+    let ip = client_ip(&req);
+    let uname = login_details.username.as_str();
+    let mut subjects = vec![Subject::User(uname)];
+    if let Some(ip) = &ip {
+        subjects.push(Subject::Ip(ip));
+    }
+    if let Some(wait) = login_throttle::retry_after(&subjects).unwrap() {
+        info!("Login for {} throttled for {}s", uname, wait);
+        return too_many_attempts(wait);
+    }
     let selected_user = customer
-        .filter(username.eq(&login_details.username))
+        .filter(username.eq(uname))
         .limit(1)
         .first::<Customer>(&conn)
+        .optional()
         .expect("Couldn't connect to DB");
-    let hashed_pass = selected_user.password;
-    if verify(entered_pass, &hashed_pass).unwrap() {
-        cookie.remember(login_details.username.clone());
-        let redis_client = redis::Client::open("redis://127.0.0.1/").unwrap();
-        let mut redis_conn = redis_client.get_connection().unwrap();
-        redis_conn
-            .set::<String, String, String>(
-                login_details.username.clone(),
-                cookie.identity().unwrap(),
-            )
-            .unwrap();
-        info!(
-            "Successful login: {} {}",
-            selected_user.username, selected_user.email_id
+    let entered_pass = &login_details.password;
+    let authenticated = match &selected_user {
+        Some(u) => verify(entered_pass, &u.password).unwrap_or(false),
+        None => {
+            // takes as long as checking a password, so response times
+            // don't tell which usernames exist
+            let _ = hash(entered_pass, DEFAULT_COST);
+            false
+        }
+    };
+    let selected_user = match selected_user {
+        Some(u) if authenticated => u,
+        _ => {
+            let penalty = login_throttle::record_failure(&subjects).unwrap();
+            let event = AddAuditEvent {
+                customer_id: selected_user.map(|u| u.id),
+                username: Some(uname.to_string()),
+                ip,
+                ..Default::default()
+            };
+            audit::record(
+                &conn,
+                AddAuditEvent {
+                    event: audit::LOGIN_FAILED.to_string(),
+                    ..event.clone()
+                },
+            );
+            if let Penalty::Locked(wait) = penalty {
+                audit::record(
+                    &conn,
+                    AddAuditEvent {
+                        event: audit::LOGIN_LOCKED.to_string(),
+                        detail: Some(format!("locked for {}s", wait)),
+                        ..event
+                    },
+                );
+            }
+            return HttpResponse::Unauthorized().body("Invalid credentials");
+        }
+    };
+    if two_factor::enabled(&conn, selected_user.id)
+        .expect("Couldn't connect to DB")
+    {
+        info!("Waiting for second factor of {}", selected_user.username);
+        let pending = session::start_pending_login(&selected_user.username)
+            .expect("Couldn't connect to Redis");
+        return HttpResponse::Accepted().cookie(pending).json(
+            SecondFactorRequired {
+                second_factor_required: true,
+            },
+        );
+    }
+    complete_login(&req, &conn, &cookie, &selected_user)
+}
+
+#[derive(Serialize)]
+struct SecondFactorRequired {
+    second_factor_required: bool,
+}
+
+/// Logs `member` in once they have proven who they are.
+pub fn complete_login(
+    req: &HttpRequest,
+    conn: &MysqlConnection,
+    cookie: &Identity,
+    member: &Customer,
+) -> HttpResponse {
+    if let Err(e) = login_throttle::record_success(&member.username) {
+        error!(
+            "Unable to reset login failures of {}: {}",
+            member.username, e
         );
-        HttpResponse::Ok().finish()
-    } else {
-        HttpResponse::Unauthorized().finish()
     }
+    cookie.remember(member.username.clone());
+    info!("Successful login: {} {}", member.username, member.email_id);
+    let mut resp = HttpResponse::Ok();
+    if merge_guest_cart(conn, req, member.id).expect("Couldn't connect to DB") {
+        resp.del_cookie(&guest_cookie_stub());
+    }
+    resp.finish()
 }
 
 pub async fn logout(cookie: Identity) -> impl Responder {
-    let redis_client = redis::Client::open("redis://127.0.0.1/").unwrap();
-    let mut redis_conn = redis_client.get_connection().unwrap();
-    redis_conn.del::<String, String>(cookie.identity().unwrap());
     cookie.forget();
     HttpResponse::Ok().body("Successful logout.")
 }
 
+/// What anyone may see of an account.
+#[derive(Serialize)]
+pub struct PublicProfile {
+    username: String,
+    reviews: i64,
+    joined: NaiveDate,
+}
+
 pub async fn user_details(
     uname: web::Path<String>,
     pool: web::Data<TPool>,
@@ -110,65 +357,223 @@ pub async fn user_details(
     info!("Fetching info for: \"{}\"", uname);
     let selected_user = customer
         .filter(username.eq(&uname))
+        .filter(deleted_at.is_null())
         .limit(1)
-        .first::<Customer>(&conn);
+        .first::<Customer>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB");
     match selected_user {
-        Ok(m) => {
+        Some(m) => {
             info!("Found user: {}", uname);
-            HttpResponse::Ok().json(m)
+            let reviews = rs::rating
+                .filter(rs::customer_id.eq(m.id))
+                .filter(rs::status.eq(APPROVED))
+                .count()
+                .get_result::<i64>(&conn)
+                .expect("Couldn't connect to DB");
+            HttpResponse::Ok().json(PublicProfile {
+                username: m.username,
+                reviews,
+                joined: m.created_at.date(),
+            })
         }
-        Err(_) => {
+        None => {
             error!("User not found: {}", uname);
             HttpResponse::NotFound().finish()
         }
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize)]
 pub struct ChangePassword {
     old_password: String,
     new_password: String,
 }
 
+/// Changes the password of the logged in customer. The old password is
+/// checked like a login, and every other session of the account is ended.
 pub async fn change_password(
+    req: HttpRequest,
     cookie: Identity,
     password_details: web::Json<ChangePassword>,
     pool: web::Data<TPool>,
 ) -> impl Responder {
-    info!("Change password request: {:?}", password_details);
+    info!("Change password request: {:?}", cookie.identity());
     let conn = pool.get().unwrap();
     if let Some(uname) = cookie.identity() {
-        let entered_pass = &password_details.old_password;
         let new_password = &password_details.new_password;
         let selected_user = customer
             .filter(username.eq(&uname))
             .limit(1)
             .first::<Customer>(&conn)
             .expect("Couldn't connect to DB");
-        let hashed_pass = selected_user.password;
-        if verify(entered_pass, &hashed_pass).unwrap() {
-            let hashed_new_password =
-                hash(&new_password, DEFAULT_COST).unwrap();
-            diesel::update(customer.filter(id.eq(selected_user.id)))
-                .set(password.eq(hashed_new_password))
-                .execute(&conn)
-                .unwrap();
-            return HttpResponse::Ok().body("Changed password successfully");
-        } else {
-            return HttpResponse::Ok().body("Invalid password");
+        match password_confirmed(
+            &req,
+            &conn,
+            &selected_user,
+            &password_details.old_password,
+        ) {
+            Ok(true) => (),
+            Ok(false) => {
+                return HttpResponse::Forbidden().body("Invalid password")
+            }
+            Err(resp) => return resp,
+        }
+        let mut errors = FieldErrors::default();
+        errors.check("new_password", validation::password(new_password));
+        if let Err(resp) = errors.into_result() {
+            return resp;
+        }
+        let hashed_new_password = hash(&new_password, DEFAULT_COST).unwrap();
+        diesel::update(customer.filter(id.eq(selected_user.id)))
+            .set(password.eq(hashed_new_password))
+            .execute(&conn)
+            .expect("Couldn't connect to DB");
+        audit::record(
+            &conn,
+            AddAuditEvent {
+                event: audit::PASSWORD_CHANGED.to_string(),
+                customer_id: Some(selected_user.id),
+                username: Some(uname.clone()),
+                ip: client_ip(&req),
+                ..Default::default()
+            },
+        );
+        if let Err(e) = session::end_other_sessions(&req, &uname) {
+            error!("Unable to end other sessions of {}: {}", uname, e);
         }
+        return HttpResponse::Ok().body("Changed password successfully");
     }
     return HttpResponse::Unauthorized().body("Login first");
 }
 
+#[derive(Deserialize)]
+pub struct UpdateProfile {
+    email_id: Option<String>,
+    phone_number: Option<String>,
+    address: Option<String>,
+    current_password: Option<String>,
+}
+
+/// Changes the contact details of the logged in customer, fields left out
+/// stay as they are and an empty address removes it. Changing the email
+/// address or phone number needs the current password, and a new email
+/// address has to be verified again.
+pub async fn update_profile(
+    req: HttpRequest,
+    cookie: Identity,
+    details: web::Json<UpdateProfile>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let uname = match cookie.identity() {
+        Some(u) => u,
+        None => {
+            return HttpResponse::Unauthorized()
+                .body("Need to be logged in to edit your profile!")
+        }
+    };
+    let selected_user = customer
+        .filter(username.eq(&uname))
+        .limit(1)
+        .first::<Customer>(&conn)
+        .expect("Couldn't connect to DB");
+    let details = details.into_inner();
+    let new_email = details
+        .email_id
+        .map(|e| e.trim().to_string())
+        .filter(|e| *e != selected_user.email_id);
+    let new_phone = details
+        .phone_number
+        .map(|p| p.trim().to_string())
+        .filter(|p| *p != selected_user.phone_number);
+    let new_address = details.address.map(|a| a.trim().to_string());
+
+    let mut errors = FieldErrors::default();
+    if let Some(e) = &new_email {
+        errors.check("email_id", validation::email(e));
+    }
+    if let Some(p) = &new_phone {
+        errors.check("phone_number", validation::phone_number(p));
+    }
+    if let Some(a) = &new_address {
+        errors.check("address", validation::address(a));
+    }
+    if let Err(resp) = errors.into_result() {
+        return resp;
+    }
+    if new_email.is_some() || new_phone.is_some() {
+        let confirmed = match details.current_password {
+            Some(p) => {
+                match password_confirmed(&req, &conn, &selected_user, &p) {
+                    Ok(c) => c,
+                    Err(resp) => return resp,
+                }
+            }
+            None => false,
+        };
+        if !confirmed {
+            return HttpResponse::Forbidden().body(
+                "Enter your current password to change your email or phone",
+            );
+        }
+    }
+
+    let target = customer.filter(id.eq(selected_user.id));
+    let updated = conn.transaction::<_, DBError, _>(|| {
+        if let Some(e) = &new_email {
+            diesel::update(target)
+                .set((email_id.eq(e), email_verified.eq(false)))
+                .execute(&conn)?;
+        }
+        if let Some(p) = &new_phone {
+            diesel::update(target)
+                .set(phone_number.eq(p))
+                .execute(&conn)?;
+        }
+        if let Some(a) = new_address {
+            let a = Some(a).filter(|a| !a.is_empty());
+            diesel::update(target).set(address.eq(a)).execute(&conn)?;
+        }
+        target.first::<Customer>(&conn)
+    });
+    let updated = updated.expect("Couldn't connect to DB");
+    if let Some(e) = &new_email {
+        info!("{} changed their email address", uname);
+        audit::record(
+            &conn,
+            AddAuditEvent {
+                event: audit::EMAIL_CHANGED.to_string(),
+                customer_id: Some(selected_user.id),
+                username: Some(uname.clone()),
+                ip: client_ip(&req),
+                detail: Some(format!("{} -> {}", selected_user.email_id, e)),
+            },
+        );
+        if let Err(e) = send_verification(&conn, &updated) {
+            error!("Unable to send verification to {}: {}", uname, e);
+        }
+    }
+    HttpResponse::Ok().body(if new_email.is_some() {
+        "Profile updated, please verify your new email address"
+    } else {
+        "Profile updated"
+    })
+}
+
+/// What the logged in customer sees of their own account.
 #[derive(Serialize)]
 struct UserProfile {
     pub username: String,
     pub email_id: String,
+    pub email_verified: bool,
     pub address: Option<String>,
     pub transactions: Vec<Transaction>,
     pub ratings_given: i32,
     pub phone_number: String,
+    pub role: String,
+    pub two_factor_enabled: bool,
+    pub joined: NaiveDate,
 }
 
 pub async fn user_profile(
@@ -193,13 +598,19 @@ pub async fn user_profile(
             .load::<Rating>(&conn)
             .expect("Couldn't connect to DB")
             .len() as i32;
+        let two_factor_enabled = two_factor::enabled(&conn, selected_user.id)
+            .expect("Couldn't connect to DB");
         let profile = UserProfile {
             username: selected_user.username,
             email_id: selected_user.email_id,
+            email_verified: selected_user.email_verified,
             address: selected_user.address,
             transactions: user_transactions,
             ratings_given: user_ratings,
             phone_number: selected_user.phone_number,
+            role: selected_user.role,
+            two_factor_enabled,
+            joined: selected_user.created_at.date(),
         };
         return HttpResponse::Ok().json(&profile);
     } else {
@@ -0,0 +1,556 @@
+use crate::handlers::cart_items::{
+    current_quantity, set_quantity, CartError, CartOwner,
+};
+use crate::models::{
+    AddWishlist, AddWishlistItem, Customer, Product, Wishlist, WishlistItem,
+};
+use crate::schema::customer::dsl::*;
+use crate::schema::product::dsl as prod;
+use crate::schema::wishlist::dsl as wl;
+use crate::schema::wishlist_item::dsl as wi;
+use crate::{last_insert_id, TPool};
+
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse, Responder};
+use diesel::prelude::*;
+use log::info;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_WISHLIST: &str = "Saved for later";
+
+fn logged_in(cookie: &Identity, conn: &MysqlConnection) -> Option<Customer> {
+    cookie.identity().map(|uname| {
+        customer
+            .filter(username.eq(&uname))
+            .limit(1)
+            .first::<Customer>(conn)
+            .expect("Couldn't connect to DB")
+    })
+}
+
+/// Returns the default wishlist of customer `cid`, creating it on first use.
+fn default_wishlist(conn: &MysqlConnection, cid: i32) -> QueryResult<Wishlist> {
+    conn.transaction(|| {
+        // the customer row is locked so concurrent first uses can't both
+        // create a default list
+        customer
+            .find(cid)
+            .select(id)
+            .for_update()
+            .first::<i32>(conn)?;
+        let existing = wl::wishlist
+            .filter(wl::customer_id.eq(cid))
+            .filter(wl::is_default.eq(true))
+            .first::<Wishlist>(conn)
+            .optional()?;
+        match existing {
+            Some(w) => Ok(w),
+            None => {
+                diesel::insert_into(wl::wishlist)
+                    .values(AddWishlist {
+                        customer_id: cid,
+                        name: DEFAULT_WISHLIST.to_string(),
+                        is_default: true,
+                    })
+                    .execute(conn)?;
+                let wid = diesel::select(last_insert_id).first::<u64>(conn)?;
+                wl::wishlist.find(wid as i32).first(conn)
+            }
+        }
+    })
+}
+
+/// The wishlist `wid` if it belongs to customer `cid`.
+fn own_wishlist(
+    conn: &MysqlConnection,
+    cid: i32,
+    wid: i32,
+) -> QueryResult<Option<Wishlist>> {
+    wl::wishlist
+        .filter(wl::id.eq(wid))
+        .filter(wl::customer_id.eq(cid))
+        .first::<Wishlist>(conn)
+        .optional()
+}
+
+fn wishlist_quantity(
+    conn: &MysqlConnection,
+    wid: i32,
+    pid: i32,
+) -> QueryResult<i32> {
+    Ok(wi::wishlist_item
+        .filter(wi::wishlist_id.eq(wid))
+        .filter(wi::product_id.eq(pid))
+        .select(wi::quantity)
+        .first::<i32>(conn)
+        .optional()?
+        .unwrap_or(0))
+}
+
+/// Adds `amount` of a product to a wishlist, merging with an existing line.
+fn add_to_wishlist(
+    conn: &MysqlConnection,
+    wid: i32,
+    pid: i32,
+    amount: i32,
+) -> Result<(), CartError> {
+    let existing = wishlist_quantity(conn, wid, pid)?;
+    let total = existing.checked_add(amount).ok_or_else(|| {
+        CartError::InvalidQuantity(String::from("Quantity is too large"))
+    })?;
+    if existing > 0 {
+        diesel::update(
+            wi::wishlist_item
+                .filter(wi::wishlist_id.eq(wid))
+                .filter(wi::product_id.eq(pid)),
+        )
+        .set(wi::quantity.eq(total))
+        .execute(conn)?;
+    } else {
+        diesel::insert_into(wi::wishlist_item)
+            .values(AddWishlistItem {
+                wishlist_id: wid,
+                product_id: pid,
+                quantity: amount,
+            })
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+fn remove_from_wishlist(
+    conn: &MysqlConnection,
+    wid: i32,
+    pid: i32,
+) -> QueryResult<usize> {
+    diesel::delete(
+        wi::wishlist_item
+            .filter(wi::wishlist_id.eq(wid))
+            .filter(wi::product_id.eq(pid)),
+    )
+    .execute(conn)
+}
+
+#[derive(Serialize)]
+pub struct WishlistEntry {
+    product_item: Product,
+    quantity: i32,
+}
+
+#[derive(Serialize)]
+pub struct WishlistView {
+    #[serde(flatten)]
+    wishlist: Wishlist,
+    items: Vec<WishlistEntry>,
+}
+
+/// Read-only view of a shared wishlist, without owner details.
+#[derive(Serialize)]
+pub struct SharedWishlist {
+    name: String,
+    items: Vec<WishlistEntry>,
+}
+
+fn wishlist_entries(
+    conn: &MysqlConnection,
+    wid: i32,
+) -> QueryResult<Vec<WishlistEntry>> {
+    Ok(wi::wishlist_item
+        .inner_join(prod::product)
+        .filter(wi::wishlist_id.eq(wid))
+        .order(wi::added_at.desc())
+        .load::<(WishlistItem, Product)>(conn)?
+        .into_iter()
+        .map(|(item, p)| WishlistEntry {
+            product_item: p,
+            quantity: item.quantity,
+        })
+        .collect())
+}
+
+pub fn wishlist_view(
+    conn: &MysqlConnection,
+    wishlist: Wishlist,
+) -> QueryResult<WishlistView> {
+    let items = wishlist_entries(conn, wishlist.id)?;
+    Ok(WishlistView { wishlist, items })
+}
+
+pub async fn list_wishlists(
+    cookie: Identity,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    if let Some(selected_user) = logged_in(&cookie, &conn) {
+        default_wishlist(&conn, selected_user.id)
+            .expect("Couldn't connect to DB");
+        let lists = wl::wishlist
+            .filter(wl::customer_id.eq(selected_user.id))
+            .order((wl::is_default.desc(), wl::created_at.asc()))
+            .load::<Wishlist>(&conn)
+            .expect("Couldn't connect to DB")
+            .into_iter()
+            .map(|w| wishlist_view(&conn, w))
+            .collect::<QueryResult<Vec<_>>>()
+            .expect("Couldn't connect to DB");
+        HttpResponse::Ok().json(&lists)
+    } else {
+        HttpResponse::Unauthorized()
+            .body("Need to be logged in to view wishlists!")
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WishlistName {
+    name: String,
+}
+
+fn check_name(name: &str) -> Result<String, HttpResponse> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > 255 {
+        Err(HttpResponse::BadRequest()
+            .body("Wishlist name must be between 1 and 255 characters"))
+    } else {
+        Ok(name.to_string())
+    }
+}
+
+pub async fn new_wishlist(
+    cookie: Identity,
+    details: web::Json<WishlistName>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let selected_user = match logged_in(&cookie, &conn) {
+        Some(u) => u,
+        None => {
+            return HttpResponse::Unauthorized()
+                .body("Need to be logged in to create wishlists!")
+        }
+    };
+    let name = match check_name(&details.name) {
+        Ok(n) => n,
+        Err(resp) => return resp,
+    };
+    info!("{} creating wishlist {:?}", selected_user.username, name);
+    let created = conn
+        .transaction::<_, diesel::result::Error, _>(|| {
+            diesel::insert_into(wl::wishlist)
+                .values(AddWishlist {
+                    customer_id: selected_user.id,
+                    name,
+                    is_default: false,
+                })
+                .execute(&conn)?;
+            let wid = diesel::select(last_insert_id).first::<u64>(&conn)?;
+            wl::wishlist.find(wid as i32).first::<Wishlist>(&conn)
+        })
+        .expect("Couldn't connect to DB");
+    HttpResponse::Ok().json(&created)
+}
+
+/// Resolves the logged in customer and one of their wishlists, or the
+/// response to send when either is missing.
+fn owned_list(
+    cookie: &Identity,
+    conn: &MysqlConnection,
+    wid: i32,
+) -> Result<(Customer, Wishlist), HttpResponse> {
+    let selected_user = logged_in(cookie, conn).ok_or_else(|| {
+        HttpResponse::Unauthorized()
+            .body("Need to be logged in to manage wishlists!")
+    })?;
+    match own_wishlist(conn, selected_user.id, wid)
+        .expect("Couldn't connect to DB")
+    {
+        Some(w) => Ok((selected_user, w)),
+        None => Err(HttpResponse::NotFound().body("Wishlist not found")),
+    }
+}
+
+pub async fn wishlist_details(
+    cookie: Identity,
+    wid: web::Path<i32>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    match owned_list(&cookie, &conn, wid.into_inner()) {
+        Ok((_, w)) => HttpResponse::Ok()
+            .json(wishlist_view(&conn, w).expect("Couldn't connect to DB")),
+        Err(resp) => resp,
+    }
+}
+
+pub async fn rename_wishlist(
+    cookie: Identity,
+    wid: web::Path<i32>,
+    details: web::Json<WishlistName>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let (_, w) = match owned_list(&cookie, &conn, wid.into_inner()) {
+        Ok(o) => o,
+        Err(resp) => return resp,
+    };
+    let name = match check_name(&details.name) {
+        Ok(n) => n,
+        Err(resp) => return resp,
+    };
+    diesel::update(wl::wishlist.find(w.id))
+        .set(wl::name.eq(name))
+        .execute(&conn)
+        .expect("Couldn't connect to DB");
+    HttpResponse::Ok().body("Wishlist renamed")
+}
+
+pub async fn delete_wishlist(
+    cookie: Identity,
+    wid: web::Path<i32>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let (selected_user, w) = match owned_list(&cookie, &conn, wid.into_inner())
+    {
+        Ok(o) => o,
+        Err(resp) => return resp,
+    };
+    if w.is_default {
+        return HttpResponse::Conflict()
+            .body("The default wishlist cannot be deleted");
+    }
+    info!("{} deleting wishlist {}", selected_user.username, w.id);
+    diesel::delete(wl::wishlist.find(w.id))
+        .execute(&conn)
+        .expect("Couldn't connect to DB");
+    HttpResponse::Ok().body("Wishlist deleted")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WishlistLine {
+    product_id: i32,
+    quantity: Option<i32>,
+}
+
+pub async fn add_wishlist_item(
+    cookie: Identity,
+    wid: web::Path<i32>,
+    line: web::Json<WishlistLine>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let (_, w) = match owned_list(&cookie, &conn, wid.into_inner()) {
+        Ok(o) => o,
+        Err(resp) => return resp,
+    };
+    let amount = line.quantity.unwrap_or(1);
+    if amount < 1 {
+        return HttpResponse::BadRequest().body("Quantity must be at least 1");
+    }
+    let found = prod::product
+        .find(line.product_id)
+        .first::<Product>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB");
+    if found.is_none() {
+        return HttpResponse::NotFound().body("Product not found");
+    }
+    if let Err(e) = add_to_wishlist(&conn, w.id, line.product_id, amount) {
+        return e.into_response();
+    }
+    HttpResponse::Ok()
+        .json(wishlist_view(&conn, w).expect("Couldn't connect to DB"))
+}
+
+pub async fn remove_wishlist_item(
+    cookie: Identity,
+    wid: web::Path<i32>,
+    line: web::Json<WishlistLine>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let (_, w) = match owned_list(&cookie, &conn, wid.into_inner()) {
+        Ok(o) => o,
+        Err(resp) => return resp,
+    };
+    let removed = remove_from_wishlist(&conn, w.id, line.product_id)
+        .expect("Couldn't connect to DB");
+    if removed == 0 {
+        return HttpResponse::NotFound().body("Item not in wishlist");
+    }
+    HttpResponse::Ok()
+        .json(wishlist_view(&conn, w).expect("Couldn't connect to DB"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SaveForLater {
+    product_id: i32,
+    wishlist_id: Option<i32>,
+}
+
+/// Moves a whole cart line into a wishlist, the default one unless
+/// `wishlist_id` is given.
+pub async fn save_for_later(
+    cookie: Identity,
+    line: web::Json<SaveForLater>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let selected_user = match logged_in(&cookie, &conn) {
+        Some(u) => u,
+        None => {
+            return HttpResponse::Unauthorized()
+                .body("Need to be logged in to save items for later!")
+        }
+    };
+    info!("{} saving for later: {:?}", selected_user.username, line);
+    let w = match line.wishlist_id {
+        Some(wid) => match own_wishlist(&conn, selected_user.id, wid)
+            .expect("Couldn't connect to DB")
+        {
+            Some(w) => w,
+            None => return HttpResponse::NotFound().body("Wishlist not found"),
+        },
+        None => default_wishlist(&conn, selected_user.id)
+            .expect("Couldn't connect to DB"),
+    };
+    let owner = CartOwner::Customer(selected_user.id);
+    let moved = conn.transaction::<_, CartError, _>(|| {
+        let in_cart = current_quantity(&conn, &owner, line.product_id)?;
+        if in_cart == 0 {
+            return Ok(false);
+        }
+        add_to_wishlist(&conn, w.id, line.product_id, in_cart)?;
+        set_quantity(&conn, &owner, line.product_id, 0)?;
+        Ok(true)
+    });
+    match moved {
+        Ok(true) => HttpResponse::Ok()
+            .json(wishlist_view(&conn, w).expect("Couldn't connect to DB")),
+        Ok(false) => HttpResponse::NotFound().body("Item not in cart"),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Moves a wishlist line into the cart. Stock and order limits apply to
+/// the resulting cart quantity, and the line stays in the wishlist if they
+/// are exceeded.
+pub async fn move_to_cart(
+    cookie: Identity,
+    wid: web::Path<i32>,
+    line: web::Json<WishlistLine>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let (selected_user, w) = match owned_list(&cookie, &conn, wid.into_inner())
+    {
+        Ok(o) => o,
+        Err(resp) => return resp,
+    };
+    info!(
+        "{} moving to cart from wishlist {}: {:?}",
+        selected_user.username, w.id, line
+    );
+    let owner = CartOwner::Customer(selected_user.id);
+    let moved = conn.transaction::<_, CartError, _>(|| {
+        let saved = wishlist_quantity(&conn, w.id, line.product_id)?;
+        if saved == 0 {
+            return Ok(false);
+        }
+        let amount = line.quantity.unwrap_or(saved).min(saved);
+        if amount < 1 {
+            return Err(CartError::InvalidQuantity(String::from(
+                "Quantity must be at least 1",
+            )));
+        }
+        let in_cart = current_quantity(&conn, &owner, line.product_id)?;
+        let wanted = in_cart.checked_add(amount).ok_or_else(|| {
+            CartError::InvalidQuantity(String::from("Quantity is too large"))
+        })?;
+        set_quantity(&conn, &owner, line.product_id, wanted)?;
+        if amount == saved {
+            remove_from_wishlist(&conn, w.id, line.product_id)?;
+        } else {
+            diesel::update(
+                wi::wishlist_item
+                    .filter(wi::wishlist_id.eq(w.id))
+                    .filter(wi::product_id.eq(line.product_id)),
+            )
+            .set(wi::quantity.eq(saved - amount))
+            .execute(&conn)?;
+        }
+        Ok(true)
+    });
+    match moved {
+        Ok(true) => HttpResponse::Ok()
+            .json(wishlist_view(&conn, w).expect("Couldn't connect to DB")),
+        Ok(false) => HttpResponse::NotFound().body("Item not in wishlist"),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Makes a wishlist readable by anyone holding its share token, creating
+/// the token on first share. Responds with the token.
+pub async fn share_wishlist(
+    cookie: Identity,
+    wid: web::Path<i32>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let (_, w) = match owned_list(&cookie, &conn, wid.into_inner()) {
+        Ok(o) => o,
+        Err(resp) => return resp,
+    };
+    let token = match w.share_token {
+        Some(t) => t,
+        None => {
+            let t = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+            diesel::update(wl::wishlist.find(w.id))
+                .set(wl::share_token.eq(&t))
+                .execute(&conn)
+                .expect("Couldn't connect to DB");
+            t
+        }
+    };
+    HttpResponse::Ok().json(&token)
+}
+
+pub async fn unshare_wishlist(
+    cookie: Identity,
+    wid: web::Path<i32>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let (_, w) = match owned_list(&cookie, &conn, wid.into_inner()) {
+        Ok(o) => o,
+        Err(resp) => return resp,
+    };
+    diesel::update(wl::wishlist.find(w.id))
+        .set(wl::share_token.eq(None::<String>))
+        .execute(&conn)
+        .expect("Couldn't connect to DB");
+    HttpResponse::Ok().body("Wishlist is no longer shared")
+}
+
+pub async fn shared_wishlist(
+    token: web::Path<String>,
+    pool: web::Data<TPool>,
+) -> impl Responder {
+    let conn = pool.get().unwrap();
+    let found = wl::wishlist
+        .filter(wl::share_token.eq(token.into_inner()))
+        .first::<Wishlist>(&conn)
+        .optional()
+        .expect("Couldn't connect to DB");
+    match found {
+        Some(w) => {
+            let items =
+                wishlist_entries(&conn, w.id).expect("Couldn't connect to DB");
+            HttpResponse::Ok().json(&SharedWishlist {
+                name: w.name,
+                items,
+            })
+        }
+        None => HttpResponse::NotFound().body("Wishlist not found"),
+    }
+}
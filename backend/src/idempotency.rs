@@ -0,0 +1,371 @@
+//! Replays the stored response of a mutating request when a client retries
+//! it with the same `Idempotency-Key` header, instead of repeating its side
+//! effects. Responses are kept in redis for `FURBY_IDEMPOTENCY_TTL` seconds.
+//!
+//! Each key remembers a fingerprint of the request it was first used with,
+//! and reusing it for a different request is answered with 422 rather than
+//! with the response to the first one.
+//!
+//! Keys belong to the logged in user or the guest cart making the request.
+//! Requests with neither can't be told apart from other clients' and are
+//! handled as if they carried no key.
+
+use crate::handlers::cart_items::GUEST_COOKIE;
+
+use actix_identity::RequestIdentity;
+use actix_web::dev::{
+    Body, MessageBody, PayloadStream, ResponseBody, Service, ServiceRequest,
+    ServiceResponse, Transform,
+};
+use actix_web::error::PayloadError;
+use actix_web::http::{header, StatusCode};
+use actix_web::web::BytesMut;
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures::future::{ok, poll_fn, ready, LocalBoxFuture, Ready};
+use futures::{stream, StreamExt};
+use log::{error, info};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+pub const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+const IN_FLIGHT: &str = "in-flight:";
+/// Bodies of idempotent requests are read up front to fingerprint them, so
+/// they are kept to a size that is fine to hold in memory.
+const MAX_BODY_BYTES: usize = 256 * 1024;
+
+fn ttl() -> usize {
+    std::env::var("FURBY_IDEMPOTENCY_TTL")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(24 * 60 * 60)
+}
+
+/// Hash of what makes a request the same request.
+fn fingerprint(req: &ServiceRequest, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str().as_bytes());
+    hasher.update(b" ");
+    hasher.update(req.path().as_bytes());
+    hasher.update(b"?");
+    hasher.update(req.query_string().as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredResponse {
+    fingerprint: String,
+    status: u16,
+    content_type: Option<String>,
+    /// `Set-Cookie` headers, so a retried login or signup still logs in.
+    cookies: Vec<String>,
+    body: String,
+}
+
+impl StoredResponse {
+    fn into_response(self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status)
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut builder = HttpResponse::build(status);
+        if let Some(c) = self.content_type {
+            builder.content_type(c);
+        }
+        for c in self.cookies {
+            builder.header(header::SET_COOKIE, c);
+        }
+        builder
+            .header("Idempotent-Replayed", "true")
+            .body(self.body)
+    }
+}
+
+/// What an already used key holds.
+enum Recorded {
+    /// The first request with the key, with this fingerprint, is still
+    /// being handled.
+    InFlight(String),
+    Done(StoredResponse),
+}
+
+impl Recorded {
+    fn fingerprint(&self) -> &str {
+        match self {
+            Recorded::InFlight(f) => f,
+            Recorded::Done(stored) => &stored.fingerprint,
+        }
+    }
+}
+
+/// Takes `redis_key` for a request with `request_fingerprint`, unless it is
+/// already taken.
+fn claim(
+    conn: &mut redis::Connection,
+    redis_key: &str,
+    request_fingerprint: &str,
+) -> redis::RedisResult<bool> {
+    let claimed = redis::cmd("SET")
+        .arg(redis_key)
+        .arg(format!("{}{}", IN_FLIGHT, request_fingerprint))
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl())
+        .query::<Option<String>>(conn)?;
+    Ok(claimed.is_some())
+}
+
+/// What `redis_key` holds. Records that can't be read are dropped, as if
+/// the key had never been used.
+fn recorded(
+    conn: &mut redis::Connection,
+    redis_key: &str,
+) -> redis::RedisResult<Option<Recorded>> {
+    let value: Option<String> = conn.get(redis_key)?;
+    let value = match value {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    if let Some(f) = value.strip_prefix(IN_FLIGHT) {
+        return Ok(Some(Recorded::InFlight(f.to_string())));
+    }
+    match serde_json::from_str::<StoredResponse>(&value) {
+        Ok(stored) => Ok(Some(Recorded::Done(stored))),
+        Err(e) => {
+            error!(
+                "Dropping unreadable idempotency record {}: {}",
+                redis_key, e
+            );
+            conn.del::<_, ()>(redis_key)?;
+            Ok(None)
+        }
+    }
+}
+
+fn unavailable(req: ServiceRequest, e: redis::RedisError) -> ServiceResponse {
+    error!("Idempotency keys are unavailable: {}", e);
+    req.into_response(
+        HttpResponse::ServiceUnavailable()
+            .body("Unable to handle Idempotency-Key, try again later"),
+    )
+}
+
+/// Middleware honouring `Idempotency-Key` on a resource. Keys are scoped
+/// to the resource and to the logged in user or guest cart.
+pub struct Idempotency {
+    scope: &'static str,
+}
+
+impl Idempotency {
+    pub fn new(scope: &'static str) -> Self {
+        Idempotency { scope }
+    }
+}
+
+impl<S> Transform<S> for Idempotency
+where
+    S: Service<
+            Request = ServiceRequest,
+            Response = ServiceResponse,
+            Error = Error,
+        > + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IdempotencyMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(IdempotencyMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            scope: self.scope,
+        })
+    }
+}
+
+pub struct IdempotencyMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    scope: &'static str,
+}
+
+impl<S> Service for IdempotencyMiddleware<S>
+where
+    S: Service<
+            Request = ServiceRequest,
+            Response = ServiceResponse,
+            Error = Error,
+        > + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let key = req
+            .headers()
+            .get(IDEMPOTENCY_HEADER)
+            .map(|k| k.to_str().map(String::from));
+        let key = match key {
+            None => return Box::pin(self.service.borrow_mut().call(req)),
+            Some(Ok(k)) if !k.is_empty() && k.len() <= 255 => k,
+            Some(_) => {
+                return Box::pin(ok(req.into_response(
+                    HttpResponse::BadRequest()
+                        .body("Invalid Idempotency-Key header"),
+                )))
+            }
+        };
+        let owner = req.get_identity().or_else(|| {
+            req.cookie(GUEST_COOKIE).map(|c| c.value().to_string())
+        });
+        let owner = match owner {
+            Some(o) => o,
+            None => return Box::pin(self.service.borrow_mut().call(req)),
+        };
+        let redis_key = format!("idempotency:{}:{}:{}", self.scope, owner, key);
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let mut payload = req.take_payload();
+            let mut body = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > MAX_BODY_BYTES {
+                    return Ok(req.into_response(
+                        HttpResponse::PayloadTooLarge()
+                            .body("Request is too large"),
+                    ));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            let body = body.freeze();
+            let request_fingerprint = fingerprint(&req, &body);
+            let replayed: PayloadStream =
+                Box::pin(stream::once(ready(Ok::<_, PayloadError>(body))));
+            req.set_payload(replayed.into());
+
+            let mut redis_conn = match redis::Client::open("redis://127.0.0.1/")
+                .and_then(|c| c.get_connection())
+            {
+                Ok(c) => c,
+                Err(e) => return Ok(unavailable(req, e)),
+            };
+            let mut claimed = match claim(
+                &mut redis_conn,
+                &redis_key,
+                &request_fingerprint,
+            ) {
+                Ok(c) => c,
+                Err(e) => return Ok(unavailable(req, e)),
+            };
+            let mut found = None;
+            if !claimed {
+                found = match recorded(&mut redis_conn, &redis_key) {
+                    Ok(f) => f,
+                    Err(e) => return Ok(unavailable(req, e)),
+                };
+                if found.is_none() {
+                    // expired or dropped in the meantime
+                    claimed = match claim(
+                        &mut redis_conn,
+                        &redis_key,
+                        &request_fingerprint,
+                    ) {
+                        Ok(c) => c,
+                        Err(e) => return Ok(unavailable(req, e)),
+                    };
+                }
+            }
+            if !claimed {
+                if let Some(f) = &found {
+                    if f.fingerprint() != request_fingerprint {
+                        info!(
+                            "Idempotency key reused for another request: {}",
+                            redis_key
+                        );
+                        return Ok(req.into_response(
+                            HttpResponse::UnprocessableEntity().body(
+                                "This Idempotency-Key was used for a \
+                                 different request",
+                            ),
+                        ));
+                    }
+                }
+                return match found {
+                    Some(Recorded::Done(stored)) => {
+                        info!("Replaying response for {}", redis_key);
+                        Ok(req.into_response(stored.into_response()))
+                    }
+                    _ => {
+                        Ok(req
+                            .into_response(HttpResponse::Conflict().body(
+                                "A request with this key is in progress",
+                            )))
+                    }
+                };
+            }
+
+            let fut = service.borrow_mut().call(req);
+            let mut res = match fut.await {
+                Ok(r) => r,
+                Err(e) => {
+                    let _: redis::RedisResult<()> = redis_conn.del(&redis_key);
+                    return Err(e);
+                }
+            };
+            let mut body = res.take_body();
+            let mut bytes = BytesMut::new();
+            while let Some(chunk) =
+                poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await
+            {
+                bytes.extend_from_slice(&chunk?);
+            }
+
+            if res.status().is_server_error()
+                || res.status() == StatusCode::CONFLICT
+            {
+                // let the client retry failures and refusals that depend on
+                // state it can change, like an unacknowledged cart
+                let _: redis::RedisResult<()> = redis_conn.del(&redis_key);
+            } else {
+                let stored = StoredResponse {
+                    fingerprint: request_fingerprint,
+                    status: res.status().as_u16(),
+                    content_type: res
+                        .headers()
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|c| c.to_str().ok())
+                        .map(String::from),
+                    cookies: res
+                        .headers()
+                        .get_all(header::SET_COOKIE)
+                        .filter_map(|c| c.to_str().ok())
+                        .map(String::from)
+                        .collect(),
+                    body: String::from_utf8_lossy(&bytes).into_owned(),
+                };
+                let stored = serde_json::to_string(&stored).unwrap();
+                if let Err(e) =
+                    redis_conn.set_ex::<_, _, ()>(&redis_key, stored, ttl())
+                {
+                    error!("Unable to store idempotent response: {}", e);
+                }
+            }
+            Ok(res.map_body(|_, _| {
+                ResponseBody::Other(Body::from(bytes.freeze()))
+            }))
+        })
+    }
+}
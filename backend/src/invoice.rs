@@ -0,0 +1,525 @@
+//! Invoices for orders, rendered as HTML or as a PDF without any external
+//! service. Shared by the `/transaction/{id}/invoice` handler and the
+//! `invoices` export tool.
+
+use crate::models::{
+    AddInvoice, Customer, Invoice as InvoiceRecord, Product, Transaction,
+    TransactionItem,
+};
+use crate::schema::customer::dsl as cust;
+use crate::schema::invoice::dsl as inv;
+use crate::schema::product::dsl as prod;
+use crate::schema::transaction_item::dsl as ti;
+
+use chrono::naive::{NaiveDate, NaiveDateTime};
+use diesel::prelude::*;
+use std::env;
+
+pub struct Party {
+    pub name: String,
+    pub address: Option<String>,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub tax_id: Option<String>,
+}
+
+pub struct InvoiceLine {
+    pub description: String,
+    pub quantity: i32,
+    pub unit_price: f32,
+    pub net: f32,
+    pub tax: f32,
+    pub total: f32,
+}
+
+pub struct Invoice {
+    pub number: String,
+    pub issued_at: NaiveDateTime,
+    pub order_id: i32,
+    pub order_date: NaiveDate,
+    pub payment_type: String,
+    pub currency: String,
+    pub seller: Party,
+    pub buyer: Party,
+    pub lines: Vec<InvoiceLine>,
+    pub tax_rate: f32,
+    pub net_total: f32,
+    pub tax_total: f32,
+    pub total: f32,
+}
+
+/// Tax rate in percent, read from `FURBY_TAX_RATE`. Prices are tax
+/// inclusive, the invoice only breaks the tax out.
+pub fn tax_rate() -> f32 {
+    env::var("FURBY_TAX_RATE")
+        .ok()
+        .and_then(|r| r.parse().ok())
+        .unwrap_or(18.)
+}
+
+pub fn seller() -> Party {
+    Party {
+        name: env::var("FURBY_SELLER_NAME")
+            .unwrap_or_else(|_| String::from("Furby")),
+        address: env::var("FURBY_SELLER_ADDRESS").ok(),
+        email: env::var("FURBY_SELLER_EMAIL").ok(),
+        phone: env::var("FURBY_SELLER_PHONE").ok(),
+        tax_id: env::var("FURBY_SELLER_TAX_ID").ok(),
+    }
+}
+
+pub fn currency() -> String {
+    env::var("FURBY_CURRENCY").unwrap_or_else(|_| String::from("INR"))
+}
+
+/// Returns the invoice record of an order, numbering it on first use so
+/// invoice numbers follow the order in which invoices are issued.
+fn invoice_record(
+    conn: &MysqlConnection,
+    order_id: i32,
+) -> QueryResult<InvoiceRecord> {
+    let existing = inv::invoice
+        .filter(inv::transaction_id.eq(order_id))
+        .first::<InvoiceRecord>(conn)
+        .optional()?;
+    if let Some(record) = existing {
+        return Ok(record);
+    }
+    let inserted = diesel::insert_into(inv::invoice)
+        .values(AddInvoice {
+            transaction_id: order_id,
+        })
+        .execute(conn);
+    match inserted {
+        Ok(_) => (),
+        // another request numbered this order first
+        Err(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => (),
+        Err(e) => return Err(e),
+    }
+    inv::invoice
+        .filter(inv::transaction_id.eq(order_id))
+        .first::<InvoiceRecord>(conn)
+}
+
+fn split_tax(gross: f32, rate: f32) -> (f32, f32) {
+    let net = gross / (1. + rate / 100.);
+    (net, gross - net)
+}
+
+/// Cancelled orders were never charged for, so they get no invoice.
+pub fn invoiceable(order: &Transaction) -> bool {
+    order.fulfilment_status != "cancelled"
+}
+
+pub fn invoice_for(
+    conn: &MysqlConnection,
+    order: &Transaction,
+) -> QueryResult<Invoice> {
+    let record = invoice_record(conn, order.id)?;
+    let rate = tax_rate();
+    let buyer = match order.customer_id {
+        Some(cid) => cust::customer.find(cid).first::<Customer>(conn).ok(),
+        None => None,
+    };
+    let buyer = match buyer {
+        Some(c) => Party {
+            name: c.username,
+            address: c.address,
+            email: Some(c.email_id),
+            phone: Some(c.phone_number),
+            tax_id: None,
+        },
+        None => Party {
+            name: String::from("Customer"),
+            address: None,
+            email: None,
+            phone: None,
+            tax_id: None,
+        },
+    };
+    let order_lines = ti::transaction_item
+        .filter(ti::transaction_id.eq(order.id))
+        .load::<TransactionItem>(conn)?;
+    // orders placed before order lines were recorded have none at all
+    let legacy = order_lines.is_empty();
+    let mut lines = vec![];
+    for line in order_lines {
+        let quantity = line.quantity - line.cancelled_quantity;
+        if quantity <= 0 {
+            continue;
+        }
+        let name = prod::product
+            .find(line.product_id)
+            .first::<Product>(conn)?
+            .name;
+        let total = quantity as f32 * line.unit_price;
+        let (net, tax) = split_tax(total, rate);
+        lines.push(InvoiceLine {
+            description: name,
+            quantity,
+            unit_price: line.unit_price,
+            net,
+            tax,
+            total,
+        });
+    }
+    if legacy {
+        let (net, tax) = split_tax(order.amount, rate);
+        lines.push(InvoiceLine {
+            description: String::from("Order total"),
+            quantity: 1,
+            unit_price: order.amount,
+            net,
+            tax,
+            total: order.amount,
+        });
+    }
+    Ok(Invoice {
+        number: format!("INV-{:06}", record.id),
+        issued_at: record.issued_at,
+        order_id: order.id,
+        order_date: order.order_date,
+        payment_type: order.payment_type.clone(),
+        currency: currency(),
+        seller: seller(),
+        buyer,
+        net_total: lines.iter().map(|l| l.net).sum(),
+        tax_total: lines.iter().map(|l| l.tax).sum(),
+        total: lines.iter().map(|l| l.total).sum(),
+        tax_rate: rate,
+        lines,
+    })
+}
+
+fn party_lines(party: &Party) -> Vec<String> {
+    let mut lines = vec![party.name.clone()];
+    if let Some(a) = &party.address {
+        lines.extend(a.lines().map(String::from));
+    }
+    if let Some(e) = &party.email {
+        lines.push(e.clone());
+    }
+    if let Some(p) = &party.phone {
+        lines.push(p.clone());
+    }
+    if let Some(t) = &party.tax_id {
+        lines.push(format!("Tax ID: {}", t));
+    }
+    lines
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn render_html(invoice: &Invoice) -> String {
+    let party = |p: &Party| {
+        party_lines(p)
+            .iter()
+            .map(|l| escape_html(l))
+            .collect::<Vec<_>>()
+            .join("<br>")
+    };
+    let rows = invoice
+        .lines
+        .iter()
+        .map(|l| {
+            format!(
+                "<tr><td>{}</td><td class=\"num\">{}</td>\
+                 <td class=\"num\">{:.2}</td><td class=\"num\">{:.2}</td>\
+                 <td class=\"num\">{:.2}</td><td class=\"num\">{:.2}</td></tr>",
+                escape_html(&l.description),
+                l.quantity,
+                l.unit_price,
+                l.net,
+                l.tax,
+                l.total
+            )
+        })
+        .collect::<String>();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Invoice {number}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; color: #222; }}
+table {{ border-collapse: collapse; width: 100%; }}
+th, td {{ padding: 0.4em; border-bottom: 1px solid #ddd; text-align: left; }}
+.num {{ text-align: right; }}
+.parties {{ display: flex; justify-content: space-between; margin: 2em 0; }}
+</style>
+</head>
+<body>
+<h1>Invoice {number}</h1>
+<p>Issued {issued}<br>Order #{order} placed {order_date}<br>Paid by {payment}</p>
+<div class="parties">
+<div><strong>Seller</strong><br>{seller}</div>
+<div><strong>Bill to</strong><br>{buyer}</div>
+</div>
+<table>
+<tr><th>Item</th><th class="num">Qty</th><th class="num">Unit price</th><th class="num">Net</th><th class="num">Tax</th><th class="num">Total</th></tr>
+{rows}
+</table>
+<table>
+<tr><td>Net amount</td><td class="num">{net:.2}</td></tr>
+<tr><td>Tax ({rate}%)</td><td class="num">{tax:.2}</td></tr>
+<tr><th>Total ({currency})</th><th class="num">{total:.2}</th></tr>
+</table>
+</body>
+</html>
+"#,
+        number = invoice.number,
+        issued = invoice.issued_at.format("%Y-%m-%d"),
+        order = invoice.order_id,
+        order_date = invoice.order_date,
+        payment = escape_html(&invoice.payment_type),
+        seller = party(&invoice.seller),
+        buyer = party(&invoice.buyer),
+        rows = rows,
+        net = invoice.net_total,
+        rate = invoice.tax_rate,
+        tax = invoice.tax_total,
+        currency = invoice.currency,
+        total = invoice.total,
+    )
+}
+
+/* PDF */
+
+const PAGE_WIDTH: f32 = 595.;
+const PAGE_HEIGHT: f32 = 842.;
+const MARGIN: f32 = 50.;
+
+/// Approximate Helvetica advance width, good enough to right align numbers.
+fn text_width(s: &str, size: f32) -> f32 {
+    s.chars()
+        .map(|c| match c {
+            '.' | ',' | ' ' | 'i' | 'l' | 'I' => 0.278,
+            '-' => 0.333,
+            _ => 0.556,
+        })
+        .sum::<f32>()
+        * size
+}
+
+/// Escapes a string for a PDF literal, replacing anything outside of
+/// Latin-1 since only the standard fonts are used.
+fn escape_pdf(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            _ => out.push('?'),
+        }
+    }
+    out
+}
+
+struct PdfPage {
+    ops: String,
+    y: f32,
+}
+
+impl PdfPage {
+    fn new() -> Self {
+        PdfPage {
+            ops: String::new(),
+            y: PAGE_HEIGHT - MARGIN,
+        }
+    }
+
+    fn text(&mut self, x: f32, size: f32, bold: bool, s: &str) {
+        let font = if bold { "F2" } else { "F1" };
+        self.ops.push_str(&format!(
+            "BT /{} {} Tf {:.2} {:.2} Td ({}) Tj ET\n",
+            font,
+            size,
+            x,
+            self.y,
+            escape_pdf(s)
+        ));
+    }
+
+    fn text_right(&mut self, x: f32, size: f32, bold: bool, s: &str) {
+        self.text(x - text_width(s, size), size, bold, s);
+    }
+
+    fn rule(&mut self) {
+        self.ops.push_str(&format!(
+            "{:.2} {:.2} m {:.2} {:.2} l S\n",
+            MARGIN,
+            self.y - 4.,
+            PAGE_WIDTH - MARGIN,
+            self.y - 4.
+        ));
+    }
+}
+
+struct PdfDocument {
+    pages: Vec<PdfPage>,
+}
+
+impl PdfDocument {
+    fn page(&mut self) -> &mut PdfPage {
+        self.pages.last_mut().unwrap()
+    }
+
+    /// Moves down by `height`, starting a new page when out of room.
+    fn advance(&mut self, height: f32) -> &mut PdfPage {
+        if self.page().y - height < MARGIN {
+            self.pages.push(PdfPage::new());
+        } else {
+            self.page().y -= height;
+        }
+        self.page()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let page_count = self.pages.len();
+        let mut objects = vec![
+            String::from("<< /Type /Catalog /Pages 2 0 R >>"),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                (0..page_count)
+                    .map(|i| format!("{} 0 R", 5 + 2 * i))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                page_count
+            ),
+            String::from(
+                "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica \
+                 /Encoding /WinAnsiEncoding >>",
+            ),
+            String::from(
+                "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold \
+                 /Encoding /WinAnsiEncoding >>",
+            ),
+        ];
+        for (i, page) in self.pages.into_iter().enumerate() {
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+                 /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> \
+                 /Contents {} 0 R >>",
+                PAGE_WIDTH,
+                PAGE_HEIGHT,
+                6 + 2 * i
+            ));
+            objects.push(format!(
+                "<< /Length {} >>\nstream\n{}endstream",
+                page.ops.len(),
+                page.ops
+            ));
+        }
+
+        let mut out = String::from("%PDF-1.4\n");
+        let mut offsets = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.push_str(&format!("{} 0 obj\n{}\nendobj\n", i + 1, object));
+        }
+        let xref = out.len();
+        out.push_str(&format!("xref\n0 {}\n", objects.len() + 1));
+        out.push_str("0000000000 65535 f \n");
+        for offset in offsets {
+            out.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        out.push_str(&format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref
+        ));
+        out.into_bytes()
+    }
+}
+
+pub fn render_pdf(invoice: &Invoice) -> Vec<u8> {
+    let mut doc = PdfDocument {
+        pages: vec![PdfPage::new()],
+    };
+    let right = PAGE_WIDTH - MARGIN;
+
+    let page = doc.page();
+    page.text(MARGIN, 20., true, &format!("Invoice {}", invoice.number));
+    for line in &[
+        format!("Issued {}", invoice.issued_at.format("%Y-%m-%d")),
+        format!("Order #{} placed {}", invoice.order_id, invoice.order_date),
+        format!("Paid by {}", invoice.payment_type),
+    ] {
+        doc.advance(14.).text(MARGIN, 10., false, line);
+    }
+
+    doc.advance(28.).text(MARGIN, 11., true, "Seller");
+    doc.page().text(320., 11., true, "Bill to");
+    let seller = party_lines(&invoice.seller);
+    let buyer = party_lines(&invoice.buyer);
+    for i in 0..seller.len().max(buyer.len()) {
+        let page = doc.advance(13.);
+        if let Some(l) = seller.get(i) {
+            page.text(MARGIN, 10., false, l);
+        }
+        if let Some(l) = buyer.get(i) {
+            page.text(320., 10., false, l);
+        }
+    }
+
+    let header = |page: &mut PdfPage| {
+        page.text(MARGIN, 10., true, "Item");
+        page.text_right(300., 10., true, "Qty");
+        page.text_right(370., 10., true, "Unit price");
+        page.text_right(430., 10., true, "Net");
+        page.text_right(485., 10., true, "Tax");
+        page.text_right(right, 10., true, "Total");
+        page.rule();
+    };
+    header(doc.advance(30.));
+    for line in &invoice.lines {
+        let pages = doc.pages.len();
+        doc.advance(16.);
+        if pages != doc.pages.len() {
+            header(doc.page());
+            doc.advance(16.);
+        }
+        let mut description = line.description.clone();
+        if description.chars().count() > 40 {
+            description = description.chars().take(37).collect();
+            description.push_str("...");
+        }
+        let page = doc.page();
+        page.text(MARGIN, 10., false, &description);
+        page.text_right(300., 10., false, &line.quantity.to_string());
+        page.text_right(370., 10., false, &format!("{:.2}", line.unit_price));
+        page.text_right(430., 10., false, &format!("{:.2}", line.net));
+        page.text_right(485., 10., false, &format!("{:.2}", line.tax));
+        page.text_right(right, 10., false, &format!("{:.2}", line.total));
+    }
+    doc.page().rule();
+
+    let totals = [
+        (String::from("Net amount"), invoice.net_total, false),
+        (
+            format!("Tax ({}%)", invoice.tax_rate),
+            invoice.tax_total,
+            false,
+        ),
+        (format!("Total ({})", invoice.currency), invoice.total, true),
+    ];
+    for (label, value, bold) in totals.iter() {
+        let page = doc.advance(16.);
+        page.text(370., 10., *bold, label);
+        page.text_right(right, 10., *bold, &format!("{:.2}", value));
+    }
+
+    doc.into_bytes()
+}
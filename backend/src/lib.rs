@@ -1,10 +1,30 @@
 #[macro_use]
 extern crate diesel;
 
+pub mod api_tokens;
+pub mod audit;
+pub mod csrf;
 pub mod handlers;
+pub mod idempotency;
+pub mod invoice;
+pub mod login_throttle;
+pub mod mailer;
 pub mod models;
+pub mod notifications;
+pub mod reminders;
 pub mod schema;
+pub mod session;
+pub mod settings;
+pub mod storage;
+pub mod tokens;
+pub mod totp;
+pub mod validation;
 
 use diesel::r2d2::{self, ConnectionManager};
 use diesel::MysqlConnection;
 pub type TPool = r2d2::Pool<ConnectionManager<MysqlConnection>>;
+
+no_arg_sql_function!(
+    last_insert_id,
+    diesel::sql_types::Unsigned<diesel::sql_types::BigInt>
+);
@@ -0,0 +1,124 @@
+//! Throttling of password guesses. Failed logins are counted in Redis per
+//! username and per client address for `FURBY_LOGIN_FAILURE_WINDOW`
+//! seconds (900). Every failure doubles the wait before the next attempt
+//! (1s, 2s, 4s ...), and once a username has failed
+//! `FURBY_LOGIN_LOCKOUT_AFTER` times (5), or an address
+//! `FURBY_LOGIN_IP_LOCKOUT_AFTER` times (20), it is locked out for
+//! `FURBY_LOGIN_LOCKOUT_SECONDS` (900).
+//!
+//! Other requests that shouldn't be repeated too often, like password reset
+//! mails, are throttled the same way under an action of their own, so they
+//! never count against logging in.
+
+use redis::{Commands, Connection, RedisResult};
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn failure_window() -> usize {
+    env_or("FURBY_LOGIN_FAILURE_WINDOW", 900)
+}
+
+fn lockout_seconds() -> usize {
+    env_or("FURBY_LOGIN_LOCKOUT_SECONDS", 900)
+}
+
+#[derive(Clone, Copy)]
+pub enum Subject<'a> {
+    User(&'a str),
+    Ip(&'a str),
+}
+
+impl Subject<'_> {
+    fn key(&self, kind: &str) -> String {
+        match self {
+            Subject::User(u) => format!("{}:user:{}", kind, u),
+            Subject::Ip(ip) => format!("{}:ip:{}", kind, ip),
+        }
+    }
+
+    fn lockout_after(&self) -> usize {
+        match self {
+            Subject::User(_) => env_or("FURBY_LOGIN_LOCKOUT_AFTER", 5),
+            Subject::Ip(_) => env_or("FURBY_LOGIN_IP_LOCKOUT_AFTER", 20),
+        }
+    }
+}
+
+/// What a failed attempt led to, with the seconds to wait.
+pub enum Penalty {
+    Delayed(usize),
+    Locked(usize),
+}
+
+fn redis_conn() -> RedisResult<Connection> {
+    redis::Client::open("redis://127.0.0.1/")?.get_connection()
+}
+
+const LOGIN: &str = "login";
+pub const RESET_MAIL: &str = "reset_mail";
+
+/// Seconds until all of `subjects` may try to log in again, if any has to
+/// wait.
+pub fn retry_after(subjects: &[Subject]) -> RedisResult<Option<usize>> {
+    retry_after_for(LOGIN, subjects)
+}
+
+/// Counts a failed login against each of `subjects`.
+pub fn record_failure(subjects: &[Subject]) -> RedisResult<Penalty> {
+    record_for(LOGIN, subjects)
+}
+
+/// Like `retry_after`, for `action`.
+pub fn retry_after_for(
+    action: &str,
+    subjects: &[Subject],
+) -> RedisResult<Option<usize>> {
+    let mut r = redis_conn()?;
+    let mut wait = None;
+    for s in subjects {
+        let ttl: isize = r.ttl(s.key(&format!("{}_blocked", action)))?;
+        if ttl > 0 {
+            wait = wait.max(Some(ttl as usize));
+        }
+    }
+    Ok(wait)
+}
+
+/// Like `record_failure`, for `action`.
+pub fn record_for(action: &str, subjects: &[Subject]) -> RedisResult<Penalty> {
+    let mut r = redis_conn()?;
+    let mut wait = 0;
+    let mut locked = false;
+    for s in subjects {
+        let failures_key = s.key(&format!("{}_failures", action));
+        let failures: usize = r.incr(&failures_key, 1)?;
+        let _: () = r.expire(&failures_key, failure_window())?;
+        let delay = if failures >= s.lockout_after() {
+            locked = true;
+            lockout_seconds()
+        } else {
+            2usize
+                .saturating_pow(failures as u32 - 1)
+                .min(lockout_seconds())
+        };
+        let _: () =
+            r.set_ex(s.key(&format!("{}_blocked", action)), 1, delay)?;
+        wait = wait.max(delay);
+    }
+    Ok(if locked {
+        Penalty::Locked(wait)
+    } else {
+        Penalty::Delayed(wait)
+    })
+}
+
+/// Forgets the failures of `uname` after a successful login.
+pub fn record_success(uname: &str) -> RedisResult<()> {
+    let user = Subject::User(uname);
+    redis_conn()?.del(&[user.key("login_failures"), user.key("login_blocked")])
+}
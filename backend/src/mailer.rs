@@ -0,0 +1,142 @@
+//! Email goes out through a `Mailer`, picked with `FURBY_MAILER`:
+//!
+//!  - `outbox` (default) queues mail in the notification outbox, from where
+//!    the `deliver_mail` binary hands it to sendmail
+//!  - `file` writes each mail to `FURBY_MAIL_DIR` (mail) as a .eml file,
+//!    handy for trying things out without a mail server
+
+use crate::models::AddNotification;
+use crate::notifications;
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use log::info;
+use rand::Rng;
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+#[derive(Debug)]
+pub enum MailError {
+    Db(diesel::result::Error),
+    Io(io::Error),
+    Rejected(String),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::Db(e) => write!(f, "unable to queue mail: {}", e),
+            MailError::Io(e) => write!(f, "unable to write mail: {}", e),
+            MailError::Rejected(e) => write!(f, "mail was rejected: {}", e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for MailError {
+    fn from(e: diesel::result::Error) -> Self {
+        MailError::Db(e)
+    }
+}
+
+/// `mail` as an RFC 5322 message.
+fn message(mail: &AddNotification, date: DateTime<Utc>) -> String {
+    format!(
+        "To: {}\r\nSubject: {}\r\nDate: {}\r\nX-Furby-Kind: {}\r\n\r\n{}\r\n",
+        mail.recipient,
+        mail.subject,
+        date.to_rfc2822(),
+        mail.kind,
+        mail.body
+    )
+}
+
+pub trait Mailer {
+    fn send(&self, mail: AddNotification) -> Result<(), MailError>;
+}
+
+pub struct OutboxMailer<'a> {
+    pub conn: &'a MysqlConnection,
+}
+
+impl Mailer for OutboxMailer<'_> {
+    fn send(&self, mail: AddNotification) -> Result<(), MailError> {
+        let nid = notifications::queue(self.conn, mail)?;
+        info!("Queued mail {}", nid);
+        Ok(())
+    }
+}
+
+pub struct FileMailer {
+    pub dir: PathBuf,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: AddNotification) -> Result<(), MailError> {
+        fs::create_dir_all(&self.dir).map_err(MailError::Io)?;
+        let sent_at = Utc::now();
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            sent_at.format("%Y%m%d%H%M%S"),
+            hex::encode(rand::thread_rng().gen::<[u8; 4]>())
+        ));
+        fs::write(&path, message(&mail, sent_at)).map_err(MailError::Io)?;
+        info!("Wrote mail to {}", path.display());
+        Ok(())
+    }
+}
+
+/// Hands mail to a sendmail compatible program, `FURBY_SENDMAIL` (sendmail)
+/// run with `-t -i`.
+pub struct SendmailMailer {
+    pub program: String,
+}
+
+impl SendmailMailer {
+    pub fn from_env() -> Self {
+        SendmailMailer {
+            program: std::env::var("FURBY_SENDMAIL")
+                .unwrap_or_else(|_| String::from("sendmail")),
+        }
+    }
+}
+
+impl Mailer for SendmailMailer {
+    fn send(&self, mail: AddNotification) -> Result<(), MailError> {
+        let mut child = Command::new(&self.program)
+            .args(["-t", "-i"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(MailError::Io)?;
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(message(&mail, Utc::now()).as_bytes())
+            .map_err(MailError::Io)?;
+        let status = child.wait().map_err(MailError::Io)?;
+        if !status.success() {
+            return Err(MailError::Rejected(format!(
+                "{} exited with {}",
+                self.program, status
+            )));
+        }
+        info!("Sent mail to {}", mail.recipient);
+        Ok(())
+    }
+}
+
+/// Mailer configured by `FURBY_MAILER`.
+pub fn from_env(conn: &MysqlConnection) -> Box<dyn Mailer + '_> {
+    match std::env::var("FURBY_MAILER").as_deref() {
+        Ok("file") => Box::new(FileMailer {
+            dir: std::env::var("FURBY_MAIL_DIR")
+                .unwrap_or_else(|_| String::from("mail"))
+                .into(),
+        }),
+        _ => Box::new(OutboxMailer { conn }),
+    }
+}
@@ -1,11 +1,18 @@
-use super::schema::{cart_items, customer, product, rating, transaction};
+use super::schema::{
+    api_token, audit_log, cart, cart_items, cart_reminder, customer,
+    customer_totp, guest_cart_items, invoice, notification_outbox,
+    payment_event, product, rating, rating_photo, rating_report, rating_vote,
+    recovery_code, refund, return_event, return_item, return_request,
+    site_setting, transaction, transaction_item, user_token, wishlist,
+    wishlist_item,
+};
 
 use chrono::naive::{NaiveDate, NaiveDateTime};
 use diesel::{Insertable, Queryable};
 use serde::{Deserialize, Serialize};
 
 /* Member */
-#[derive(Queryable, Serialize)]
+#[derive(Queryable)]
 pub struct Customer {
     pub id: i32,
     pub username: String,
@@ -13,6 +20,20 @@ pub struct Customer {
     pub phone_number: String,
     pub email_id: String,
     pub address: Option<String>,
+    pub role: String,
+    pub email_verified: bool,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl Customer {
+    pub fn is_staff(&self) -> bool {
+        self.role == "staff" || self.role == "admin"
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == "admin"
+    }
 }
 
 #[derive(Insertable, Deserialize)]
@@ -37,6 +58,9 @@ pub struct Product {
     pub description: Option<String>,
     pub src: Option<String>,
     pub ios_src: Option<String>,
+    pub stock: Option<i32>,
+    pub max_order_quantity: Option<i32>,
+    pub archived: bool,
 }
 
 #[derive(Insertable, Deserialize)]
@@ -56,6 +80,12 @@ pub struct NewProduct {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ios_src: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stock: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_order_quantity: Option<i32>,
 }
 
 #[derive(Deserialize)]
@@ -64,14 +94,32 @@ pub struct UpdateProduct {
     pub kind: Option<String>,
     pub price: f32,
     pub description: Option<String>,
+    pub stock: Option<i32>,
+    pub max_order_quantity: Option<i32>,
+    pub archived: Option<bool>,
 }
 
 /* Cart Items */
+#[derive(Queryable, Serialize)]
+pub struct Cart {
+    pub customer_id: i32,
+    pub updated_at: NaiveDateTime,
+    pub reminders_sent: i32,
+    pub last_reminded_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "cart"]
+pub struct TouchCart {
+    pub customer_id: i32,
+}
+
 #[derive(Queryable, Serialize)]
 pub struct CartItem {
     pub cart_id: i32,
     pub product_id: i32,
     pub quantity: Option<i32>,
+    pub unit_price: Option<f32>,
 }
 
 #[derive(Insertable, Deserialize)]
@@ -80,6 +128,124 @@ pub struct AddCartItem {
     pub cart_id: i32,
     pub product_id: i32,
     pub quantity: Option<i32>,
+    pub unit_price: Option<f32>,
+}
+
+/// A difference between a cart line and the product as it is sold now.
+#[derive(Serialize)]
+#[serde(tag = "issue", rename_all = "snake_case")]
+pub enum CartIssue {
+    PriceChanged {
+        product_id: i32,
+        name: String,
+        old_price: f32,
+        new_price: f32,
+    },
+    OutOfStock {
+        product_id: i32,
+        name: String,
+        requested: i32,
+        available: i32,
+    },
+    Archived {
+        product_id: i32,
+        name: String,
+    },
+}
+
+#[derive(Queryable, Serialize)]
+pub struct GuestCartItem {
+    pub guest_id: String,
+    pub product_id: i32,
+    pub quantity: i32,
+    pub created_at: NaiveDateTime,
+    pub unit_price: Option<f32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "guest_cart_items"]
+pub struct AddGuestCartItem {
+    pub guest_id: String,
+    pub product_id: i32,
+    pub quantity: i32,
+    pub unit_price: Option<f32>,
+}
+
+/* Wishlist */
+#[derive(Queryable, Serialize)]
+pub struct Wishlist {
+    pub id: i32,
+    pub customer_id: i32,
+    pub name: String,
+    pub is_default: bool,
+    pub share_token: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "wishlist"]
+pub struct AddWishlist {
+    pub customer_id: i32,
+    pub name: String,
+    pub is_default: bool,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct WishlistItem {
+    pub wishlist_id: i32,
+    pub product_id: i32,
+    pub quantity: i32,
+    pub added_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "wishlist_item"]
+pub struct AddWishlistItem {
+    pub wishlist_id: i32,
+    pub product_id: i32,
+    pub quantity: i32,
+}
+
+/* Notifications */
+#[derive(Queryable, Serialize)]
+pub struct Notification {
+    pub id: i32,
+    pub customer_id: Option<i32>,
+    pub kind: String,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub created_at: NaiveDateTime,
+    pub sent_at: Option<NaiveDateTime>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "notification_outbox"]
+pub struct AddNotification {
+    pub customer_id: Option<i32>,
+    pub kind: String,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct CartReminder {
+    pub id: i32,
+    pub customer_id: i32,
+    pub notification_id: i32,
+    pub sent_at: NaiveDateTime,
+    pub recovered_transaction_id: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "cart_reminder"]
+pub struct AddCartReminder {
+    pub customer_id: i32,
+    pub notification_id: i32,
 }
 
 /* Rating */
@@ -91,6 +257,14 @@ pub struct Rating {
     pub product_id: Option<i32>,
     pub customer_id: Option<i32>,
     pub stars: Option<i32>,
+    pub verified_purchase: bool,
+    pub edited_at: Option<NaiveDateTime>,
+    pub status: String,
+    pub flag_reason: Option<String>,
+    pub moderated_by: Option<i32>,
+    pub moderated_at: Option<NaiveDateTime>,
+    pub helpful_votes: i32,
+    pub not_helpful_votes: i32,
 }
 
 #[derive(Insertable, Deserialize)]
@@ -104,6 +278,58 @@ pub struct AddRating {
 
     pub product_id: i32,
     pub customer_id: i32,
+    pub verified_purchase: bool,
+    pub status: String,
+    pub flag_reason: Option<String>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct RatingPhoto {
+    pub id: i32,
+    pub rating_id: i32,
+    pub storage_key: String,
+    pub thumbnail_key: String,
+    pub content_type: String,
+    pub size: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "rating_photo"]
+pub struct AddRatingPhoto {
+    pub rating_id: i32,
+    pub storage_key: String,
+    pub thumbnail_key: String,
+    pub content_type: String,
+    pub size: i32,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct RatingReport {
+    pub id: i32,
+    pub rating_id: i32,
+    pub customer_id: i32,
+    pub reason: String,
+    pub note: Option<String>,
+    pub resolved: bool,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "rating_report"]
+pub struct AddRatingReport {
+    pub rating_id: i32,
+    pub customer_id: i32,
+    pub reason: String,
+    pub note: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "rating_vote"]
+pub struct AddRatingVote {
+    pub rating_id: i32,
+    pub customer_id: i32,
+    pub helpful: bool,
 }
 
 /* Transaction */
@@ -114,6 +340,8 @@ pub struct Transaction {
     pub amount: f32,
     pub customer_id: Option<i32>,
     pub order_date: NaiveDate,
+    pub payment_status: String,
+    pub fulfilment_status: String,
 }
 
 #[derive(Insertable, Deserialize)]
@@ -123,3 +351,238 @@ pub struct AddTransaction {
     pub amount: f32,
     pub customer_id: Option<i32>,
 }
+
+/* Transaction Item */
+#[derive(Queryable, Serialize)]
+pub struct TransactionItem {
+    pub id: i32,
+    pub transaction_id: i32,
+    pub product_id: i32,
+    pub quantity: i32,
+    pub unit_price: f32,
+    pub cancelled_quantity: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "transaction_item"]
+pub struct AddTransactionItem {
+    pub transaction_id: i32,
+    pub product_id: i32,
+    pub quantity: i32,
+    pub unit_price: f32,
+}
+
+/* Invoice */
+#[derive(Queryable, Serialize)]
+pub struct Invoice {
+    pub id: i32,
+    pub transaction_id: i32,
+    pub issued_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "invoice"]
+pub struct AddInvoice {
+    pub transaction_id: i32,
+}
+
+/* Payment Event */
+#[derive(Queryable, Serialize)]
+pub struct PaymentEvent {
+    pub id: String,
+    pub event_type: String,
+    pub transaction_id: Option<i32>,
+    pub payload: String,
+    pub received_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "payment_event"]
+pub struct AddPaymentEvent {
+    pub id: String,
+    pub event_type: String,
+    pub transaction_id: Option<i32>,
+    pub payload: String,
+}
+
+/* Refund */
+#[derive(Queryable, Serialize)]
+pub struct Refund {
+    pub id: i32,
+    pub transaction_id: i32,
+    pub amount: f32,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "refund"]
+pub struct AddRefund {
+    pub transaction_id: i32,
+    pub amount: f32,
+}
+
+/* Returns */
+#[derive(Queryable, Serialize)]
+pub struct ReturnRequest {
+    pub id: i32,
+    pub transaction_id: i32,
+    pub customer_id: i32,
+    pub status: String,
+    pub refund_amount: Option<f32>,
+    pub refund_id: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "return_request"]
+pub struct AddReturnRequest {
+    pub transaction_id: i32,
+    pub customer_id: i32,
+}
+
+#[derive(Queryable, Insertable, Serialize)]
+#[table_name = "return_item"]
+pub struct ReturnItem {
+    pub return_id: i32,
+    pub transaction_item_id: i32,
+    pub quantity: i32,
+    pub reason: String,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct ReturnEvent {
+    pub id: i32,
+    pub return_id: i32,
+    pub status: String,
+    pub note: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "return_event"]
+pub struct AddReturnEvent {
+    pub return_id: i32,
+    pub status: String,
+    pub note: Option<String>,
+}
+
+/* One-time tokens */
+#[derive(Queryable)]
+pub struct UserToken {
+    pub id: i32,
+    pub customer_id: i32,
+    pub purpose: String,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "user_token"]
+pub struct AddUserToken {
+    pub customer_id: i32,
+    pub purpose: String,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+}
+
+/* Audit log */
+#[derive(Queryable, Serialize)]
+pub struct AuditEvent {
+    pub id: i32,
+    pub event: String,
+    pub customer_id: Option<i32>,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub detail: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Clone, Default)]
+#[table_name = "audit_log"]
+pub struct AddAuditEvent {
+    pub event: String,
+    pub customer_id: Option<i32>,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub detail: Option<String>,
+}
+
+/* Two-factor authentication */
+#[derive(Queryable)]
+pub struct CustomerTotp {
+    pub customer_id: i32,
+    pub secret: String,
+    pub confirmed_at: Option<NaiveDateTime>,
+    pub last_used_step: Option<i64>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "customer_totp"]
+pub struct AddCustomerTotp {
+    pub customer_id: i32,
+    pub secret: String,
+}
+
+#[derive(Queryable)]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub customer_id: i32,
+    pub code_hash: String,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "recovery_code"]
+pub struct AddRecoveryCode {
+    pub customer_id: i32,
+    pub code_hash: String,
+}
+
+/* Site settings */
+#[derive(Queryable, Serialize)]
+pub struct SiteSetting {
+    pub name: String,
+    pub value: String,
+    pub updated_by: Option<i32>,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "site_setting"]
+pub struct AddSiteSetting {
+    pub name: String,
+    pub value: String,
+    pub updated_by: Option<i32>,
+}
+
+/* API tokens */
+#[derive(Queryable)]
+pub struct ApiToken {
+    pub id: i32,
+    pub customer_id: i32,
+    pub name: String,
+    pub kind: String,
+    pub token_prefix: String,
+    pub token_hash: String,
+    pub scopes: String,
+    pub expires_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "api_token"]
+pub struct AddApiToken {
+    pub customer_id: i32,
+    pub name: String,
+    pub kind: String,
+    pub token_prefix: String,
+    pub token_hash: String,
+    pub scopes: String,
+    pub expires_at: NaiveDateTime,
+}
@@ -0,0 +1,188 @@
+//! Outgoing notifications are written to the `notification_outbox` table in
+//! the same transaction as the change that caused them, and delivered from
+//! there separately by `deliver_pending`.
+//!
+//! Bodies with a one-time token in them are blanked once delivered, or once
+//! delivery is given up on, so the table never holds a usable token for
+//! longer than it takes to send it.
+
+use crate::last_insert_id;
+use crate::mailer::Mailer;
+use crate::models::{AddNotification, Notification};
+use crate::schema::notification_outbox::dsl::*;
+use crate::tokens::{EMAIL_VERIFICATION, PASSWORD_RESET};
+
+use chrono::naive::NaiveDateTime;
+use chrono::Duration;
+use diesel::dsl::now;
+use diesel::prelude::*;
+use log::error;
+
+const DELIVERY_BATCH: i64 = 100;
+/// Failed deliveries are given up on after this many attempts.
+const MAX_ATTEMPTS: i32 = 8;
+const REDACTED_BODY: &str = "[removed after delivery]";
+
+/// Base url of the storefront, for links in notifications.
+pub fn store_url() -> String {
+    std::env::var("FURBY_STORE_URL")
+        .unwrap_or_else(|_| String::from("http://127.0.0.1:8000"))
+}
+
+/// Queues a notification and returns its id.
+pub fn queue(
+    conn: &MysqlConnection,
+    notification: AddNotification,
+) -> QueryResult<i32> {
+    diesel::insert_into(notification_outbox)
+        .values(notification)
+        .execute(conn)?;
+    Ok(diesel::select(last_insert_id).first::<u64>(conn)? as i32)
+}
+
+/// Sends queued notifications that are due through `mailer`, oldest first,
+/// and returns how many went out. Ones that fail are retried later, backing
+/// off after each attempt, and given up on after `MAX_ATTEMPTS` so they
+/// can't hold up newer mail.
+pub fn deliver_pending(
+    conn: &MysqlConnection,
+    mailer: &dyn Mailer,
+) -> QueryResult<usize> {
+    let started = diesel::select(now).first::<NaiveDateTime>(conn)?;
+    let pending = notification_outbox
+        .filter(sent_at.is_null())
+        .filter(attempts.lt(MAX_ATTEMPTS))
+        .filter(next_attempt_at.is_null().or(next_attempt_at.le(started)))
+        .order(id.asc())
+        .limit(DELIVERY_BATCH)
+        .load::<Notification>(conn)?;
+    let mut delivered = 0;
+    for n in pending {
+        let secret = n.kind == PASSWORD_RESET || n.kind == EMAIL_VERIFICATION;
+        let sent = mailer.send(AddNotification {
+            customer_id: n.customer_id,
+            kind: n.kind,
+            recipient: n.recipient,
+            subject: n.subject,
+            body: n.body,
+        });
+        let at = diesel::select(now).first::<NaiveDateTime>(conn)?;
+        if let Err(e) = sent {
+            let tried = n.attempts + 1;
+            let target = notification_outbox.find(n.id);
+            let failed = (
+                attempts.eq(tried),
+                last_error.eq(e.to_string()),
+                next_attempt_at.eq(at + retry_delay(tried)),
+            );
+            if tried < MAX_ATTEMPTS {
+                error!("Unable to deliver notification {}: {}", n.id, e);
+                diesel::update(target).set(failed).execute(conn)?;
+            } else {
+                error!("Giving up on notification {}: {}", n.id, e);
+                diesel::update(target).set(failed).execute(conn)?;
+                if secret {
+                    diesel::update(target)
+                        .set(body.eq(REDACTED_BODY))
+                        .execute(conn)?;
+                }
+            }
+            continue;
+        }
+        if secret {
+            diesel::update(notification_outbox.find(n.id))
+                .set((sent_at.eq(at), body.eq(REDACTED_BODY)))
+                .execute(conn)?;
+        } else {
+            diesel::update(notification_outbox.find(n.id))
+                .set(sent_at.eq(at))
+                .execute(conn)?;
+        }
+        delivered += 1;
+    }
+    Ok(delivered)
+}
+
+/// How long to wait before trying a notification again after `tried`
+/// failed attempts: a minute, doubling up to a day.
+fn retry_delay(tried: i32) -> Duration {
+    let minutes = 1i64 << (tried - 1).clamp(0, 11);
+    Duration::minutes(minutes).min(Duration::days(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deliver_pending, queue, retry_delay};
+    use super::{DELIVERY_BATCH, MAX_ATTEMPTS};
+    use crate::mailer::{MailError, Mailer};
+    use crate::models::{AddNotification, Notification};
+    use crate::schema::notification_outbox::dsl as no;
+
+    use chrono::Duration;
+    use diesel::prelude::*;
+
+    use std::cell::RefCell;
+
+    /// Fails for one recipient, records the others.
+    struct Bouncing {
+        sent: RefCell<Vec<String>>,
+    }
+
+    impl Mailer for Bouncing {
+        fn send(&self, mail: AddNotification) -> Result<(), MailError> {
+            if mail.recipient == "bounces@example.com" {
+                return Err(MailError::Rejected(String::from("no such user")));
+            }
+            self.sent.borrow_mut().push(mail.recipient);
+            Ok(())
+        }
+    }
+
+    fn mail_to(to: &str) -> AddNotification {
+        AddNotification {
+            customer_id: None,
+            kind: String::from("test"),
+            recipient: to.to_string(),
+            subject: String::from("Hello"),
+            body: String::from("Hello there"),
+        }
+    }
+
+    #[test]
+    fn failing_mail_does_not_hold_up_newer_mail() {
+        let conn = MysqlConnection::establish(env!("DATABASE_URL")).unwrap();
+        conn.begin_test_transaction().unwrap();
+        // leave whatever is queued already out of it
+        diesel::update(no::notification_outbox.filter(no::sent_at.is_null()))
+            .set(no::attempts.eq(MAX_ATTEMPTS))
+            .execute(&conn)
+            .unwrap();
+        for _ in 0..DELIVERY_BATCH {
+            queue(&conn, mail_to("bounces@example.com")).unwrap();
+        }
+        let newer = queue(&conn, mail_to("someone@example.com")).unwrap();
+        let mailer = Bouncing {
+            sent: RefCell::new(vec![]),
+        };
+
+        // a full batch of failures, the newer mail doesn't fit in
+        assert_eq!(deliver_pending(&conn, &mailer).unwrap(), 0);
+        // the failures now wait for their retry
+        assert_eq!(deliver_pending(&conn, &mailer).unwrap(), 1);
+        assert_eq!(*mailer.sent.borrow(), ["someone@example.com"]);
+        let delivered = no::notification_outbox
+            .find(newer)
+            .first::<Notification>(&conn)
+            .unwrap();
+        assert!(delivered.sent_at.is_some());
+    }
+
+    #[test]
+    fn retries_back_off_up_to_a_day() {
+        assert_eq!(retry_delay(1), Duration::minutes(1));
+        assert_eq!(retry_delay(2), Duration::minutes(2));
+        assert_eq!(retry_delay(5), Duration::minutes(16));
+        assert_eq!(retry_delay(MAX_ATTEMPTS), Duration::minutes(128));
+        assert_eq!(retry_delay(30), Duration::days(1));
+    }
+}
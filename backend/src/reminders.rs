@@ -0,0 +1,174 @@
+//! Reminders for abandoned carts. A cart counts as abandoned once it has
+//! not been changed for `FURBY_ABANDONED_CART_HOURS`, and gets at most
+//! `FURBY_CART_REMINDERS` reminders, spaced by the same period, until it
+//! is changed again. Orders placed within `FURBY_CART_RECOVERY_DAYS` of a
+//! reminder count as recovered by it.
+
+use crate::invoice::currency;
+use crate::models::{AddCartReminder, AddNotification, Cart, Customer};
+use crate::notifications::{self, store_url};
+use crate::schema::cart::dsl::*;
+use crate::schema::cart_items::dsl as ci;
+use crate::schema::cart_reminder::dsl as cr;
+use crate::schema::customer::dsl as cust;
+use crate::schema::product::dsl as prod;
+use crate::schema::transaction::dsl as ts;
+
+use chrono::naive::NaiveDateTime;
+use chrono::Duration;
+use diesel::dsl::now;
+use diesel::prelude::*;
+use log::info;
+use serde::Serialize;
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn abandoned_after() -> Duration {
+    Duration::hours(env_or("FURBY_ABANDONED_CART_HOURS", 24))
+}
+
+fn max_reminders() -> i32 {
+    env_or("FURBY_CART_REMINDERS", 2)
+}
+
+fn recovery_window() -> Duration {
+    Duration::days(env_or("FURBY_CART_RECOVERY_DAYS", 7))
+}
+
+fn db_now(conn: &MysqlConnection) -> QueryResult<NaiveDateTime> {
+    diesel::select(now).first(conn)
+}
+
+fn reminder_body(
+    member: &Customer,
+    lines: &[(String, Option<i32>, f32)],
+) -> String {
+    let mut body = format!(
+        "Hi {},\n\nYou left these in your cart:\n\n",
+        member.username
+    );
+    let mut total = 0.;
+    for (name, q, price) in lines {
+        let q = q.unwrap_or(1);
+        total += q as f32 * price;
+        body.push_str(&format!(
+            "  {} x {} ({:.2} {})\n",
+            q,
+            name,
+            price,
+            currency()
+        ));
+    }
+    body.push_str(&format!(
+        "\nTotal: {:.2} {}\n\nPick up where you left off: {}/cart\n",
+        total,
+        currency(),
+        store_url()
+    ));
+    body
+}
+
+/// Queues a reminder for every abandoned cart that is due one and returns
+/// how many were queued. Empty carts are never reminded.
+pub fn queue_cart_reminders(conn: &MysqlConnection) -> QueryResult<usize> {
+    let current = db_now(conn)?;
+    let cutoff = current - abandoned_after();
+    let due = cart
+        .filter(updated_at.lt(cutoff))
+        .filter(reminders_sent.lt(max_reminders()))
+        .filter(last_reminded_at.is_null().or(last_reminded_at.lt(cutoff)))
+        .load::<Cart>(conn)?;
+    let mut queued = 0;
+    for c in due {
+        let lines = ci::cart_items
+            .inner_join(prod::product)
+            .filter(ci::cart_id.eq(c.customer_id))
+            .select((prod::name, ci::quantity, prod::price))
+            .load::<(String, Option<i32>, f32)>(conn)?;
+        if lines.is_empty() {
+            continue;
+        }
+        let member =
+            cust::customer.find(c.customer_id).first::<Customer>(conn)?;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            let nid = notifications::queue(
+                conn,
+                AddNotification {
+                    customer_id: Some(member.id),
+                    kind: String::from("cart_reminder"),
+                    recipient: member.email_id.clone(),
+                    subject: String::from("You left something in your cart"),
+                    body: reminder_body(&member, &lines),
+                },
+            )?;
+            diesel::insert_into(cr::cart_reminder)
+                .values(AddCartReminder {
+                    customer_id: member.id,
+                    notification_id: nid,
+                })
+                .execute(conn)?;
+            diesel::update(cart.find(member.id))
+                .set((
+                    reminders_sent.eq(reminders_sent + 1),
+                    last_reminded_at.eq(current),
+                ))
+                .execute(conn)?;
+            Ok(())
+        })?;
+        info!("Queued cart reminder for {}", member.username);
+        queued += 1;
+    }
+    Ok(queued)
+}
+
+/// Credits order `tid` of customer `cid` to the reminders sent to them
+/// within the recovery window that have not recovered an order yet.
+pub fn mark_recovered(
+    conn: &MysqlConnection,
+    cid: i32,
+    tid: i32,
+) -> QueryResult<usize> {
+    let since = db_now(conn)? - recovery_window();
+    diesel::update(
+        cr::cart_reminder
+            .filter(cr::customer_id.eq(cid))
+            .filter(cr::recovered_transaction_id.is_null())
+            .filter(cr::sent_at.ge(since)),
+    )
+    .set(cr::recovered_transaction_id.eq(tid))
+    .execute(conn)
+}
+
+#[derive(Serialize)]
+pub struct ReminderStats {
+    pub reminders_sent: i64,
+    pub orders_recovered: i64,
+    pub revenue_recovered: f32,
+}
+
+pub fn reminder_stats(conn: &MysqlConnection) -> QueryResult<ReminderStats> {
+    let sent = cr::cart_reminder.count().get_result(conn)?;
+    let mut recovered = cr::cart_reminder
+        .filter(cr::recovered_transaction_id.is_not_null())
+        .select(cr::recovered_transaction_id)
+        .load::<Option<i32>>(conn)?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    recovered.sort_unstable();
+    recovered.dedup();
+    let amounts = ts::transaction
+        .filter(ts::id.eq_any(&recovered))
+        .select(ts::amount)
+        .load::<f32>(conn)?;
+    Ok(ReminderStats {
+        reminders_sent: sent,
+        orders_recovered: recovered.len() as i64,
+        revenue_recovered: amounts.into_iter().sum(),
+    })
+}
@@ -1,8 +1,56 @@
+table! {
+    api_token (id) {
+        id -> Integer,
+        customer_id -> Integer,
+        name -> Varchar,
+        kind -> Varchar,
+        token_prefix -> Varchar,
+        token_hash -> Char,
+        scopes -> Varchar,
+        expires_at -> Datetime,
+        last_used_at -> Nullable<Datetime>,
+        revoked_at -> Nullable<Datetime>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    audit_log (id) {
+        id -> Integer,
+        event -> Varchar,
+        customer_id -> Nullable<Integer>,
+        username -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        detail -> Nullable<Text>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    cart (customer_id) {
+        customer_id -> Integer,
+        updated_at -> Datetime,
+        reminders_sent -> Integer,
+        last_reminded_at -> Nullable<Datetime>,
+    }
+}
+
 table! {
     cart_items (cart_id, product_id) {
         cart_id -> Integer,
         product_id -> Integer,
         quantity -> Nullable<Integer>,
+        unit_price -> Nullable<Float>,
+    }
+}
+
+table! {
+    cart_reminder (id) {
+        id -> Integer,
+        customer_id -> Integer,
+        notification_id -> Integer,
+        sent_at -> Datetime,
+        recovered_transaction_id -> Nullable<Integer>,
     }
 }
 
@@ -14,6 +62,64 @@ table! {
         phone_number -> Varchar,
         email_id -> Varchar,
         address -> Nullable<Text>,
+        role -> Varchar,
+        email_verified -> Bool,
+        deleted_at -> Nullable<Datetime>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    customer_totp (customer_id) {
+        customer_id -> Integer,
+        secret -> Varchar,
+        confirmed_at -> Nullable<Datetime>,
+        last_used_step -> Nullable<Bigint>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    guest_cart_items (guest_id, product_id) {
+        guest_id -> Varchar,
+        product_id -> Integer,
+        quantity -> Integer,
+        created_at -> Datetime,
+        unit_price -> Nullable<Float>,
+    }
+}
+
+table! {
+    invoice (id) {
+        id -> Integer,
+        transaction_id -> Integer,
+        issued_at -> Datetime,
+    }
+}
+
+table! {
+    notification_outbox (id) {
+        id -> Integer,
+        customer_id -> Nullable<Integer>,
+        kind -> Varchar,
+        recipient -> Varchar,
+        subject -> Varchar,
+        body -> Text,
+        created_at -> Datetime,
+        sent_at -> Nullable<Datetime>,
+        attempts -> Integer,
+        last_error -> Nullable<Text>,
+        next_attempt_at -> Nullable<Datetime>,
+    }
+}
+
+table! {
+    payment_event (id) {
+        id -> Varchar,
+        event_type -> Varchar,
+        transaction_id -> Nullable<Integer>,
+        payload -> Text,
+        received_at -> Datetime,
     }
 }
 
@@ -26,6 +132,9 @@ table! {
         description -> Nullable<Varchar>,
         src -> Nullable<Text>,
         ios_src -> Nullable<Text>,
+        stock -> Nullable<Integer>,
+        max_order_quantity -> Nullable<Integer>,
+        archived -> Bool,
     }
 }
 
@@ -37,6 +146,117 @@ table! {
         product_id -> Nullable<Integer>,
         customer_id -> Nullable<Integer>,
         stars -> Nullable<Integer>,
+        verified_purchase -> Bool,
+        edited_at -> Nullable<Datetime>,
+        status -> Varchar,
+        flag_reason -> Nullable<Varchar>,
+        moderated_by -> Nullable<Integer>,
+        moderated_at -> Nullable<Datetime>,
+        helpful_votes -> Integer,
+        not_helpful_votes -> Integer,
+    }
+}
+
+table! {
+    rating_duplicate (id) {
+        id -> Integer,
+        comment_text -> Nullable<Text>,
+        comment_date -> Nullable<Date>,
+        product_id -> Nullable<Integer>,
+        customer_id -> Nullable<Integer>,
+        stars -> Nullable<Integer>,
+    }
+}
+
+table! {
+    rating_photo (id) {
+        id -> Integer,
+        rating_id -> Integer,
+        storage_key -> Varchar,
+        thumbnail_key -> Varchar,
+        content_type -> Varchar,
+        size -> Integer,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    rating_report (id) {
+        id -> Integer,
+        rating_id -> Integer,
+        customer_id -> Integer,
+        reason -> Varchar,
+        note -> Nullable<Text>,
+        resolved -> Bool,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    rating_vote (rating_id, customer_id) {
+        rating_id -> Integer,
+        customer_id -> Integer,
+        helpful -> Bool,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    recovery_code (id) {
+        id -> Integer,
+        customer_id -> Integer,
+        code_hash -> Char,
+        used_at -> Nullable<Datetime>,
+    }
+}
+
+table! {
+    refund (id) {
+        id -> Integer,
+        transaction_id -> Integer,
+        amount -> Float,
+        status -> Varchar,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    return_event (id) {
+        id -> Integer,
+        return_id -> Integer,
+        status -> Varchar,
+        note -> Nullable<Text>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    return_item (return_id, transaction_item_id) {
+        return_id -> Integer,
+        transaction_item_id -> Integer,
+        quantity -> Integer,
+        reason -> Text,
+    }
+}
+
+table! {
+    return_request (id) {
+        id -> Integer,
+        transaction_id -> Integer,
+        customer_id -> Integer,
+        status -> Varchar,
+        refund_amount -> Nullable<Float>,
+        refund_id -> Nullable<Integer>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    site_setting (name) {
+        name -> Varchar,
+        value -> Varchar,
+        updated_by -> Nullable<Integer>,
+        updated_at -> Datetime,
     }
 }
 
@@ -47,19 +267,118 @@ table! {
         amount -> Float,
         customer_id -> Nullable<Integer>,
         order_date -> Date,
+        payment_status -> Varchar,
+        fulfilment_status -> Varchar,
+    }
+}
+
+table! {
+    transaction_item (id) {
+        id -> Integer,
+        transaction_id -> Integer,
+        product_id -> Integer,
+        quantity -> Integer,
+        unit_price -> Float,
+        cancelled_quantity -> Integer,
+    }
+}
+
+table! {
+    user_token (id) {
+        id -> Integer,
+        customer_id -> Integer,
+        purpose -> Varchar,
+        token_hash -> Char,
+        expires_at -> Datetime,
+        used_at -> Nullable<Datetime>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    wishlist (id) {
+        id -> Integer,
+        customer_id -> Integer,
+        name -> Varchar,
+        is_default -> Bool,
+        share_token -> Nullable<Varchar>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    wishlist_item (wishlist_id, product_id) {
+        wishlist_id -> Integer,
+        product_id -> Integer,
+        quantity -> Integer,
+        added_at -> Datetime,
     }
 }
 
+joinable!(api_token -> customer (customer_id));
+joinable!(audit_log -> customer (customer_id));
+joinable!(cart -> customer (customer_id));
 joinable!(cart_items -> customer (cart_id));
 joinable!(cart_items -> product (product_id));
+joinable!(cart_reminder -> customer (customer_id));
+joinable!(cart_reminder -> notification_outbox (notification_id));
+joinable!(cart_reminder -> transaction (recovered_transaction_id));
+joinable!(customer_totp -> customer (customer_id));
+joinable!(guest_cart_items -> product (product_id));
+joinable!(invoice -> transaction (transaction_id));
+joinable!(notification_outbox -> customer (customer_id));
+joinable!(payment_event -> transaction (transaction_id));
 joinable!(rating -> customer (customer_id));
 joinable!(rating -> product (product_id));
+joinable!(rating_photo -> rating (rating_id));
+joinable!(rating_report -> customer (customer_id));
+joinable!(rating_report -> rating (rating_id));
+joinable!(rating_vote -> customer (customer_id));
+joinable!(rating_vote -> rating (rating_id));
+joinable!(recovery_code -> customer (customer_id));
+joinable!(refund -> transaction (transaction_id));
+joinable!(return_event -> return_request (return_id));
+joinable!(return_item -> return_request (return_id));
+joinable!(return_item -> transaction_item (transaction_item_id));
+joinable!(return_request -> customer (customer_id));
+joinable!(return_request -> refund (refund_id));
+joinable!(return_request -> transaction (transaction_id));
+joinable!(site_setting -> customer (updated_by));
 joinable!(transaction -> customer (customer_id));
+joinable!(transaction_item -> product (product_id));
+joinable!(transaction_item -> transaction (transaction_id));
+joinable!(user_token -> customer (customer_id));
+joinable!(wishlist -> customer (customer_id));
+joinable!(wishlist_item -> product (product_id));
+joinable!(wishlist_item -> wishlist (wishlist_id));
 
 allow_tables_to_appear_in_same_query!(
+    api_token,
+    audit_log,
+    cart,
     cart_items,
+    cart_reminder,
     customer,
+    customer_totp,
+    guest_cart_items,
+    invoice,
+    notification_outbox,
+    payment_event,
     product,
     rating,
+    rating_duplicate,
+    rating_photo,
+    rating_report,
+    rating_vote,
+    recovery_code,
+    refund,
+    return_event,
+    return_item,
+    return_request,
+    site_setting,
     transaction,
+    transaction_item,
+    user_token,
+    wishlist,
+    wishlist_item,
 );
@@ -0,0 +1,275 @@
+//! Login sessions. The identity cookie carries the username together with a
+//! session id, and the ids a user is logged in with are kept in Redis under
+//! `sessions:<username>`. Handlers still only ever see the username, but
+//! a cookie whose session is gone from Redis no longer logs anyone in, so
+//! sessions can be ended from the server side.
+//!
+//! Accounts with two-factor authentication first get a pending login,
+//! which only lets them send their second factor.
+//!
+//! Requests with an `Authorization: Bearer` API token are logged in as the
+//! token's owner for just that request, see `api_tokens`.
+//!
+//! How cookies are set depends on the environment: with `FURBY_ENV` set to
+//! `production` they are only sent over https.
+
+use crate::api_tokens::{self, Refusal};
+use crate::TPool;
+
+use actix_identity::{CookieIdentityPolicy, IdentityPolicy};
+use actix_web::cookie::{CookieBuilder, SameSite};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{
+    Error, ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized,
+};
+use actix_web::http::{header, Cookie};
+use actix_web::{web, HttpMessage, HttpRequest};
+use futures::future::{ready, Ready};
+use log::warn;
+use rand::Rng;
+use redis::{Commands, RedisResult};
+
+const SEPARATOR: char = '|';
+const LOGIN_COOKIE: &str = "user-login";
+
+/// Whether this is a production deployment, from `FURBY_ENV`.
+pub fn production() -> bool {
+    std::env::var("FURBY_ENV").as_deref() == Ok("production")
+}
+
+/// `FURBY_COOKIE_SAME_SITE`, `lax` unless set to `strict` or `none`. `none`
+/// lets a storefront on another site log in, and only works over https.
+fn same_site() -> SameSite {
+    match std::env::var("FURBY_COOKIE_SAME_SITE").as_deref() {
+        Ok("strict") => SameSite::Strict,
+        Ok("none") if production() => SameSite::None,
+        Ok("none") => {
+            warn!("SameSite=None cookies need https, using Lax instead");
+            SameSite::Lax
+        }
+        _ => SameSite::Lax,
+    }
+}
+
+/// Applies the environment's cookie settings to a cookie being set.
+pub fn harden(cookie: CookieBuilder<'static>) -> CookieBuilder<'static> {
+    cookie
+        .http_only(true)
+        .secure(production())
+        .same_site(same_site())
+}
+
+/// Policy for the login cookie, signed with `key`. The cookie is sent to
+/// the host that set it only, or to `FURBY_COOKIE_DOMAIN` if that is set.
+pub fn login_cookie_policy(key: &[u8]) -> CookieIdentityPolicy {
+    let policy = CookieIdentityPolicy::new(key)
+        .name(LOGIN_COOKIE)
+        .path("/")
+        .same_site(same_site())
+        .http_only(true)
+        .secure(production());
+    match std::env::var("FURBY_COOKIE_DOMAIN") {
+        Ok(d) => policy.domain(d),
+        Err(_) => policy,
+    }
+}
+
+fn redis_conn() -> RedisResult<redis::Connection> {
+    redis::Client::open("redis://127.0.0.1/")?.get_connection()
+}
+
+fn sessions_key(uname: &str) -> String {
+    format!("sessions:{}", uname)
+}
+
+/// Logs `uname` out everywhere.
+pub fn end_all_sessions(uname: &str) -> RedisResult<()> {
+    redis_conn()?.del(sessions_key(uname))
+}
+
+/// Logs `uname` out everywhere except in the session `req` was made with.
+pub fn end_other_sessions(req: &HttpRequest, uname: &str) -> RedisResult<()> {
+    let current = req
+        .extensions()
+        .get::<SessionCookie>()
+        .and_then(|c| c.0.rfind(SEPARATOR).map(|i| c.0[i + 1..].to_string()));
+    let key = sessions_key(uname);
+    let mut pipe = redis::pipe();
+    pipe.atomic().del(&key).ignore();
+    if let Some(sid) = current {
+        pipe.sadd(&key, sid).ignore();
+    }
+    pipe.query(&mut redis_conn()?)
+}
+
+/// Raw cookie value of the current request, kept so the session can be
+/// ended on logout.
+struct SessionCookie(String);
+
+/// Marks requests made with an API token, which never get a cookie.
+struct TokenRequest;
+
+fn bearer_token(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let token = value.strip_prefix("Bearer ")?;
+    Some(token.trim().to_string())
+}
+
+pub struct SessionPolicy {
+    cookie: CookieIdentityPolicy,
+}
+
+impl SessionPolicy {
+    pub fn new(cookie: CookieIdentityPolicy) -> Self {
+        SessionPolicy { cookie }
+    }
+
+    fn load_token(
+        &self,
+        req: &mut ServiceRequest,
+        token: &str,
+    ) -> Result<Option<String>, Error> {
+        let pool = req
+            .app_data::<web::Data<TPool>>()
+            .expect("Database pool is registered");
+        let conn = pool.get().map_err(ErrorInternalServerError)?;
+        let method = req.method().as_str().to_string();
+        match api_tokens::authenticate(&conn, token, &method, req.path()) {
+            Ok(uname) => {
+                req.extensions_mut().insert(TokenRequest);
+                Ok(Some(uname))
+            }
+            Err(Refusal::Invalid) => Err(ErrorUnauthorized(
+                "API token is invalid, expired or revoked",
+            )),
+            Err(Refusal::OutOfScope) => {
+                Err(ErrorForbidden("API token doesn't cover this request"))
+            }
+            Err(Refusal::Db(e)) => Err(ErrorInternalServerError(e)),
+        }
+    }
+
+    fn load(&self, req: &mut ServiceRequest) -> Result<Option<String>, Error> {
+        if let Some(token) = bearer_token(req) {
+            return self.load_token(req, &token);
+        }
+        let value = match self.cookie.from_request(req).into_inner()? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let (uname, sid) = match value.rfind(SEPARATOR) {
+            Some(i) => (&value[..i], &value[i + 1..]),
+            None => return Ok(None),
+        };
+        let active: bool = redis_conn()
+            .and_then(|mut r| r.sismember(sessions_key(uname), sid))
+            .map_err(ErrorInternalServerError)?;
+        if !active {
+            return Ok(None);
+        }
+        let uname = uname.to_string();
+        req.extensions_mut().insert(SessionCookie(value));
+        Ok(Some(uname))
+    }
+
+    fn store<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        res: &mut ServiceResponse<B>,
+    ) -> Result<(), Error> {
+        if res.request().extensions().get::<TokenRequest>().is_some() {
+            return Ok(());
+        }
+        let current = res
+            .request()
+            .extensions()
+            .get::<SessionCookie>()
+            .map(|c| c.0.clone());
+        if !changed {
+            return self.cookie.to_response(current, false, res).into_inner();
+        }
+        let mut r = redis_conn().map_err(ErrorInternalServerError)?;
+        if let Some((uname, sid)) = current
+            .as_ref()
+            .and_then(|c| c.rfind(SEPARATOR).map(|i| c.split_at(i)))
+        {
+            let _: () = r
+                .srem(sessions_key(uname), &sid[1..])
+                .map_err(ErrorInternalServerError)?;
+        }
+        let value = match identity {
+            Some(uname) => {
+                let sid = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+                let _: () = r
+                    .sadd(sessions_key(&uname), &sid)
+                    .map_err(ErrorInternalServerError)?;
+                Some(format!("{}{}{}", uname, SEPARATOR, sid))
+            }
+            None => None,
+        };
+        self.cookie.to_response(value, true, res).into_inner()
+    }
+}
+
+impl IdentityPolicy for SessionPolicy {
+    type Future = Ready<Result<Option<String>, Error>>;
+    type ResponseFuture = Ready<Result<(), Error>>;
+
+    fn from_request(&self, req: &mut ServiceRequest) -> Self::Future {
+        ready(self.load(req))
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        res: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        ready(self.store(identity, changed, res))
+    }
+}
+
+/// Cookie of a login that still needs its second factor.
+pub const PENDING_LOGIN_COOKIE: &str = "furby-2fa";
+const PENDING_LOGIN_SECONDS: usize = 300;
+
+fn pending_key(token: &str) -> String {
+    format!("login_pending:{}", token)
+}
+
+/// Remembers that `uname` got their password right, and returns the
+/// cookie to finish the login with.
+pub fn start_pending_login(uname: &str) -> RedisResult<Cookie<'static>> {
+    let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+    let _: () = redis_conn()?.set_ex(
+        pending_key(&token),
+        uname,
+        PENDING_LOGIN_SECONDS,
+    )?;
+    Ok(harden(Cookie::build(PENDING_LOGIN_COOKIE, token))
+        .path("/user/login")
+        .finish())
+}
+
+/// Username of the pending login `req` belongs to.
+pub fn pending_login(req: &HttpRequest) -> RedisResult<Option<String>> {
+    match req.cookie(PENDING_LOGIN_COOKIE) {
+        Some(c) => redis_conn()?.get(pending_key(c.value())),
+        None => Ok(None),
+    }
+}
+
+pub fn end_pending_login(req: &HttpRequest) -> RedisResult<()> {
+    match req.cookie(PENDING_LOGIN_COOKIE) {
+        Some(c) => redis_conn()?.del(pending_key(c.value())),
+        None => Ok(()),
+    }
+}
+
+/// Bare pending login cookie, to drop it with `del_cookie`.
+pub fn pending_login_stub() -> Cookie<'static> {
+    Cookie::build(PENDING_LOGIN_COOKIE, "")
+        .path("/user/login")
+        .finish()
+}
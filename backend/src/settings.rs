@@ -0,0 +1,40 @@
+//! Settings admins can change while the store runs, kept in the
+//! `site_setting` table.
+
+use crate::models::AddSiteSetting;
+use crate::schema::site_setting::dsl::*;
+
+use diesel::prelude::*;
+
+/// Whether staff and admin accounts need two-factor authentication to use
+/// their staff powers, `true` or `false`.
+pub const REQUIRE_STAFF_2FA: &str = "require_staff_2fa";
+
+pub const KNOWN: &[&str] = &[REQUIRE_STAFF_2FA];
+
+pub fn get(conn: &MysqlConnection, key: &str) -> QueryResult<Option<String>> {
+    site_setting
+        .find(key)
+        .select(value)
+        .first::<String>(conn)
+        .optional()
+}
+
+pub fn set(
+    conn: &MysqlConnection,
+    key: &str,
+    new_value: &str,
+    admin_id: i32,
+) -> QueryResult<usize> {
+    diesel::replace_into(site_setting)
+        .values(AddSiteSetting {
+            name: key.to_string(),
+            value: new_value.to_string(),
+            updated_by: Some(admin_id),
+        })
+        .execute(conn)
+}
+
+pub fn flag(conn: &MysqlConnection, key: &str) -> QueryResult<bool> {
+    Ok(get(conn, key)?.as_deref() == Some("true"))
+}
@@ -0,0 +1,52 @@
+//! Uploaded files are kept on the local disk under `FURBY_STORAGE_DIR`
+//! and served by the server below `FURBY_STORAGE_URL`. Files are addressed
+//! by keys, paths relative to the storage directory.
+
+use rand::Rng;
+
+use std::path::PathBuf;
+use std::{fs, io};
+
+#[derive(Clone)]
+pub struct LocalStorage {
+    pub root: PathBuf,
+    pub base_url: String,
+}
+
+impl LocalStorage {
+    pub fn from_env() -> Self {
+        LocalStorage {
+            root: std::env::var("FURBY_STORAGE_DIR")
+                .unwrap_or_else(|_| String::from("uploads"))
+                .into(),
+            base_url: std::env::var("FURBY_STORAGE_URL")
+                .unwrap_or_else(|_| String::from("/uploads")),
+        }
+    }
+
+    /// A fresh key below `prefix`, ending in `extension`.
+    pub fn new_key(prefix: &str, extension: &str) -> String {
+        let name = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+        format!("{}/{}.{}", prefix, name, extension)
+    }
+
+    pub fn save(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+        let path = self.root.join(key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, bytes)
+    }
+
+    /// Removes a file, ignoring files that are already gone.
+    pub fn remove(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.root.join(key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), key)
+    }
+}
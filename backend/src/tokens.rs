@@ -0,0 +1,83 @@
+//! Single use tokens mailed to customers, for password resets and email
+//! verification. Only a hash of each token is stored, so a leaked table
+//! can't be used to act on anyone's behalf. The mail itself is blanked in
+//! the outbox once delivered, see `notifications`.
+
+use crate::models::{AddUserToken, UserToken};
+use crate::schema::user_token::dsl::*;
+
+use chrono::naive::NaiveDateTime;
+use chrono::Duration;
+use diesel::dsl::now;
+use diesel::prelude::*;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+pub const PASSWORD_RESET: &str = "password_reset";
+pub const EMAIL_VERIFICATION: &str = "email_verification";
+
+fn hash_of(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Creates a token for `purpose` that expires after `minutes`, and returns
+/// it. This is the only time the token itself is around.
+pub fn issue(
+    conn: &MysqlConnection,
+    cid: i32,
+    token_purpose: &str,
+    minutes: i64,
+) -> QueryResult<String> {
+    let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+    let issued_at = diesel::select(now).first::<NaiveDateTime>(conn)?;
+    diesel::insert_into(user_token)
+        .values(AddUserToken {
+            customer_id: cid,
+            purpose: token_purpose.to_string(),
+            token_hash: hash_of(&token),
+            expires_at: issued_at + Duration::minutes(minutes),
+        })
+        .execute(conn)?;
+    Ok(token)
+}
+
+/// Marks an unused and unexpired `token` for `purpose` as used, and returns
+/// it. Call within a transaction, together with whatever the token allows.
+pub fn redeem(
+    conn: &MysqlConnection,
+    token: &str,
+    token_purpose: &str,
+) -> QueryResult<Option<UserToken>> {
+    let used_now = diesel::select(now).first::<NaiveDateTime>(conn)?;
+    let found = user_token
+        .filter(token_hash.eq(hash_of(token.trim())))
+        .filter(purpose.eq(token_purpose))
+        .filter(used_at.is_null())
+        .filter(expires_at.gt(used_now))
+        .for_update()
+        .first::<UserToken>(conn)
+        .optional()?;
+    if let Some(t) = &found {
+        diesel::update(user_token.find(t.id))
+            .set(used_at.eq(used_now))
+            .execute(conn)?;
+    }
+    Ok(found)
+}
+
+/// Retires the outstanding tokens of a customer for `purpose`.
+pub fn revoke(
+    conn: &MysqlConnection,
+    cid: i32,
+    token_purpose: &str,
+) -> QueryResult<usize> {
+    let revoked_at = diesel::select(now).first::<NaiveDateTime>(conn)?;
+    diesel::update(
+        user_token
+            .filter(customer_id.eq(cid))
+            .filter(purpose.eq(token_purpose))
+            .filter(used_at.is_null()),
+    )
+    .set(used_at.eq(revoked_at))
+    .execute(conn)
+}
@@ -0,0 +1,103 @@
+//! Time based one-time passwords (RFC 6238) as used by authenticator apps:
+//! six digits from HMAC-SHA1 over 30 second steps.
+
+use base32::Alphabet;
+use hmac::{Hmac, Mac, NewMac};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rand::Rng;
+use sha1::Sha1;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha1 = Hmac<Sha1>;
+
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+/// Steps either side of the current one that are still accepted, to allow
+/// for clock drift and slow typing.
+const SKEW: u64 = 1;
+const ISSUER: &str = "Furby";
+
+const BASE32: Alphabet = Alphabet::RFC4648 { padding: false };
+
+/// A new random secret, base32 encoded the way authenticator apps want it.
+pub fn new_secret() -> String {
+    base32::encode(BASE32, &rand::thread_rng().gen::<[u8; 20]>())
+}
+
+pub fn current_step() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock is before 1970")
+        .as_secs()
+        / STEP_SECONDS
+}
+
+fn code_at(key: &[u8], step: u64) -> u32 {
+    let mut mac = HmacSha1::new_varkey(key).expect("HMAC takes any key");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let truncated = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// The step `code` is valid for, close to `step`. Steps up to `last_used`
+/// are refused so that a code can't be used twice.
+pub fn verify(
+    secret: &str,
+    code: &str,
+    step: u64,
+    last_used: Option<u64>,
+) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize
+        || !code.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    let key = base32::decode(BASE32, secret)?;
+    (step.saturating_sub(SKEW)..=step + SKEW)
+        .filter(|s| Some(*s) > last_used)
+        .find(|s| code_at(&key, *s) == code)
+}
+
+fn uri_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Key uri that authenticator apps import, usually from a QR code.
+pub fn otpauth_uri(secret: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}\
+         &algorithm=SHA1&digits={digits}&period={period}",
+        issuer = uri_encode(ISSUER),
+        account = uri_encode(account),
+        secret = secret,
+        digits = DIGITS,
+        period = STEP_SECONDS
+    )
+}
+
+/// `uri` as an SVG QR code.
+pub fn qr_code_svg(uri: &str) -> String {
+    QrCode::new(uri.as_bytes())
+        .expect("otpauth uri fits in a QR code")
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build()
+}
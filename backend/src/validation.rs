@@ -0,0 +1,118 @@
+//! Checks for customer supplied account data. Problems are collected per
+//! field so a form can point at everything that needs fixing at once, and
+//! are answered with 422:
+//!
+//!     {"errors": {"phone_number": ["must be 10 digits"]}}
+
+use actix_web::HttpResponse;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+
+pub const MAX_ADDRESS_CHARS: usize = 500;
+
+#[derive(Serialize, Default, Debug)]
+pub struct FieldErrors {
+    errors: BTreeMap<&'static str, Vec<String>>,
+}
+
+impl FieldErrors {
+    /// Records the outcome of a check on `field`.
+    pub fn check(&mut self, field: &'static str, result: Result<(), String>) {
+        if let Err(message) = result {
+            self.errors.entry(field).or_default().push(message);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// `Ok` if every check passed, otherwise the 422 to send.
+    pub fn into_result(self) -> Result<(), HttpResponse> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(HttpResponse::UnprocessableEntity().json(&self))
+        }
+    }
+}
+
+/// 3 to 32 letters, digits, `.`, `_` or `-`, starting with a letter or
+/// digit.
+pub fn username(value: &str) -> Result<(), String> {
+    let length = value.chars().count();
+    if !(3..=32).contains(&length) {
+        return Err(String::from("must be 3 to 32 characters long"));
+    }
+    if !value.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err(String::from("must start with a letter or digit"));
+    }
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    {
+        return Err(String::from(
+            "may only contain letters, digits, '.', '_' and '-'",
+        ));
+    }
+    Ok(())
+}
+
+/// Something that looks like `someone@example.com`. Whether it really
+/// reaches someone is up to email verification.
+pub fn email(value: &str) -> Result<(), String> {
+    let invalid = || Err(String::from("is not a valid email address"));
+    if value.len() > 255 || value.chars().any(char::is_whitespace) {
+        return invalid();
+    }
+    let (local, domain) = match value.rfind('@') {
+        Some(i) => (&value[..i], &value[i + 1..]),
+        None => return invalid(),
+    };
+    let labels = domain.split('.').collect::<Vec<_>>();
+    if local.is_empty()
+        || local.contains('@')
+        || labels.len() < 2
+        || labels
+            .iter()
+            .any(|l| l.is_empty() || l.starts_with('-') || l.ends_with('-'))
+    {
+        return invalid();
+    }
+    Ok(())
+}
+
+pub fn phone_number(value: &str) -> Result<(), String> {
+    if value.len() == 10 && value.chars().all(|c| c.is_ascii_digit()) {
+        Ok(())
+    } else {
+        Err(String::from("must be 10 digits"))
+    }
+}
+
+/// At least 8 characters with a letter and a digit. bcrypt ignores
+/// anything past 72 bytes, so longer passwords are refused rather than
+/// silently cut short.
+pub fn password(value: &str) -> Result<(), String> {
+    if value.chars().count() < 8 {
+        return Err(String::from("must be at least 8 characters long"));
+    }
+    if value.len() > 72 {
+        return Err(String::from("must be at most 72 bytes long"));
+    }
+    if !value.chars().any(char::is_alphabetic)
+        || !value.chars().any(|c| c.is_ascii_digit())
+    {
+        return Err(String::from("must contain a letter and a digit"));
+    }
+    Ok(())
+}
+
+pub fn address(value: &str) -> Result<(), String> {
+    if value.chars().count() > MAX_ADDRESS_CHARS {
+        Err(format!("must be at most {} characters", MAX_ADDRESS_CHARS))
+    } else {
+        Ok(())
+    }
+}
@@ -11,6 +11,8 @@ import Http
 import Json.Decode as D
 import Json.Encode as Encode
 import Styles exposing (..)
+import Task
+import Time
 import Tuple exposing (..)
 import Utils exposing (..)
 
@@ -19,6 +21,7 @@ type alias Model =
     { pageStatus : Status
     , paymentMode : String
     , cartTotal : Float
+    , checkoutKey : String
     }
 
 
@@ -27,6 +30,9 @@ type Status
     | Loaded
     | NotLoaded
     | CheckedOut
+    | CartChanged
+    | EmailNotVerified
+    | CheckoutFailed
 
 
 type Msg
@@ -35,22 +41,40 @@ type Msg
     | AmountLoaded (Result Http.Error Float)
     | FetchAmount
     | PaymentModeSelected String
+    | CheckoutKeyGenerated Time.Posix
+    | AcceptChangesPressed
+    | ChangesAccepted (Result Http.Error ())
 
 
 init : Model
 init =
-    Model NotLoaded "Cash" 0
+    Model NotLoaded "Cash" 0 ""
 
 
 update : Msg -> Model -> ( Model, Cmd Msg )
 update msg model =
     case msg of
         CheckoutPressed ->
-            ( model, tryCheckout model.paymentMode )
+            ( model, tryCheckout model.paymentMode model.checkoutKey )
 
-        CheckoutSuccessful _ ->
+        CheckoutSuccessful (Err (Http.BadStatus 409)) ->
+            ( { model | pageStatus = CartChanged }, Cmd.none )
+
+        CheckoutSuccessful (Err (Http.BadStatus 403)) ->
+            ( { model | pageStatus = EmailNotVerified }, Cmd.none )
+
+        CheckoutSuccessful (Err _) ->
+            ( { model | pageStatus = CheckoutFailed }, Cmd.none )
+
+        CheckoutSuccessful (Ok _) ->
             ( { model | pageStatus = CheckedOut }, Cmd.none )
 
+        AcceptChangesPressed ->
+            ( model, acceptChanges )
+
+        ChangesAccepted _ ->
+            ( { model | pageStatus = Loaded }, fetchAmount )
+
         AmountLoaded res ->
             case res of
                 Ok v ->
@@ -69,9 +93,20 @@ update msg model =
         PaymentModeSelected s ->
             ( { model | paymentMode = s }, Cmd.none )
 
+        CheckoutKeyGenerated t ->
+            ( { model | checkoutKey = "checkout-" ++ String.fromInt (Time.posixToMillis t) }, Cmd.none )
+
 
 fetchAmount : Cmd Msg
 fetchAmount =
+    Cmd.batch
+        [ fetchCartTotal
+        , Task.perform CheckoutKeyGenerated Time.now
+        ]
+
+
+fetchCartTotal : Cmd Msg
+fetchCartTotal =
     Http.riskyRequest
         { method = "GET"
         , headers = []
@@ -83,11 +118,11 @@ fetchAmount =
         }
 
 
-tryCheckout : String -> Cmd Msg
-tryCheckout pm =
+tryCheckout : String -> String -> Cmd Msg
+tryCheckout pm key =
     Http.riskyRequest
         { method = "POST"
-        , headers = []
+        , headers = [ Http.header "Idempotency-Key" key ]
         , url = "http://127.0.0.1:7878/transaction/checkout"
         , body = Http.stringBody "application/json" pm
         , expect = Http.expectWhatever CheckoutSuccessful
@@ -96,6 +131,19 @@ tryCheckout pm =
         }
 
 
+acceptChanges : Cmd Msg
+acceptChanges =
+    Http.riskyRequest
+        { method = "POST"
+        , headers = []
+        , url = "http://127.0.0.1:7878/cart/acknowledge"
+        , body = Http.emptyBody
+        , expect = Http.expectWhatever ChangesAccepted
+        , timeout = Nothing
+        , tracker = Nothing
+        }
+
+
 viewStatus : Status -> String
 viewStatus s =
     case s of
@@ -111,6 +159,15 @@ viewStatus s =
         CheckedOut ->
             "Checked out!"
 
+        CartChanged ->
+            "Prices or availability changed since you added these items"
+
+        EmailNotVerified ->
+            "Please verify your email address before checking out, check your inbox for the link"
+
+        CheckoutFailed ->
+            "Checkout failed, please try again"
+
 
 view : Model -> Html Msg
 view model =
@@ -135,6 +192,18 @@ view model =
                 , div [] [ furbyRadio "Cash" (PaymentModeSelected "Cash") ]
                 , div [] [ furbyRadio "Debit Card" (PaymentModeSelected "Debit Card") ]
                 , div [] [ furbyRadio "Credit Card" (PaymentModeSelected "Credit Card") ]
+                , if model.pageStatus == CartChanged then
+                    div [ css [ cardSupportingText, marginTop (px 20) ] ]
+                        [ text <| viewStatus CartChanged
+                        , a [ href "/cart" ] [ text " (review cart)" ]
+                        ]
+
+                  else if model.pageStatus == EmailNotVerified || model.pageStatus == CheckoutFailed then
+                    div [ css [ cardSupportingText, marginTop (px 20) ] ]
+                        [ text <| viewStatus model.pageStatus ]
+
+                  else
+                    text ""
                 , div
                     []
                     [ div
@@ -142,6 +211,11 @@ view model =
                         [ furbyButton [ style "width" "100%" ] [ a [ href "/cart" ] [ text "Cancel" ] ] ]
                     , div
                         [ css [ float left, Css.width (pct 40), margin (px 15) ] ]
-                        [ furbyButton [ onClick CheckoutPressed, style "width" "100%" ] [ text "Confirm and Pay" ] ]
+                        [ if model.pageStatus == CartChanged then
+                            furbyButton [ onClick AcceptChangesPressed, style "width" "100%" ] [ text "Accept changes" ]
+
+                          else
+                            furbyButton [ onClick CheckoutPressed, style "width" "100%" ] [ text "Confirm and Pay" ]
+                        ]
                     ]
                 ]
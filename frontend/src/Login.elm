@@ -18,6 +18,7 @@ import Url.Parser as P exposing ((</>), Parser, int, oneOf, s, string)
 type alias Model =
     { username : String
     , password : String
+    , code : String
     , loginStatus : LoginStatus
     }
 
@@ -26,20 +27,26 @@ type LoginStatus
     = NotLoggedIn
     | LoggedIn
     | InvalidLogin
+    | TooManyAttempts
+    | NeedsCode
+    | InvalidCode
     | LoggingIn
 
 
 type Msg
     = PassEntered String
     | UserEntered String
+    | CodeEntered String
     | LoginPressed
-    | LoginSuccess (Result Http.Error ())
+    | VerifyPressed
+    | LoginSuccess (Result Http.Error Bool)
+    | VerifySuccess (Result Http.Error ())
     | LoginFail
 
 
 init : Model
 init =
-    Model "" "" NotLoggedIn
+    Model "" "" "" NotLoggedIn
 
 
 update : Msg -> Model -> ( Model, Cmd Msg )
@@ -55,14 +62,42 @@ update msg model =
             , Cmd.none
             )
 
+        CodeEntered s ->
+            ( { model | code = s }
+            , Cmd.none
+            )
+
         LoginPressed ->
             ( { model | loginStatus = LoggingIn }, tryLogin model )
 
+        VerifyPressed ->
+            ( { model | loginStatus = LoggingIn }, tryVerify model )
+
         LoginSuccess res ->
             case res of
-                Ok s ->
+                Ok True ->
+                    ( { model | loginStatus = NeedsCode }, Cmd.none )
+
+                Ok False ->
+                    ( { model | loginStatus = LoggedIn }, Cmd.none )
+
+                Err (Http.BadStatus 429) ->
+                    ( { model | loginStatus = TooManyAttempts }, Cmd.none )
+
+                Err e ->
+                    ( { model | loginStatus = InvalidLogin }, Cmd.none )
+
+        VerifySuccess res ->
+            case res of
+                Ok _ ->
                     ( { model | loginStatus = LoggedIn }, Cmd.none )
 
+                Err (Http.BadStatus 429) ->
+                    ( { model | loginStatus = TooManyAttempts }, Cmd.none )
+
+                Err (Http.BadStatus 401) ->
+                    ( { model | loginStatus = InvalidCode }, Cmd.none )
+
                 Err e ->
                     ( { model | loginStatus = InvalidLogin }, Cmd.none )
 
@@ -85,7 +120,42 @@ tryLogin model =
         , headers = []
         , url = "http://127.0.0.1:7878/user/login"
         , body = model |> encodeLogin |> Http.jsonBody
-        , expect = Http.expectWhatever LoginSuccess
+        , expect = Http.expectStringResponse LoginSuccess needsSecondFactor
+        , timeout = Nothing
+        , tracker = Nothing
+        }
+
+
+{-| The server answers 202 when the password was right but the account
+also wants a code from its authenticator app.
+-}
+needsSecondFactor : Http.Response String -> Result Http.Error Bool
+needsSecondFactor response =
+    case response of
+        Http.GoodStatus_ meta _ ->
+            Ok (meta.statusCode == 202)
+
+        Http.BadStatus_ meta _ ->
+            Err (Http.BadStatus meta.statusCode)
+
+        Http.BadUrl_ u ->
+            Err (Http.BadUrl u)
+
+        Http.Timeout_ ->
+            Err Http.Timeout
+
+        Http.NetworkError_ ->
+            Err Http.NetworkError
+
+
+tryVerify : Model -> Cmd Msg
+tryVerify model =
+    Http.riskyRequest
+        { method = "POST"
+        , headers = []
+        , url = "http://127.0.0.1:7878/user/login/verify"
+        , body = Http.jsonBody <| Encode.object [ ( "code", Encode.string model.code ) ]
+        , expect = Http.expectWhatever VerifySuccess
         , timeout = Nothing
         , tracker = Nothing
         }
@@ -100,6 +170,15 @@ viewStatus ls =
         InvalidLogin ->
             "Invalid Login"
 
+        TooManyAttempts ->
+            "Too many failed logins, please wait a while"
+
+        NeedsCode ->
+            "Enter the code from your authenticator app, or a recovery code"
+
+        InvalidCode ->
+            "Invalid code"
+
         LoggedIn ->
             "Logged in!"
 
@@ -132,7 +211,14 @@ view model =
         [ div [ fieldPadding, css [ bigHeading ] ] [ text "Login" ]
         , div [ fieldPadding ] [ viewInput "text" "Enter name here" model.username UserEntered ]
         , div [ fieldPadding ] [ viewInput "password" "Password" model.password PassEntered ]
-        , div [ css [ textAlign center ], fieldPadding ] [ furbyButton [ onClick LoginPressed ] [ text "Login" ] ]
+        , if model.loginStatus == NeedsCode || model.loginStatus == InvalidCode then
+            div []
+                [ div [ fieldPadding ] [ viewInput "text" "Code" model.code CodeEntered ]
+                , div [ css [ textAlign center ], fieldPadding ] [ furbyButton [ onClick VerifyPressed ] [ text "Verify" ] ]
+                ]
+
+          else
+            div [ css [ textAlign center ], fieldPadding ] [ furbyButton [ onClick LoginPressed ] [ text "Login" ] ]
         , div [ css [ textAlign center ] ] [ text (viewStatus model.loginStatus) ]
         , div [ fieldPadding ] [ text "Don't have an account? ", furbyLink [ href "/signup" ] [ text "Register now!" ] ]
         ]
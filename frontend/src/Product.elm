@@ -40,11 +40,14 @@ emptyProduct =
 
 
 type alias Rating =
-    { commentDate : String
+    { id : Int
+    , commentDate : String
     , commentText : Maybe String
     , customerName : String
     , productName : String
     , stars : Int
+    , helpfulVotes : Int
+    , notHelpfulVotes : Int
     }
 
 
@@ -76,6 +79,8 @@ type Msg
     | AddRatingFail
     | AddToCartSuccess (Result Http.Error ())
     | AddToCartPressed
+    | VotePressed Int Bool
+    | VoteRecorded (Result Http.Error ())
 
 
 init : Model
@@ -144,6 +149,12 @@ update msg model =
         AddToCartSuccess _ ->
             ( model, Cmd.none )
 
+        VotePressed ratingId helpful ->
+            ( model, voteRating ratingId helpful )
+
+        VoteRecorded _ ->
+            ( model, fetchRatings model.listing.id )
+
 
 decodeProduct : D.Decoder Product
 decodeProduct =
@@ -159,17 +170,20 @@ decodeProduct =
 
 decodeRating : D.Decoder Rating
 decodeRating =
-    D.map5 Rating
+    D.map8 Rating
+        (D.field "id" D.int)
         (D.field "comment_date" D.string)
         (D.field "comment_text" (D.nullable D.string))
         (D.field "customer_name" D.string)
         (D.field "product_name" D.string)
         (D.field "stars" D.int)
+        (D.field "helpful_votes" D.int)
+        (D.field "not_helpful_votes" D.int)
 
 
 decodeRatings : D.Decoder (List Rating)
 decodeRatings =
-    D.list decodeRating
+    D.field "reviews" (D.list decodeRating)
 
 
 fetchListing : Int -> Cmd Msg
@@ -214,7 +228,7 @@ submitRating model =
     Http.riskyRequest
         { method = "POST"
         , headers = []
-        , url = "http://127.0.0.1:7878/rating/add"
+        , url = "http://127.0.0.1:7878/rating/edit"
         , body = model |> encodeRatingForm |> Http.jsonBody
         , expect = Http.expectWhatever AddRatingSuccess
         , timeout = Nothing
@@ -222,6 +236,24 @@ submitRating model =
         }
 
 
+voteRating : Int -> Bool -> Cmd Msg
+voteRating ratingId helpful =
+    Http.riskyRequest
+        { method = "POST"
+        , headers = []
+        , url = "http://127.0.0.1:7878/rating/vote"
+        , body =
+            Http.jsonBody <|
+                Encode.object
+                    [ ( "rating_id", Encode.int ratingId )
+                    , ( "helpful", Encode.bool helpful )
+                    ]
+        , expect = Http.expectWhatever VoteRecorded
+        , timeout = Nothing
+        , tracker = Nothing
+        }
+
+
 addToCart : Model -> Cmd Msg
 addToCart model =
     let
@@ -368,6 +400,14 @@ viewRating r =
 
           else
             text ""
+        , div
+            [ css [ cardSecondaryText, paddingTop (px 12) ] ]
+            [ text "Was this review helpful? "
+            , button [ onClick (VotePressed r.id True) ]
+                [ text <| "Yes (" ++ String.fromInt r.helpfulVotes ++ ")" ]
+            , button [ onClick (VotePressed r.id False) ]
+                [ text <| "No (" ++ String.fromInt r.notHelpfulVotes ++ ")" ]
+            ]
         ]
 
 
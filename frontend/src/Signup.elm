@@ -28,6 +28,7 @@ type Status
     = UsernameTaken
     | InvalidPhone
     | InvalidEmail
+    | InvalidDetails
     | CreatedSuccessfully
     | CreatingUser
     | Empty
@@ -102,6 +103,12 @@ update msg model =
                 Ok _ ->
                     ( { model | status = CreatedSuccessfully }, Cmd.none )
 
+                Err (Http.BadStatus 409) ->
+                    ( { model | status = UsernameTaken }, Cmd.none )
+
+                Err (Http.BadStatus 422) ->
+                    ( { model | status = InvalidDetails }, Cmd.none )
+
                 Err _ ->
                     ( model, Cmd.none )
 
@@ -169,6 +176,9 @@ viewStatus s =
         InvalidEmail ->
             "Invalid email address!"
 
+        InvalidDetails ->
+            "Please check your details, passwords need 8 characters with a letter and a digit!"
+
         CreatedSuccessfully ->
             "User created successfully"
 